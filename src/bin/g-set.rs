@@ -0,0 +1,14 @@
+use std::process;
+
+use maelstrom_rust::server::Server;
+use maelstrom_rust::workloads::g_set;
+
+fn main() {
+    match g_set::register(Server::builder()) {
+        Ok(builder) => builder.build().run(),
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+    }
+}
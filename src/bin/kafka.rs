@@ -0,0 +1,14 @@
+use std::process;
+
+use maelstrom_rust::server::Server;
+use maelstrom_rust::workloads::kafka;
+
+fn main() {
+    match kafka::register(Server::builder()) {
+        Ok(builder) => builder.build().run(),
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+    }
+}
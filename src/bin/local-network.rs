@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use maelstrom_rust::protocol::{Message, MessageType};
+use maelstrom_rust::services::{LinKv, LinTso, LwwKv, SeqKv, Service};
+
+/// How long to wait for outstanding client requests once the input has been closed
+const DRAIN_TIMEOUT_MS: u64 = 5_000;
+
+const USAGE: &str = "\
+Usage: local-network [OPTIONS] -- <WORKLOAD BINARY> [ARGS...]
+
+Runs a cluster of workload nodes with in-process stand-ins for Maelstrom's services. Client
+requests are read from standard input, one JSON message per line, and responses are written to
+standard output.
+
+Options:
+  --nodes <N>                 number of nodes, named n1 to nN [default: 3]
+  --seq-kv-staleness <N>      versions behind the latest a seq-kv read may be [default: 1]
+  --lww-kv-replicas <N>       independent lww-kv replicas [default: 2]
+  --lww-kv-sync-interval <N>  lww-kv operations between replica exchanges [default: 10]";
+
+struct Options {
+    nodes: usize,
+    seq_kv_staleness: usize,
+    lww_kv_replicas: usize,
+    lww_kv_sync_interval: usize,
+    command: Vec<String>,
+}
+
+impl Options {
+    fn parse(mut arguments: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            nodes: 3,
+            seq_kv_staleness: 1,
+            lww_kv_replicas: 2,
+            lww_kv_sync_interval: 10,
+            command: vec![],
+        };
+        while let Some(argument) = arguments.next() {
+            let target = match argument.as_str() {
+                "--" => {
+                    options.command = arguments.collect();
+                    break;
+                }
+                "--nodes" => &mut options.nodes,
+                "--seq-kv-staleness" => &mut options.seq_kv_staleness,
+                "--lww-kv-replicas" => &mut options.lww_kv_replicas,
+                "--lww-kv-sync-interval" => &mut options.lww_kv_sync_interval,
+                other => return Err(format!("Unknown option: {}", other)),
+            };
+            *target = arguments
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| format!("{} requires a number", argument))?;
+        }
+        if options.command.is_empty() {
+            return Err("No workload binary given".to_string());
+        }
+        Ok(options)
+    }
+}
+
+/// Something that happened on the network
+enum Event {
+    /// A message was sent by a node or a client
+    Sent(Box<Message>),
+    /// There are no more client requests
+    InputClosed,
+}
+
+/// An in-memory network connecting the workload nodes, the clients and the services. Every message
+/// is delivered immediately and in order.
+struct Network {
+    nodes: HashMap<String, (Child, ChildStdin)>,
+    services: HashMap<String, Box<dyn Service>>,
+    /// The client requests that have not been responded to yet
+    outstanding: HashSet<(String, usize)>,
+}
+
+impl Network {
+    /// Start the nodes, and initialise them
+    fn start(options: &Options, events: &Sender<Event>) -> io::Result<Self> {
+        let node_ids: Vec<String> = (1..=options.nodes).map(|i| format!("n{}", i)).collect();
+        let mut nodes = HashMap::new();
+        for node_id in &node_ids {
+            let mut child = Command::new(&options.command[0])
+                .args(&options.command[1..])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let stdin = child.stdin.take().expect("Node input is piped");
+            let stdout = child.stdout.take().expect("Node output is piped");
+            let events = events.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    let Ok(line) = line else { break };
+                    match serde_json::from_str(&line) {
+                        Ok(message) => {
+                            if events.send(Event::Sent(Box::new(message))).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => eprintln!("Node sent an invalid message: {}: {}", e, line),
+                    }
+                }
+            });
+            nodes.insert(node_id.clone(), (child, stdin));
+        }
+        let services: Vec<Box<dyn Service>> = vec![
+            Box::new(LinKv::default()),
+            Box::new(SeqKv::new(options.seq_kv_staleness)),
+            Box::new(LwwKv::new(
+                options.lww_kv_replicas,
+                options.lww_kv_sync_interval,
+            )),
+            Box::new(LinTso::default()),
+        ];
+        let mut network = Self {
+            nodes,
+            services: services
+                .into_iter()
+                .map(|service| (service.name().to_string(), service))
+                .collect(),
+            outstanding: Default::default(),
+        };
+        for (index, node_id) in node_ids.iter().enumerate() {
+            network.deliver(&init(node_id, &node_ids, index))?;
+        }
+        Ok(network)
+    }
+
+    /// Route a message to its destination
+    fn route(&mut self, message: Message) -> io::Result<()> {
+        let is_from_client =
+            !self.nodes.contains_key(&message.src) && !self.services.contains_key(&message.src);
+        if let Some(service) = self.services.get_mut(&message.dest) {
+            let response = service.handle(&message);
+            return self.deliver(&response);
+        }
+        if self.nodes.contains_key(&message.dest) {
+            if is_from_client {
+                if let Some(msg_id) = message.body.msg_id {
+                    self.outstanding.insert((message.src.clone(), msg_id));
+                }
+            }
+            return self.deliver(&message);
+        }
+        // anything else is a response to a client
+        if let Some(in_reply_to) = message.body.in_reply_to {
+            self.outstanding
+                .remove(&(message.dest.clone(), in_reply_to));
+        }
+        let mut out = io::stdout().lock();
+        writeln!(out, "{}", serde_json::to_string(&message)?)?;
+        out.flush()
+    }
+
+    fn deliver(&mut self, message: &Message) -> io::Result<()> {
+        let Some((_, stdin)) = self.nodes.get_mut(&message.dest) else {
+            eprintln!("No such node, dropping: {}", message);
+            return Ok(());
+        };
+        writeln!(stdin, "{}", serde_json::to_string(message)?)?;
+        stdin.flush()
+    }
+
+    fn stop(self) {
+        for (_, (mut child, stdin)) in self.nodes {
+            drop(stdin);
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn init(node_id: &str, node_ids: &[String], index: usize) -> Message {
+    Message::new("c0", node_id, MessageType::init)
+        .with_msg_id(index)
+        .with_node_id(node_id)
+        .with_node_ids(node_ids)
+}
+
+/// Read client requests from standard input
+fn spawn_input_reader(events: Sender<Event>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(message) => {
+                    if events.send(Event::Sent(Box::new(message))).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => eprintln!("Invalid client request: {}: {}", e, line),
+            }
+        }
+        let _ = events.send(Event::InputClosed);
+    });
+}
+
+fn run(network: &mut Network, events: Receiver<Event>) -> io::Result<()> {
+    let mut deadline = None;
+    loop {
+        if let Some(deadline) = deadline {
+            if network.outstanding.is_empty() || Instant::now() >= deadline {
+                return Ok(());
+            }
+        }
+        let event = match deadline {
+            Some(deadline) => {
+                match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            }
+            None => match events.recv() {
+                Ok(event) => event,
+                Err(_) => return Ok(()),
+            },
+        };
+        match event {
+            Event::Sent(message) => network.route(*message)?,
+            Event::InputClosed => {
+                deadline = Some(Instant::now() + Duration::from_millis(DRAIN_TIMEOUT_MS))
+            }
+        }
+    }
+}
+
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let (event_sender, event_receiver) = mpsc::channel();
+    let mut network = Network::start(&options, &event_sender).expect("Unable to start the nodes");
+    spawn_input_reader(event_sender);
+    if let Err(e) = run(&mut network, event_receiver) {
+        eprintln!("Network error: {}", e);
+    }
+    network.stop();
+}
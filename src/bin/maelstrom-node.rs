@@ -0,0 +1,31 @@
+use std::env;
+use std::process;
+
+use maelstrom_rust::server::Server;
+use maelstrom_rust::workloads::{self, ConfigError};
+
+/// Runs the workload named by the first argument or, failing that, the `MAELSTROM_WORKLOAD`
+/// environment variable
+fn main() {
+    let Some(name) = env::args()
+        .nth(1)
+        .or_else(|| env::var("MAELSTROM_WORKLOAD").ok())
+    else {
+        eprintln!(
+            "Usage: maelstrom-node <WORKLOAD>\n\nWorkloads: {}",
+            workloads::NAMES.join(", ")
+        );
+        process::exit(2);
+    };
+    match workloads::register(&name, Server::builder()) {
+        Ok(builder) => builder.build().run(),
+        Err(error @ ConfigError::UnknownWorkload(_)) => {
+            eprintln!("{}\n\nWorkloads: {}", error, workloads::NAMES.join(", "));
+            process::exit(2);
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::node::Node;
+use crate::protocol::{Message, MessageType, Origin};
+
+/// A request and the circumstances in which it is being processed, as passed to
+/// `Module::handle` and `RequestHandler::handle`
+#[derive(Clone, Copy, Debug)]
+pub struct RequestContext<'a> {
+    node: &'a Node,
+    request: &'a Message,
+    received: Instant,
+}
+
+impl<'a> RequestContext<'a> {
+    /// Create the context for a request received now. The server creates the context for each
+    /// request it receives, this is only needed to call handlers directly, for example in tests.
+    pub fn new(node: &'a Node, request: &'a Message) -> Self {
+        Self::received_at(node, request, Instant::now())
+    }
+
+    pub(crate) fn received_at(node: &'a Node, request: &'a Message, received: Instant) -> Self {
+        Self {
+            node,
+            request,
+            received,
+        }
+    }
+
+    /// Returns: the node on which the request is being processed
+    pub fn node(&self) -> &'a Node {
+        self.node
+    }
+
+    /// Returns: the request being processed
+    pub fn request(&self) -> &'a Message {
+        self.request
+    }
+
+    /// Returns: the ID of the client, node or service that sent the request
+    pub fn sender(&self) -> &'a str {
+        &self.request.src
+    }
+
+    /// Returns: whether the request came from a client, another node or a service
+    pub fn origin(&self) -> Origin {
+        self.request.origin()
+    }
+
+    /// Returns: the request's message ID, which the server guarantees is present. `0` if the
+    /// context was created for a request without one.
+    pub fn msg_id(&self) -> usize {
+        self.request.body.msg_id.unwrap_or_default()
+    }
+
+    /// Returns: when the server read the request, which may be some time before it was handed to
+    /// the handler if requests are processed in order
+    pub fn received(&self) -> Instant {
+        self.received
+    }
+
+    /// Returns: a response to the request of the given type, addressed to the sender with a new
+    /// message ID. Any other fields of the body are left for the caller to set, see
+    /// `Message::reply`.
+    pub fn reply(&self, message_type: MessageType) -> Message {
+        self.request.reply(self.node, message_type)
+    }
+}
+
+/// The owned equivalent of `RequestContext`, as passed to `AsyncModule::handle` and
+/// `AsyncRequestHandler::handle`, so that the futures they return can keep it
+#[derive(Clone, Debug)]
+pub struct AsyncRequestContext {
+    node: Arc<Node>,
+    request: Message,
+    received: Instant,
+}
+
+impl AsyncRequestContext {
+    /// Create the context for a request received now. The server creates the context for each
+    /// request it receives, this is only needed to call handlers directly, for example in tests.
+    pub fn new(node: Arc<Node>, request: Message) -> Self {
+        Self::received_at(node, request, Instant::now())
+    }
+
+    pub(crate) fn received_at(node: Arc<Node>, request: Message, received: Instant) -> Self {
+        Self {
+            node,
+            request,
+            received,
+        }
+    }
+
+    /// Returns: the context borrowed from this one, which provides the rest of its accessors
+    pub fn borrowed(&self) -> RequestContext<'_> {
+        RequestContext::received_at(&self.node, &self.request, self.received)
+    }
+
+    /// Returns: the node on which the request is being processed
+    pub fn node(&self) -> &Arc<Node> {
+        &self.node
+    }
+
+    /// Returns: the request being processed
+    pub fn request(&self) -> &Message {
+        &self.request
+    }
+
+    /// Returns: a response to the request of the given type, see `RequestContext::reply`
+    pub fn reply(&self, message_type: MessageType) -> Message {
+        self.borrowed().reply(message_type)
+    }
+}
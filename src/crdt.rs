@@ -0,0 +1,717 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::context::RequestContext;
+use crate::node::Node;
+use crate::protocol::{Message, MessageType};
+use crate::server::{Module, Timer};
+
+/// A state-based conflict-free replicated data type (CvRDT). Each replica can be updated locally
+/// without coordination, and replicas converge once they have merged each other's states, in
+/// whatever order and however many times that happens. For this, `merge` must be commutative,
+/// associative and idempotent.
+pub trait Crdt: Default + Serialize + DeserializeOwned + Send + Sync {
+    /// Combine another replica's state into this one
+    fn merge(&mut self, other: &Self);
+}
+
+/// A counter that can only be incremented. Each node only changes its own total, so merging takes
+/// the highest total seen for each node.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct GCounter {
+    totals: HashMap<String, u64>,
+}
+
+impl GCounter {
+    /// Add to the node's own total
+    pub fn increment(&mut self, node_id: &str, amount: u64) {
+        *self.totals.entry(node_id.to_string()).or_default() += amount;
+    }
+
+    /// Returns: the sum of every node's total
+    pub fn value(&self) -> u64 {
+        self.totals.values().sum()
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (node_id, other_total) in &other.totals {
+            let total = self.totals.entry(node_id.clone()).or_default();
+            *total = (*total).max(*other_total);
+        }
+    }
+}
+
+/// A counter that supports both increments and decrements, tracked as a pair of `GCounter`s
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    /// Add a delta, which may be negative, to the counter
+    pub fn add(&mut self, node_id: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.increment(node_id, delta.unsigned_abs());
+        } else {
+            self.decrements.increment(node_id, delta.unsigned_abs());
+        }
+    }
+
+    /// Returns: the sum of the increments less the sum of the decrements
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Crdt for PnCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+/// A set to which elements can be added but never removed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "T: Eq + Hash + Serialize + DeserializeOwned")]
+pub struct GSet<T> {
+    elements: HashSet<T>,
+}
+
+impl<T> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash> GSet<T> {
+    /// Returns: `true` if the element was not already present
+    pub fn insert(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    /// Returns: `true` if the element has been added
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+
+    /// Returns: every element, in no particular order
+    pub fn elements(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+
+    /// Returns: the number of elements
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Returns: `true` if no element has been added
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+impl<T> Crdt for GSet<T>
+where
+    T: Eq + Hash + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+}
+
+/// A set from which elements can be removed, but once removed an element can never be added
+/// again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "T: Eq + Hash + Serialize + DeserializeOwned")]
+pub struct TwoPhaseSet<T> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T> Default for TwoPhaseSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> TwoPhaseSet<T> {
+    /// Returns: `true` if the element is now present, which is not the case if it was removed
+    /// previously
+    pub fn insert(&mut self, element: T) -> bool {
+        if self.removed.contains(&element) {
+            return false;
+        }
+        self.added.insert(element);
+        true
+    }
+
+    /// Returns: `true` if the element was present
+    pub fn remove(&mut self, element: &T) -> bool {
+        if !self.contains(element) {
+            return false;
+        }
+        self.removed.insert(element.clone())
+    }
+
+    /// Returns: `true` if the element has been added and not removed
+    pub fn contains(&self, element: &T) -> bool {
+        self.added.contains(element) && !self.removed.contains(element)
+    }
+
+    /// Returns: every element that has been added and not removed, in no particular order
+    pub fn elements(&self) -> impl Iterator<Item = &T> {
+        self.added
+            .elements()
+            .filter(|element| !self.removed.contains(element))
+    }
+}
+
+impl<T> Crdt for TwoPhaseSet<T>
+where
+    T: Eq + Hash + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+}
+
+/// An observed-remove set. Every addition is tagged uniquely, and a removal only removes the tags
+/// the removing node has observed, so an element that is concurrently added and removed remains
+/// in the set. Unlike a `TwoPhaseSet`, elements can be re-added after removal.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "T: Eq + Hash + Serialize + DeserializeOwned")]
+pub struct OrSet<T> {
+    /// The tags of each addition of each element
+    #[serde_as(as = "Vec<(_, _)>")]
+    added: HashMap<T, HashSet<String>>,
+    /// The tags of additions that have been removed
+    removed: HashSet<String>,
+    /// The number of tags generated by each node, used to keep tags unique
+    sequences: GCounter,
+}
+
+impl<T> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            added: HashMap::new(),
+            removed: HashSet::new(),
+            sequences: GCounter::default(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> OrSet<T> {
+    /// Add the element with a new tag, unique to the node, so that removals which have not
+    /// observed this addition do not affect it
+    pub fn insert(&mut self, node_id: &str, element: T) {
+        self.sequences.increment(node_id, 1);
+        let tag = format!(
+            "{}:{}",
+            node_id,
+            self.sequences
+                .totals
+                .get(node_id)
+                .cloned()
+                .unwrap_or_default()
+        );
+        self.added.entry(element).or_default().insert(tag);
+    }
+
+    /// Returns: `true` if the element was present
+    pub fn remove(&mut self, element: &T) -> bool {
+        let Some(tags) = self.added.get(element) else {
+            return false;
+        };
+        let present = tags.iter().any(|tag| !self.removed.contains(tag));
+        self.removed.extend(tags.iter().cloned());
+        present
+    }
+
+    /// Returns: `true` if any addition of the element has not been removed
+    pub fn contains(&self, element: &T) -> bool {
+        self.added
+            .get(element)
+            .is_some_and(|tags| tags.iter().any(|tag| !self.removed.contains(tag)))
+    }
+
+    /// Returns: every element present, in no particular order
+    pub fn elements(&self) -> impl Iterator<Item = &T> {
+        self.added.keys().filter(|element| self.contains(element))
+    }
+}
+
+impl<T> Crdt for OrSet<T>
+where
+    T: Eq + Hash + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn merge(&mut self, other: &Self) {
+        for (element, other_tags) in &other.added {
+            self.added
+                .entry(element.clone())
+                .or_default()
+                .extend(other_tags.iter().cloned());
+        }
+        self.removed.extend(other.removed.iter().cloned());
+        self.sequences.merge(&other.sequences);
+    }
+}
+
+/// A single value where the most recent write wins. Writes are ordered by timestamp, with ties
+/// broken by the ID of the node that performed the write.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    timestamp: u64,
+    node_id: String,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            timestamp: 0,
+            node_id: String::new(),
+        }
+    }
+}
+
+impl<T> LwwRegister<T> {
+    /// Write a value, unless a later write has already been observed.
+    ///
+    /// Parameters:
+    /// - `value` - the new value, `None` clears the register
+    /// - `timestamp` - when the write happened, e.g. from a clock or a timestamp oracle
+    /// - `node_id` - the node performing the write
+    pub fn set(&mut self, value: Option<T>, timestamp: u64, node_id: &str) {
+        if (timestamp, node_id) > (self.timestamp, self.node_id.as_str()) {
+            self.value = value;
+            self.timestamp = timestamp;
+            self.node_id = node_id.to_string();
+        }
+    }
+
+    /// Returns: the value of the latest write, `None` if it was never written or was cleared
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    /// Returns: the timestamp of the latest write, `0` if it was never written
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl<T> Crdt for LwwRegister<T>
+where
+    T: Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn merge(&mut self, other: &Self) {
+        self.set(other.value.clone(), other.timestamp, &other.node_id);
+    }
+}
+
+/// A map in which each key is a `LwwRegister`. Removing a key leaves a timestamped tombstone so
+/// that older writes cannot resurrect it.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "K: Eq + Hash + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned")]
+pub struct LwwMap<K, V> {
+    #[serde_as(as = "Vec<(_, _)>")]
+    entries: HashMap<K, LwwRegister<V>>,
+}
+
+impl<K, V> Default for LwwMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> LwwMap<K, V> {
+    /// Write the value of a key, unless a later write or removal has already been observed. See
+    /// `LwwRegister::set`.
+    pub fn set(&mut self, key: K, value: V, timestamp: u64, node_id: &str) {
+        self.entries
+            .entry(key)
+            .or_default()
+            .set(Some(value), timestamp, node_id);
+    }
+
+    /// Remove a key, unless a later write has already been observed. See `LwwRegister::set`.
+    pub fn remove(&mut self, key: K, timestamp: u64, node_id: &str) {
+        self.entries
+            .entry(key)
+            .or_default()
+            .set(None, timestamp, node_id);
+    }
+
+    /// Returns: the value of the key, `None` if it was never written or was removed
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).and_then(LwwRegister::get)
+    }
+
+    /// Returns: every key that has not been removed and its value, in no particular order
+    pub fn entries(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, register)| register.get().map(|value| (key, value)))
+    }
+
+    /// Returns: the latest timestamp of any write or removal observed, so that later writes can
+    /// be given a greater one
+    pub fn timestamp(&self) -> u64 {
+        self.entries
+            .values()
+            .map(LwwRegister::timestamp)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl<K, V> Crdt for LwwMap<K, V>
+where
+    K: Eq + Hash + Clone + Serialize + DeserializeOwned + Send + Sync,
+    V: Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn merge(&mut self, other: &Self) {
+        for (key, other_register) in &other.entries {
+            self.entries
+                .entry(key.clone())
+                .or_default()
+                .merge(other_register);
+        }
+    }
+}
+
+/// Replicates a CRDT between all the nodes in the cluster. It periodically sends this node's
+/// state to every other node in a `replicate` message, and merges the states it receives. Lost
+/// messages do not need to be retried because the next replication supersedes them.
+///
+/// Install it as both the `replicate` module and a timer:
+///
+/// ```ignore
+/// let replicator = Replicator::new(Duration::from_millis(500));
+/// Server::builder()
+///     .with_module(MessageType::replicate, Box::new(replicator.clone()))
+///     .with_timer(Arc::new(replicator));
+/// ```
+pub struct Replicator<C: Crdt> {
+    state: Arc<RwLock<C>>,
+    interval: Duration,
+}
+
+impl<C: Crdt> Clone for Replicator<C> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            interval: self.interval,
+        }
+    }
+}
+
+impl<C: Crdt> Replicator<C> {
+    /// Parameters:
+    /// - `interval` - how often to send this node's state to the other nodes
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            state: Default::default(),
+            interval,
+        }
+    }
+
+    /// Read this node's replica
+    pub fn read(&self) -> RwLockReadGuard<'_, C> {
+        self.state
+            .read()
+            .expect("Cannot read replicated state: lock is poisoned")
+    }
+
+    /// Update this node's replica
+    pub fn write(&self) -> RwLockWriteGuard<'_, C> {
+        self.state
+            .write()
+            .expect("Cannot update replicated state: lock is poisoned")
+    }
+}
+
+impl<C: Crdt + 'static> Module for Replicator<C> {
+    fn handle(&self, _: Sender<Message>, context: &RequestContext) {
+        let request = context.request();
+        let Some(value) = &request.body.value else {
+            eprintln!("Replicated state is missing: {}", request);
+            return;
+        };
+        match C::deserialize(value) {
+            Ok(other) => self.write().merge(&other),
+            Err(e) => eprintln!("Unable to parse replicated state: {}", e),
+        }
+    }
+}
+
+impl<C: Crdt + 'static> Timer for Replicator<C> {
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn tick(&self, response_sender: Sender<Message>, node: &Node) {
+        let state = serde_json::to_value(&*self.read()).expect("Cannot serialise replicated state");
+        for peer in node.peers() {
+            let replication =
+                Message::request(node, peer, MessageType::replicate).with_value(state.clone());
+            response_sender.send(replication).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn merged<C: Crdt + Clone>(a: &C, b: &C) -> C {
+        let mut result = a.clone();
+        result.merge(b);
+        result
+    }
+
+    /// Returns: the serialised state, with arrays sorted because they hold the contents of hash
+    /// sets and maps in no particular order
+    fn state<C: Crdt>(crdt: &C) -> Value {
+        fn canonical(value: Value) -> Value {
+            match value {
+                Value::Array(elements) => {
+                    let mut elements: Vec<Value> = elements.into_iter().map(canonical).collect();
+                    elements.sort_by_key(Value::to_string);
+                    Value::Array(elements)
+                }
+                Value::Object(fields) => Value::Object(
+                    fields
+                        .into_iter()
+                        .map(|(name, value)| (name, canonical(value)))
+                        .collect(),
+                ),
+                other => other,
+            }
+        }
+        canonical(serde_json::to_value(crdt).unwrap())
+    }
+
+    /// Check that merging the replicas is commutative, associative and idempotent
+    fn assert_merge_laws<C: Crdt + Clone>(a: &C, b: &C, c: &C) {
+        assert_eq!(state(&merged(a, b)), state(&merged(b, a)), "commutative");
+        assert_eq!(
+            state(&merged(&merged(a, b), c)),
+            state(&merged(a, &merged(b, c))),
+            "associative"
+        );
+        assert_eq!(state(&merged(a, a)), state(a), "idempotent");
+        assert_eq!(
+            state(&merged(&merged(a, b), b)),
+            state(&merged(a, b)),
+            "idempotent after merging"
+        );
+    }
+
+    #[test]
+    fn g_counter_merge_laws() {
+        let (mut a, mut b, mut c) = (
+            GCounter::default(),
+            GCounter::default(),
+            GCounter::default(),
+        );
+        a.increment("n1", 3);
+        b.increment("n1", 1);
+        b.increment("n2", 5);
+        c.increment("n3", 2);
+        assert_merge_laws(&a, &b, &c);
+        assert_eq!(merged(&merged(&a, &b), &c).value(), 10);
+    }
+
+    #[test]
+    fn pn_counter_merge_laws() {
+        let (mut a, mut b, mut c) = (
+            PnCounter::default(),
+            PnCounter::default(),
+            PnCounter::default(),
+        );
+        a.add("n1", 4);
+        a.add("n1", -1);
+        b.add("n2", -6);
+        c.add("n1", 2);
+        c.add("n3", 7);
+        assert_merge_laws(&a, &b, &c);
+        assert_eq!(merged(&merged(&a, &b), &c).value(), 4);
+    }
+
+    #[test]
+    fn g_set_merge_laws() {
+        let (mut a, mut b, mut c) = (GSet::default(), GSet::default(), GSet::default());
+        a.insert(1);
+        a.insert(2);
+        b.insert(2);
+        b.insert(3);
+        c.insert(4);
+        assert_merge_laws(&a, &b, &c);
+        assert_eq!(merged(&merged(&a, &b), &c).len(), 4);
+    }
+
+    #[test]
+    fn two_phase_set_merge_laws() {
+        let (mut a, mut b, mut c) = (
+            TwoPhaseSet::default(),
+            TwoPhaseSet::default(),
+            TwoPhaseSet::default(),
+        );
+        a.insert(1);
+        a.insert(2);
+        b.insert(1);
+        b.remove(&1);
+        c.insert(3);
+        c.remove(&3);
+        assert_merge_laws(&a, &b, &c);
+        let all = merged(&merged(&a, &b), &c);
+        assert!(!all.contains(&1));
+        assert!(all.contains(&2));
+    }
+
+    #[test]
+    fn two_phase_set_elements_cannot_be_added_after_removal() {
+        let mut a = TwoPhaseSet::default();
+        a.insert(1);
+        let mut b = a.clone();
+        b.remove(&1);
+        a.merge(&b);
+        assert!(!a.insert(1));
+        assert!(!a.contains(&1));
+    }
+
+    #[test]
+    fn or_set_merge_laws() {
+        let (mut a, mut b, mut c) = (OrSet::default(), OrSet::default(), OrSet::default());
+        a.insert("n1", 1);
+        a.insert("n1", 2);
+        b.merge(&a);
+        b.remove(&1);
+        b.insert("n2", 3);
+        c.insert("n3", 1);
+        c.remove(&1);
+        c.insert("n3", 1);
+        assert_merge_laws(&a, &b, &c);
+    }
+
+    #[test]
+    fn or_set_concurrent_add_wins_over_remove() {
+        let mut n1 = OrSet::default();
+        n1.insert("n1", 'x');
+        let mut n2 = n1.clone();
+        // n2 removes the addition it has observed while n1 adds the element again
+        assert!(n2.remove(&'x'));
+        n1.insert("n1", 'x');
+        assert!(merged(&n1, &n2).contains(&'x'));
+        assert!(merged(&n2, &n1).contains(&'x'));
+    }
+
+    #[test]
+    fn or_set_remove_applies_to_observed_additions() {
+        let mut n1 = OrSet::default();
+        n1.insert("n1", 'x');
+        let mut n2 = n1.clone();
+        assert!(n2.remove(&'x'));
+        n1.merge(&n2);
+        assert!(!n1.contains(&'x'));
+        // unlike a two-phase set, the element can be added again
+        n1.insert("n1", 'x');
+        n2.merge(&n1);
+        assert!(n2.contains(&'x'));
+    }
+
+    #[test]
+    fn or_set_tags_stay_unique_after_merging() {
+        let mut n1 = OrSet::default();
+        n1.insert("n1", 'x');
+        let mut n2 = n1.clone();
+        n2.remove(&'x');
+        // a restarted n1 that has since merged n2's state must not reuse the removed tag
+        let mut restarted = OrSet::default();
+        restarted.merge(&n2);
+        restarted.insert("n1", 'x');
+        assert!(merged(&restarted, &n2).contains(&'x'));
+    }
+
+    #[test]
+    fn lww_register_merge_laws() {
+        let (mut a, mut b, mut c) = (
+            LwwRegister::default(),
+            LwwRegister::default(),
+            LwwRegister::default(),
+        );
+        a.set(Some(1), 5, "n1");
+        b.set(Some(2), 5, "n2");
+        c.set(None, 3, "n3");
+        assert_merge_laws(&a, &b, &c);
+    }
+
+    #[test]
+    fn lww_register_later_write_wins() {
+        let (mut a, mut b) = (LwwRegister::default(), LwwRegister::default());
+        a.set(Some("late".to_string()), 2, "n1");
+        b.set(Some("early".to_string()), 1, "n9");
+        assert_eq!(merged(&a, &b).get().map(String::as_str), Some("late"));
+        assert_eq!(merged(&b, &a).get().map(String::as_str), Some("late"));
+    }
+
+    #[test]
+    fn lww_register_ties_are_broken_by_node_id() {
+        let (mut a, mut b) = (LwwRegister::default(), LwwRegister::default());
+        a.set(Some("from n1".to_string()), 7, "n1");
+        b.set(Some("from n2".to_string()), 7, "n2");
+        assert_eq!(merged(&a, &b).get().map(String::as_str), Some("from n2"));
+        assert_eq!(merged(&b, &a).get().map(String::as_str), Some("from n2"));
+        // a write with the same timestamp from a lower node ID is ignored locally too
+        b.set(Some("ignored".to_string()), 7, "n1");
+        assert_eq!(b.get().map(String::as_str), Some("from n2"));
+    }
+
+    #[test]
+    fn lww_map_merge_laws() {
+        let (mut a, mut b, mut c) = (LwwMap::default(), LwwMap::default(), LwwMap::default());
+        a.set('x', 1, 1, "n1");
+        a.set('y', 2, 2, "n1");
+        b.set('x', 3, 2, "n2");
+        b.remove('y', 2, "n2");
+        c.set('z', 4, 1, "n3");
+        c.set('x', 5, 2, "n1");
+        assert_merge_laws(&a, &b, &c);
+        let all = merged(&merged(&a, &b), &c);
+        assert_eq!(all.get(&'x'), Some(&3));
+        assert_eq!(all.get(&'y'), None);
+        assert_eq!(all.get(&'z'), Some(&4));
+    }
+
+    #[test]
+    fn lww_map_removal_is_not_undone_by_an_older_write() {
+        let (mut a, mut b) = (LwwMap::default(), LwwMap::default());
+        a.remove('x', 3, "n1");
+        b.set('x', 1, 2, "n2");
+        assert_eq!(merged(&a, &b).get(&'x'), None);
+        assert_eq!(merged(&b, &a).get(&'x'), None);
+    }
+}
@@ -0,0 +1,244 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rayon::ThreadPool;
+
+/// A future that can be sent between threads, as returned by async handlers
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Runs futures on a thread pool. A future is polled on a worker thread whenever it is woken, so
+/// while it waits, for example for a response to `Node::rpc_async`, it does not occupy a thread.
+#[derive(Clone)]
+pub struct Executor {
+    pool: Arc<ThreadPool>,
+}
+
+impl Executor {
+    /// Parameters:
+    /// - `pool` - the threads on which to poll futures
+    pub fn new(pool: Arc<ThreadPool>) -> Self {
+        Self { pool }
+    }
+
+    /// Run a future to completion in the background
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            pool: self.pool.clone(),
+        });
+        task.wake();
+    }
+}
+
+/// A spawned future. Waking the task schedules it to be polled on the pool.
+struct Task {
+    /// The future, or `None` once it has completed
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    pool: Arc<ThreadPool>,
+}
+
+impl Task {
+    fn poll(self: Arc<Self>) {
+        // If the task is woken while it is being polled, the next poll waits for this one
+        let mut guard = self
+            .future
+            .lock()
+            .expect("Unable to poll task: lock poisoned");
+        let Some(future) = guard.as_mut() else {
+            return;
+        };
+        let waker = Waker::from(self.clone());
+        if future
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            *guard = None;
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let pool = self.pool.clone();
+        pool.spawn(move || self.poll());
+    }
+}
+
+/// Returns: a future that completes once the duration has elapsed
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + duration,
+    }
+}
+
+/// A future that completes at a deadline, see `sleep`
+pub struct Sleep {
+    deadline: Instant,
+}
+
+impl Sleep {
+    /// Returns: `true` if the deadline has passed
+    pub(crate) fn poll_elapsed(&self, context: &Context) -> bool {
+        if Instant::now() >= self.deadline {
+            return true;
+        }
+        timers().register(self.deadline, context.waker().clone());
+        false
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if self.poll_elapsed(context) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Returns: a future that runs the futures concurrently and completes with all of their outputs,
+/// in the same order as the futures
+pub fn join_all<'a, T>(futures: Vec<BoxFuture<'a, T>>) -> BoxFuture<'a, Vec<T>>
+where
+    T: Send + 'a,
+{
+    let mut pending: Vec<Option<BoxFuture<'a, T>>> = futures.into_iter().map(Some).collect();
+    let mut outputs: Vec<Option<T>> = pending.iter().map(|_| None).collect();
+    Box::pin(poll_fn(move |context| {
+        for (future, output) in pending.iter_mut().zip(outputs.iter_mut()) {
+            if let Some(Poll::Ready(value)) = future.as_mut().map(|f| f.as_mut().poll(context)) {
+                *output = Some(value);
+                *future = None;
+            }
+        }
+        if pending.iter().all(Option::is_none) {
+            Poll::Ready(
+                outputs
+                    .iter_mut()
+                    .map(|output| output.take().unwrap())
+                    .collect(),
+            )
+        } else {
+            Poll::Pending
+        }
+    }))
+}
+
+/// A deadline and the task to wake when it passes
+struct TimerEntry {
+    deadline: Instant,
+    waker: Waker,
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.deadline.cmp(&other.deadline)
+    }
+}
+
+/// The pending deadlines of every `Sleep`, served by a single background thread
+struct Timers {
+    entries: Mutex<BinaryHeap<Reverse<TimerEntry>>>,
+    changed: Condvar,
+}
+
+impl Timers {
+    fn register(&self, deadline: Instant, waker: Waker) {
+        self.entries
+            .lock()
+            .expect("Unable to register timer: lock poisoned")
+            .push(Reverse(TimerEntry { deadline, waker }));
+        self.changed.notify_one();
+    }
+
+    /// Wake each task when its deadline passes, forever
+    fn run(&self) {
+        let mut entries = self
+            .entries
+            .lock()
+            .expect("Unable to read timers: lock poisoned");
+        loop {
+            let now = Instant::now();
+            while entries
+                .peek()
+                .is_some_and(|Reverse(entry)| entry.deadline <= now)
+            {
+                let Reverse(entry) = entries.pop().unwrap();
+                entry.waker.wake();
+            }
+            entries = match entries.peek() {
+                Some(Reverse(entry)) => {
+                    let timeout = entry.deadline - now;
+                    self.changed
+                        .wait_timeout(entries, timeout)
+                        .expect("Unable to wait for timers: lock poisoned")
+                        .0
+                }
+                None => self
+                    .changed
+                    .wait(entries)
+                    .expect("Unable to wait for timers: lock poisoned"),
+            };
+        }
+    }
+}
+
+fn timers() -> &'static Timers {
+    static TIMERS: OnceLock<Timers> = OnceLock::new();
+    TIMERS.get_or_init(|| {
+        thread::spawn(|| timers().run());
+        Timers {
+            entries: Default::default(),
+            changed: Condvar::new(),
+        }
+    })
+}
+
+/// Wakes a thread blocked on a future
+#[cfg(test)]
+struct Unpark(thread::Thread);
+
+#[cfg(test)]
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Returns: the output of the future, run to completion on the calling thread
+#[cfg(test)]
+pub(crate) fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -0,0 +1,306 @@
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType};
+
+/// How long to wait for a service to respond unless configured otherwise
+const DEFAULT_TIMEOUT_MS: u64 = 1_000;
+
+/// A client for one of Maelstrom's key/value
+/// [services](https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md). Each call sends a
+/// request using `Node::rpc` and blocks until the service responds or the timeout elapses. The
+/// `_async` variants use `Node::rpc_async` instead, for use in async handlers.
+///
+/// Errors returned by the service are mapped to `AppError`:
+/// - `AppError::KeyDoesNotExist` - the key has never been written
+/// - `AppError::PreconditionFailed` - a compare-and-set found a different value
+/// - `AppError::Timeout` - the service did not respond in time, the operation may have happened
+/// - `AppError::Unavailable` - the service refused the operation, which definitely did not happen
+/// - `AppError::Crash` - any other error, after which the operation may have happened
+#[derive(Clone, Debug)]
+pub struct KvClient {
+    service: &'static str,
+    timeout: Duration,
+}
+
+impl KvClient {
+    /// A client for the sequentially consistent store
+    pub fn seq_kv() -> Self {
+        Self::new("seq-kv")
+    }
+
+    /// A client for the linearizable store
+    pub fn lin_kv() -> Self {
+        Self::new("lin-kv")
+    }
+
+    /// A client for the store that resolves conflicting writes by last-writer-wins
+    pub fn lww_kv() -> Self {
+        Self::new("lww-kv")
+    }
+
+    fn new(service: &'static str) -> Self {
+        Self {
+            service,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+
+    /// Change how long to wait for each response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The name of the service, as used for the destination of requests
+    pub fn service(&self) -> &str {
+        self.service
+    }
+
+    /// Returns: the value of the key
+    pub fn read(&self, node: &Node, key: impl Into<Value>) -> Result<Value, AppError> {
+        let key = key.into();
+        let request =
+            Message::new(&node.node_id, self.service, MessageType::read).with_key(key.clone());
+        let response = node.rpc(request, self.timeout)?;
+        self.read_ok(&key, response)
+    }
+
+    /// The async equivalent of `read`
+    pub async fn read_async(&self, node: &Node, key: impl Into<Value>) -> Result<Value, AppError> {
+        let key = key.into();
+        let request =
+            Message::new(&node.node_id, self.service, MessageType::read).with_key(key.clone());
+        let response = node.rpc_async(request, self.timeout).await?;
+        self.read_ok(&key, response)
+    }
+
+    /// Interpret the response to a `read` request
+    fn read_ok(&self, key: &Value, response: Message) -> Result<Value, AppError> {
+        match response.body.message_type {
+            MessageType::read_ok => response.body.value.ok_or_else(|| {
+                AppError::Unavailable(format!("{} returned no value for {}", self.service, key))
+            }),
+            _ => Err(error(self.service, key, &response)),
+        }
+    }
+
+    /// Set the value of the key
+    pub fn write(&self, node: &Node, key: impl Into<Value>, value: Value) -> Result<(), AppError> {
+        let key = key.into();
+        let response = node.rpc(self.write_request(node, &key, value), self.timeout)?;
+        self.ok(MessageType::write_ok, &key, response)
+    }
+
+    /// The async equivalent of `write`
+    pub async fn write_async(
+        &self,
+        node: &Node,
+        key: impl Into<Value>,
+        value: Value,
+    ) -> Result<(), AppError> {
+        let key = key.into();
+        let request = self.write_request(node, &key, value);
+        let response = node.rpc_async(request, self.timeout).await?;
+        self.ok(MessageType::write_ok, &key, response)
+    }
+
+    fn write_request(&self, node: &Node, key: &Value, value: Value) -> Message {
+        Message::new(&node.node_id, self.service, MessageType::write)
+            .with_key(key.clone())
+            .with_value(value)
+    }
+
+    /// Set the value of the key to `to` if it currently has the value `from`.
+    ///
+    /// Parameters:
+    /// - `create_if_not_exists` - whether to set the value if the key does not exist, rather than
+    ///   fail with `AppError::KeyDoesNotExist`
+    pub fn cas(
+        &self,
+        node: &Node,
+        key: impl Into<Value>,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<(), AppError> {
+        let key = key.into();
+        let request = self.cas_request(node, &key, from, to, create_if_not_exists);
+        let response = node.rpc(request, self.timeout)?;
+        self.ok(MessageType::cas_ok, &key, response)
+    }
+
+    /// The async equivalent of `cas`
+    pub async fn cas_async(
+        &self,
+        node: &Node,
+        key: impl Into<Value>,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<(), AppError> {
+        let key = key.into();
+        let request = self.cas_request(node, &key, from, to, create_if_not_exists);
+        let response = node.rpc_async(request, self.timeout).await?;
+        self.ok(MessageType::cas_ok, &key, response)
+    }
+
+    fn cas_request(
+        &self,
+        node: &Node,
+        key: &Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Message {
+        Message::new(&node.node_id, self.service, MessageType::cas)
+            .with_key(key.clone())
+            .with_from(from)
+            .with_to(to)
+            .with_create_if_not_exists(create_if_not_exists)
+    }
+
+    /// Interpret the response to a request that returns no value
+    fn ok(&self, expected: MessageType, key: &Value, response: Message) -> Result<(), AppError> {
+        if response.body.message_type == expected {
+            Ok(())
+        } else {
+            Err(error(self.service, key, &response))
+        }
+    }
+}
+
+/// A client for Maelstrom's `lin-tso` timestamp oracle
+#[derive(Clone, Debug)]
+pub struct TsoClient {
+    timeout: Duration,
+}
+
+impl Default for TsoClient {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        }
+    }
+}
+
+impl TsoClient {
+    /// Change how long to wait for each response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns: a timestamp greater than any the oracle has issued before
+    pub fn ts(&self, node: &Node) -> Result<u64, AppError> {
+        let response = node.rpc(
+            Message::new(&node.node_id, "lin-tso", MessageType::ts),
+            self.timeout,
+        )?;
+        ts_ok(response)
+    }
+
+    /// The async equivalent of `ts`
+    pub async fn ts_async(&self, node: &Node) -> Result<u64, AppError> {
+        let request = Message::new(&node.node_id, "lin-tso", MessageType::ts);
+        let response = node.rpc_async(request, self.timeout).await?;
+        ts_ok(response)
+    }
+}
+
+/// Interpret the response to a `ts` request
+fn ts_ok(response: Message) -> Result<u64, AppError> {
+    match response.body.message_type {
+        MessageType::ts_ok => response
+            .body
+            .ts
+            .ok_or_else(|| AppError::Unavailable("lin-tso returned no timestamp".to_string())),
+        _ => Err(error("lin-tso", &Value::Null, &response)),
+    }
+}
+
+/// Interpret an error response from a service
+fn error(service: &str, key: &Value, response: &Message) -> AppError {
+    let text = response.body.text.clone().unwrap_or_default();
+    match response.body.code {
+        Some(0) => AppError::Timeout,
+        Some(20) => AppError::KeyDoesNotExist(
+            key.as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| key.to_string()),
+        ),
+        Some(22) => AppError::PreconditionFailed(text),
+        Some(30) => AppError::TxnConflict(text),
+        // node-not-found, not-supported, temporarily-unavailable, malformed-request and
+        // key-already-exists are definite: the service did not perform the operation
+        Some(code @ (1 | 10 | 11 | 12 | 21)) => AppError::Unavailable(format!(
+            "{} responded with error {}: {}",
+            service, code, text
+        )),
+        // anything else, including crash, abort and custom codes, may have been performed, or
+        // the response was not an error at all
+        _ => AppError::Crash(format!(
+            "{} responded with {:?} {:?}: {}",
+            service, response.body.message_type, response.body.code, text
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn error_codes_are_interpreted_by_whether_the_operation_may_have_happened() {
+        // the code in the response, and the code and definiteness of the resulting error
+        let cases = [
+            (0, 0, false),
+            (1, 11, true),
+            (10, 11, true),
+            (11, 11, true),
+            (12, 11, true),
+            (20, 20, true),
+            (21, 11, true),
+            (22, 22, true),
+            (30, 30, true),
+            (13, 13, false),
+            (14, 13, false),
+            (1000, 13, false),
+        ];
+        for (code, expected, definite) in cases {
+            let response = Message::error("lin-kv", "n1", 1, code, "failed");
+            let error = error("lin-kv", &json!("k"), &response);
+            assert_eq!(error.code(), expected, "code {}", code);
+            assert_eq!(error.is_definite(), definite, "code {}", code);
+        }
+    }
+
+    #[test]
+    fn missing_keys_and_failed_preconditions_keep_their_details() {
+        let response = Message::error("lin-kv", "n1", 1, 20, "not found");
+        assert!(matches!(
+            error("lin-kv", &json!("k"), &response),
+            AppError::KeyDoesNotExist(key) if key == "k"
+        ));
+        assert!(matches!(
+            error("lin-kv", &json!(1), &response),
+            AppError::KeyDoesNotExist(key) if key == "1"
+        ));
+        let response = Message::error("lin-kv", "n1", 1, 22, "expected 1");
+        assert!(matches!(
+            error("lin-kv", &json!("k"), &response),
+            AppError::PreconditionFailed(text) if text == "expected 1"
+        ));
+    }
+
+    #[test]
+    fn responses_that_are_not_errors_are_indefinite() {
+        let response = Message::new("lin-kv", "n1", MessageType::read_ok).with_in_reply_to(1_usize);
+        let error = error("lin-kv", &json!("k"), &response);
+        assert_eq!(error.code(), 13);
+        assert!(!error.is_definite());
+    }
+}
@@ -0,0 +1,45 @@
+//! A framework for implementing the distributed systems in the
+//! [Maelstrom](https://github.com/jepsen-io/maelstrom/) workbench, along with implementations of
+//! its workloads.
+//!
+//! A node is a `server::Server` with workload handlers installed. Simple request/response handlers
+//! implement `server::RequestHandler`, or are plain functions, and return a `server::Response`.
+//! Handlers that send messages of their own implement `server::Module`. For example:
+//!
+//! ```no_run
+//! use maelstrom_rust::server::Server;
+//! use maelstrom_rust::workloads::{echo, ConfigError};
+//!
+//! fn main() -> Result<(), ConfigError> {
+//!     echo::register(Server::builder())?.build().run();
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The `server`, `node` and `protocol` modules make up the framework. The remaining modules build
+//! on it: replicated data types, clients and stand-ins for Maelstrom's services, and the
+//! workloads themselves.
+#![warn(missing_docs)]
+
+/// The context in which a request is processed
+pub mod context;
+/// Conflict-free replicated data types and a module that replicates them between nodes
+pub mod crdt;
+/// A minimal executor for async handlers, built on the server's thread pool
+pub mod executor;
+/// Clients for Maelstrom's key/value and timestamp services
+pub mod kv;
+/// Hooks that wrap every request handler, and the built-in middleware
+pub mod middleware;
+/// The node on which requests are processed, and the errors they may produce
+pub mod node;
+/// The messages exchanged over the Maelstrom network
+pub mod protocol;
+/// The server that listens on the Maelstrom network and dispatches requests to handlers
+pub mod server;
+/// In-process stand-ins for Maelstrom's services
+pub mod services;
+/// Persistence of node state across restarts
+pub mod storage;
+/// Implementations of Maelstrom's workloads
+pub mod workloads;
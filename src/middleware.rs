@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use statsd::Client;
+
+use crate::node::{AppError, Node};
+use crate::protocol::Message;
+
+/// Wraps every request handler, for cross-cutting concerns such as logging, metrics, validation or
+/// fault injection. Middlewares are called in the order they are installed before a request is
+/// handled, and in the reverse order afterwards.
+///
+/// Middlewares see the messages a handler sends through the `response_sender` it is given for the
+/// request. Messages sent through other channels, such as `Node::rpc` requests or messages sent by
+/// daemons, do not pass through middlewares.
+pub trait Middleware: Sync + Send {
+    /// Inspect a request before it is handled. By default, this accepts every request.
+    ///
+    /// Parameters:
+    /// - `node` - the node on which the request is being processed
+    /// - `request` - a message received from either a client or another cluster member
+    ///
+    /// Returns: an error to reject the request. Neither the handler nor any later middleware sees
+    /// the request, and the error is sent to the caller instead.
+    fn before(&self, _node: &Node, _request: &Message) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    /// Inspect the messages sent in response to a request before they are sent. They may be
+    /// modified, removed or added to. By default, this does nothing.
+    ///
+    /// Parameters:
+    /// - `node` - the node on which the request was processed
+    /// - `request` - the request that was processed
+    /// - `outgoing` - the messages to send, in order
+    /// - `elapsed` - how long the handler and any later middlewares took to process the request
+    fn after(
+        &self,
+        _node: &Node,
+        _request: &Message,
+        _outgoing: &mut Vec<Message>,
+        _elapsed: Duration,
+    ) {
+    }
+}
+
+/// Reports how long each type of request takes to process, as the StatsD timer
+/// `server.handler.<type>`. The server always installs this before any other middleware.
+pub struct Timing {
+    stats: Arc<Client>,
+}
+
+impl Timing {
+    /// Parameters:
+    /// - `stats` - the client to report timings to
+    pub fn new(stats: Arc<Client>) -> Self {
+        Self { stats }
+    }
+}
+
+impl Middleware for Timing {
+    fn after(&self, _: &Node, request: &Message, _: &mut Vec<Message>, elapsed: Duration) {
+        self.stats.timer(
+            &format!("server.handler.{:?}", request.body.message_type),
+            elapsed.as_secs_f64() * 1_000.0,
+        );
+    }
+}
+
+/// Writes every request and the messages sent in response to standard error
+pub struct Logging;
+
+impl Middleware for Logging {
+    fn before(&self, node: &Node, request: &Message) -> Result<(), AppError> {
+        eprintln!("{} received: {}", node.node_id, request);
+        Ok(())
+    }
+
+    fn after(
+        &self,
+        node: &Node,
+        request: &Message,
+        outgoing: &mut Vec<Message>,
+        elapsed: Duration,
+    ) {
+        for message in outgoing.iter() {
+            eprintln!(
+                "{} sent in response to {}#{} after {:?}: {}",
+                node.node_id,
+                request.src,
+                request.body.msg_id.unwrap_or_default(),
+                elapsed,
+                message
+            );
+        }
+    }
+}
@@ -1,9 +1,25 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::hash::{Hash, Hasher};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Poll, Waker};
+use std::time::Duration;
 
-use AppError::{AlreadyInitialised, MissingField};
+use AppError::{
+    AlreadyInitialised, Crash, KeyDoesNotExist, MissingField, NotSupported, PreconditionFailed,
+    Timeout, TxnConflict, Unavailable,
+};
 
+use crate::executor;
 use crate::protocol::Message;
 
+/// The number of points each node has on the consistent hashing ring. More points spread keys more
+/// evenly between the nodes.
+const VIRTUAL_NODES: usize = 64;
+
 /// Application-specific errors which may occur. Note that these _do not_ correspond one-to-one with
 /// the Maelstrom protocol errors.
 #[derive(Debug)]
@@ -13,6 +29,26 @@ pub enum AppError {
     MissingField(String),
     /// An initialisation request was received but the application was already initialised.
     AlreadyInitialised,
+    /// A request to another node or service did not receive a response in time.
+    Timeout,
+    /// The operation cannot be performed at this time, for example because a service it depends
+    /// on refused it. The operation definitely did not happen. The parameter describes the cause.
+    Unavailable(String),
+    /// The operation failed in a way that leaves it unknown whether it happened, for example
+    /// because a service it depends on crashed while performing it. The parameter describes the
+    /// cause.
+    Crash(String),
+    /// The requested key does not exist. The parameter identifies the key.
+    KeyDoesNotExist(String),
+    /// The request expected a condition to hold, such as a key having a particular value, but it
+    /// did not. The parameter describes the condition.
+    PreconditionFailed(String),
+    /// The request is valid but this workload does not support it. The parameter describes the
+    /// unsupported operation.
+    NotSupported(String),
+    /// The transaction was aborted because it conflicted with another. The parameter describes
+    /// the conflict.
+    TxnConflict(String),
 }
 
 impl AppError {
@@ -22,22 +58,40 @@ impl AppError {
     /// at some later time. Maelstrom uses this information to interpret histories correctly, so
     /// it's important that you never return a definite error under indefinite conditions. When in
     /// doubt, indefinite is always safe. Custom error codes are always indefinite."
-    fn _is_definite(&self) -> bool {
+    pub fn is_definite(&self) -> bool {
         match self {
-            MissingField(_) | AlreadyInitialised => true,
-            // _ => false,
+            MissingField(_)
+            | AlreadyInitialised
+            | Unavailable(_)
+            | KeyDoesNotExist(_)
+            | PreconditionFailed(_)
+            | NotSupported(_)
+            | TxnConflict(_) => true,
+            Timeout | Crash(_) => false,
         }
     }
 
     /// The Maelstrom error code as documented here:
-    /// https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors .
-    pub(crate) fn code(&self) -> u16 {
+    /// <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>.
+    pub fn code(&self) -> u16 {
         match self {
+            Timeout => 0,
+            NotSupported(_) => 10,
+            Unavailable(_) => 11,
             MissingField(_) => 12,
-            AlreadyInitialised => 22,
+            Crash(_) => 13,
+            KeyDoesNotExist(_) => 20,
+            AlreadyInitialised | PreconditionFailed(_) => 22,
+            TxnConflict(_) => 30,
         }
     }
 
+    /// Returns: the `error` response to send for a failed request
+    ///
+    /// Parameters:
+    /// - `node_id` - the node that processed the request
+    /// - `destination` - the sender of the request
+    /// - `in_reply_to` - the request message ID
     pub fn to_message(&self, node_id: &str, destination: &str, in_reply_to: usize) -> Message {
         match self {
             MissingField(ref field) => Message::error(
@@ -54,6 +108,55 @@ impl AppError {
                 self.code(),
                 "Node was already initialised",
             ),
+            Timeout => Message::error(
+                node_id,
+                destination,
+                in_reply_to,
+                self.code(),
+                "Timed out waiting for a dependency",
+            ),
+            Unavailable(ref cause) => Message::error(
+                node_id,
+                destination,
+                in_reply_to,
+                self.code(),
+                format!("Temporarily unavailable: {}", cause).as_str(),
+            ),
+            Crash(ref cause) => Message::error(
+                node_id,
+                destination,
+                in_reply_to,
+                self.code(),
+                format!("Internal error: {}", cause).as_str(),
+            ),
+            KeyDoesNotExist(ref key) => Message::error(
+                node_id,
+                destination,
+                in_reply_to,
+                self.code(),
+                format!("Key does not exist: {}", key).as_str(),
+            ),
+            PreconditionFailed(ref condition) => Message::error(
+                node_id,
+                destination,
+                in_reply_to,
+                self.code(),
+                format!("Precondition failed: {}", condition).as_str(),
+            ),
+            NotSupported(ref operation) => Message::error(
+                node_id,
+                destination,
+                in_reply_to,
+                self.code(),
+                format!("Not supported: {}", operation).as_str(),
+            ),
+            TxnConflict(ref conflict) => Message::error(
+                node_id,
+                destination,
+                in_reply_to,
+                self.code(),
+                format!("Transaction aborted: {}", conflict).as_str(),
+            ),
         }
     }
 }
@@ -64,14 +167,342 @@ pub struct Node {
     /// The node's unique identifier, which won't be available until it has been initialised
     pub node_id: String,
     /// The counter for unique message IDs
-    pub(crate) next_message_id: AtomicUsize,
-    /// The other node IDs in the cluster
+    next_message_id: AtomicUsize,
+    /// All the node IDs in the cluster including this one, in the same order on every node
     pub node_ids: Vec<String>,
+    /// Each node's position on the consistent hashing ring, ordered by position
+    ring: Vec<(u64, usize)>,
+    /// The neighbours of each node, as given by the latest `topology` message
+    topology: RwLock<HashMap<String, Vec<String>>>,
+    /// The channel on which to send network messages
+    response_sender: Sender<Message>,
+    /// The requests sent using `rpc` or `rpc_async` that are awaiting a response, keyed by
+    /// message ID
+    pending_replies: Mutex<HashMap<usize, PendingReply>>,
+}
+
+/// How to deliver the response to an outstanding request
+#[derive(Debug)]
+enum PendingReply {
+    /// A thread is blocked in `rpc`, waiting to receive the response
+    Blocking(Sender<Message>),
+    /// A future returned by `rpc_async` will take the response when it is next polled
+    Async(Arc<Mutex<AsyncReply>>),
+}
+
+#[derive(Debug, Default)]
+struct AsyncReply {
+    response: Option<Message>,
+    /// The task to wake once the response arrives
+    waker: Option<Waker>,
 }
 
 impl Node {
+    /// Create a node. The server creates the node when it is initialised, this is only needed to
+    /// run handlers outside a server, for example in tests.
+    ///
+    /// Parameters:
+    /// - `node_id` - the node's unique identifier
+    /// - `node_ids` - all the nodes in the cluster including this one
+    /// - `response_sender` - the channel on which to send network messages, including `rpc`
+    ///   requests
+    pub fn new(node_id: String, node_ids: Vec<String>, response_sender: Sender<Message>) -> Self {
+        let mut ring: Vec<(u64, usize)> = node_ids
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node_id)| {
+                (0..VIRTUAL_NODES).map(move |point| (hash(&(node_id, point)), index))
+            })
+            .collect();
+        ring.sort_unstable();
+        Self {
+            node_id,
+            next_message_id: Default::default(),
+            node_ids,
+            ring,
+            topology: Default::default(),
+            response_sender,
+            pending_replies: Default::default(),
+        }
+    }
+
+    /// Returns: the other nodes in the cluster, in the order of `node_ids`
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.node_ids
+            .iter()
+            .filter(move |node_id| **node_id != self.node_id)
+    }
+
+    /// Returns: this node's position in `node_ids`, which is the same on every node, or `None` if
+    /// it is not a member of the cluster
+    pub fn index(&self) -> Option<usize> {
+        self.node_ids
+            .iter()
+            .position(|node_id| *node_id == self.node_id)
+    }
+
+    /// Returns: the node that every node agrees leads the cluster without having to communicate,
+    /// the first in `node_ids`. It does not change when the node fails, so workloads that must
+    /// tolerate failures need to elect a leader instead.
+    pub fn leader(&self) -> Option<&str> {
+        self.node_ids.first().map(String::as_str)
+    }
+
+    /// Returns: whether this node is the `leader`
+    pub fn is_leader(&self) -> bool {
+        self.leader() == Some(self.node_id.as_str())
+    }
+
+    /// Returns: the node that owns the key, by consistent hashing over `node_ids`. Every node
+    /// agrees on the owner, and when the cluster changes only the keys of the nodes that joined or
+    /// left move. `None` if the cluster is empty.
+    ///
+    /// Keys are hashed with the standard library's default hasher, which is the same on every
+    /// node as they run the same binary. Keys that cannot be hashed, such as JSON values, can be
+    /// passed as their string representations.
+    pub fn owner<K: Hash + ?Sized>(&self, key: &K) -> Option<&str> {
+        self.owners(key, 1).into_iter().next()
+    }
+
+    /// Returns: up to `count` distinct nodes responsible for the key, for example to replicate it,
+    /// starting with its `owner`
+    pub fn owners<K: Hash + ?Sized>(&self, key: &K, count: usize) -> Vec<&str> {
+        let start = self.ring.partition_point(|(point, _)| *point < hash(key));
+        let mut owners: Vec<&str> = Vec::with_capacity(count);
+        for (_, index) in self.ring[start..].iter().chain(&self.ring[..start]) {
+            if owners.len() == count.min(self.node_ids.len()) {
+                break;
+            }
+            let node_id = self.node_ids[*index].as_str();
+            if !owners.contains(&node_id) {
+                owners.push(node_id);
+            }
+        }
+        owners
+    }
+
+    /// Returns: the neighbours of each node, as given by the latest `topology` message. This is
+    /// empty until one is received. The server records the topology before passing the message
+    /// to any handler.
+    pub fn topology(&self) -> HashMap<String, Vec<String>> {
+        self.topology
+            .read()
+            .expect("Unable to read topology: lock poisoned")
+            .clone()
+    }
+
+    /// Returns: this node's neighbours, as given by the latest `topology` message
+    pub fn neighbours(&self) -> Vec<String> {
+        self.topology
+            .read()
+            .expect("Unable to read topology: lock poisoned")
+            .get(&self.node_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Replace the topology. The server calls this for every `topology` message it receives.
+    pub fn set_topology(&self, topology: HashMap<String, Vec<String>>) {
+        *self
+            .topology
+            .write()
+            .expect("Unable to update topology: lock poisoned") = topology;
+    }
+
     /// Get the next available message identifier
     pub fn get_and_increment_message_id(&self) -> usize {
         self.next_message_id.fetch_add(1, Ordering::Relaxed)
     }
+
+    /// Send a request to another node or a Maelstrom service and wait for the response. This
+    /// blocks the calling thread.
+    ///
+    /// Parameters:
+    /// - `request` - the request to send, a message ID will be assigned
+    /// - `timeout` - how long to wait for a response
+    ///
+    /// Returns: the response, which may be an `error` message, or `AppError::Timeout` if no
+    /// response arrived in time
+    pub fn rpc(&self, request: Message, timeout: Duration) -> Result<Message, AppError> {
+        let (reply_sender, reply_receiver) = mpsc::channel();
+        let message_id = self.send_request(request, PendingReply::Blocking(reply_sender));
+        let result = reply_receiver.recv_timeout(timeout);
+        self.deregister(message_id);
+        result.map_err(|_| Timeout)
+    }
+
+    /// Send a request to another node or a Maelstrom service. Unlike `rpc`, this does not block
+    /// the calling thread, the returned future completes once the response arrives. It must be
+    /// run by an `executor::Executor`, as async handlers are.
+    ///
+    /// Parameters:
+    /// - `request` - the request to send, a message ID will be assigned
+    /// - `timeout` - how long to wait for a response
+    ///
+    /// Returns: the response, which may be an `error` message, or `AppError::Timeout` if no
+    /// response arrived in time
+    pub async fn rpc_async(
+        &self,
+        request: Message,
+        timeout: Duration,
+    ) -> Result<Message, AppError> {
+        let reply = Arc::new(Mutex::new(AsyncReply::default()));
+        let message_id = self.send_request(request, PendingReply::Async(reply.clone()));
+        let deadline = executor::sleep(timeout);
+        let result = poll_fn(|context| {
+            let mut reply = reply
+                .lock()
+                .expect("Unable to check for response: lock poisoned");
+            if let Some(response) = reply.response.take() {
+                return Poll::Ready(Ok(response));
+            }
+            reply.waker = Some(context.waker().clone());
+            if deadline.poll_elapsed(context) {
+                return Poll::Ready(Err(Timeout));
+            }
+            Poll::Pending
+        })
+        .await;
+        self.deregister(message_id);
+        result
+    }
+
+    /// Assign the request a message ID, register how its response should be delivered and send it
+    ///
+    /// Returns: the message ID
+    fn send_request(&self, mut request: Message, pending_reply: PendingReply) -> usize {
+        let message_id = self.get_and_increment_message_id();
+        request.body.msg_id = Some(message_id);
+        self.pending_replies
+            .lock()
+            .expect("Unable to register request: lock poisoned")
+            .insert(message_id, pending_reply);
+        self.response_sender
+            .send(request)
+            .expect("Message receiver has been closed");
+        message_id
+    }
+
+    fn deregister(&self, message_id: usize) {
+        self.pending_replies
+            .lock()
+            .expect("Unable to deregister request: lock poisoned")
+            .remove(&message_id);
+    }
+
+    /// Hand a response to the `rpc` call that is waiting for it. The server calls this for every
+    /// message it receives, a custom event loop must do the same for `rpc` to receive responses.
+    ///
+    /// Returns: the message if it is not a response to an `rpc` call, so that it can be processed
+    /// as a request
+    pub fn complete_rpc(&self, message: Message) -> Option<Message> {
+        let Some(in_reply_to) = message.body.in_reply_to else {
+            return Some(message);
+        };
+        let pending_reply = self
+            .pending_replies
+            .lock()
+            .expect("Unable to look up request: lock poisoned")
+            .remove(&in_reply_to);
+        match pending_reply {
+            // the caller may have given up waiting, in which case the response is discarded
+            Some(PendingReply::Blocking(reply_sender)) => {
+                let _ = reply_sender.send(message);
+                None
+            }
+            Some(PendingReply::Async(reply)) => {
+                let mut reply = reply
+                    .lock()
+                    .expect("Unable to deliver response: lock poisoned");
+                reply.response = Some(message);
+                if let Some(waker) = reply.waker.take() {
+                    waker.wake();
+                }
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+/// Returns: the position of a value on the consistent hashing ring
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::mpsc;
+
+    fn node_ids() -> Vec<String> {
+        (1..=5).map(|index| format!("n{}", index)).collect()
+    }
+
+    fn node(node_id: &str) -> Node {
+        Node::new(node_id.to_string(), node_ids(), mpsc::channel().0)
+    }
+
+    #[test]
+    fn owners_are_distinct_and_start_with_the_owner() {
+        let n1 = node("n1");
+        for key in 0..100 {
+            let owners = n1.owners(&key, 3);
+            assert_eq!(owners.len(), 3);
+            assert_eq!(owners.iter().collect::<HashSet<_>>().len(), 3);
+            assert_eq!(Some(owners[0]), n1.owner(&key));
+        }
+        // there cannot be more owners than nodes
+        assert_eq!(n1.owners("key", 10).len(), 5);
+    }
+
+    #[test]
+    fn every_node_agrees_on_the_owners_of_a_key() {
+        let nodes: Vec<Node> = node_ids().iter().map(|node_id| node(node_id)).collect();
+        for key in 0..100 {
+            let owners = nodes[0].owners(&key, 2);
+            for other in &nodes[1..] {
+                assert_eq!(other.owners(&key, 2), owners);
+            }
+        }
+        // keys are spread over the cluster
+        let owners: HashSet<&str> = (0..100).filter_map(|key| nodes[0].owner(&key)).collect();
+        assert_eq!(owners.len(), 5);
+    }
+
+    #[test]
+    fn index_and_leader_follow_the_order_of_the_node_ids() {
+        assert_eq!(node("n1").index(), Some(0));
+        assert_eq!(node("n4").index(), Some(3));
+        assert_eq!(node("n6").index(), None);
+        for node_id in node_ids() {
+            assert_eq!(node(&node_id).leader(), Some("n1"));
+        }
+        assert!(node("n1").is_leader());
+        assert!(!node("n2").is_leader());
+        assert_eq!(node("n2").peers().count(), 4);
+    }
+
+    #[test]
+    fn set_topology_replaces_the_neighbours() {
+        let n1 = node("n1");
+        assert!(n1.neighbours().is_empty());
+        let topology = |neighbours: &[&str]| {
+            HashMap::from([(
+                "n1".to_string(),
+                neighbours
+                    .iter()
+                    .map(|node_id| node_id.to_string())
+                    .collect(),
+            )])
+        };
+        n1.set_topology(topology(&["n2", "n3"]));
+        assert_eq!(n1.neighbours(), vec!["n2", "n3"]);
+        n1.set_topology(topology(&["n4"]));
+        assert_eq!(n1.neighbours(), vec!["n4"]);
+        assert_eq!(n1.topology(), topology(&["n4"]));
+    }
 }
@@ -1,9 +1,12 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
+use serde_json::{Map, Value};
 use serde_with::skip_serializing_none;
 
+use crate::node::Node;
+
 /// A Maelstrom message, which can be either an input to or output of the application.
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
 pub struct Message {
@@ -17,7 +20,7 @@ pub struct Message {
 
 impl Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Message[src={}, dest={}, body.message_type={:#?}, body.msg_id={:?}, body.in_reply_to={:#?}]",
+        write!(f, "Message[src={}, dest={}, body.message_type={:?}, body.msg_id={:?}, body.in_reply_to={:?}]",
             self.src,
             self.dest,
             self.body.message_type,
@@ -26,9 +29,12 @@ impl Display for Message {
     }
 }
 
+/// The payload of a Maelstrom message. Only `message_type` is always present, which of the other
+/// fields are depends on the type of the message.
 #[skip_serializing_none]
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct MessageBody {
+    /// Identifies the request or response, and so which fields are expected
     #[serde(rename = "type")]
     pub message_type: MessageType,
 
@@ -46,6 +52,8 @@ pub struct MessageBody {
     pub node_ids: Option<Vec<String>>,
 
     // echo fields
+    /// Applicable to `MessageType::echo` and `MessageType::echo_ok` messages only:
+    /// The text to send back to the caller
     pub echo: Option<String>,
 
     // error fields
@@ -65,10 +73,229 @@ pub struct MessageBody {
     // broadcast fields
     /// Applicable to `MessageType::broadcast` messages only:
     /// A single message to broadcast to everyone
-    pub message: Option<Box<RawValue>>,
-    /// Applicable to `MessageType::read_ok` messages only:
-    /// All messages present on a node
-    pub messages: Option<Vec<Box<RawValue>>>,
+    pub message: Option<Value>,
+    /// Applicable to `MessageType::read_ok`, `MessageType::sync_ok`, `MessageType::ihave` and
+    /// `MessageType::graft` messages only:
+    /// All messages present on a node, or the subset that is being announced or requested. They
+    /// are kept as raw JSON so that a node can send the messages it stores without copying them.
+    pub messages: Option<Vec<Arc<RawValue>>>,
+    /// Applicable to `MessageType::read` and `MessageType::sync` messages only:
+    /// Only messages received after this position in the node's log are requested. Applicable to
+    /// `MessageType::read_ok` and `MessageType::sync_ok` messages only: the position to request
+    /// from next time in order to receive only new messages.
+    pub offset: Option<usize>,
+    /// Applicable to `MessageType::read_ok` and `MessageType::sync_ok` messages only:
+    /// Identifies the sender's log, it changes whenever the log starts again from empty so that
+    /// offsets into the previous log are not reused
+    pub incarnation: Option<u64>,
+
+    // counter fields
+    /// Applicable to `MessageType::add` messages only:
+    /// The amount by which to change a counter, which may be negative
+    pub delta: Option<i64>,
+    /// Applicable to `MessageType::read_ok` messages from counters and sets: the current value of
+    /// the counter or all the elements of the set. Applicable to `MessageType::replicate`
+    /// messages: the sender's replicated state.
+    pub value: Option<Value>,
+
+    // set fields
+    /// Applicable to `MessageType::add` messages to sets only:
+    /// The element to add to the set
+    pub element: Option<Value>,
+
+    // log and key/value fields
+    /// Applicable to `MessageType::send` messages: the log to append to. Applicable to
+    /// key/value service requests: the key to operate on.
+    pub key: Option<Value>,
+    /// Applicable to `MessageType::send` messages only:
+    /// The message to append to the log
+    pub msg: Option<Value>,
+    /// Applicable to `MessageType::poll`, `MessageType::commit_offsets` and
+    /// `MessageType::list_committed_offsets_ok` messages only:
+    /// An offset for each log
+    pub offsets: Option<HashMap<String, usize>>,
+    /// Applicable to `MessageType::poll_ok` messages only:
+    /// For each log, pairs of offset and message starting at the requested offset
+    pub msgs: Option<HashMap<String, Vec<(usize, Value)>>>,
+    /// Applicable to `MessageType::list_committed_offsets` messages only:
+    /// The logs for which to list the committed offsets
+    pub keys: Option<Vec<String>>,
+    /// Applicable to `MessageType::cas` messages only:
+    /// The value the key is expected to have
+    pub from: Option<Value>,
+    /// Applicable to `MessageType::cas` messages only:
+    /// The value to set if the key has the expected value
+    pub to: Option<Value>,
+    /// Applicable to `MessageType::cas` messages only:
+    /// Whether to treat a missing key as having the expected value
+    pub create_if_not_exists: Option<bool>,
+
+    // consensus fields
+    /// Applicable to `MessageType::request_vote`, `MessageType::append_entries` and their
+    /// responses only:
+    /// The sender's current term
+    pub term: Option<u64>,
+    /// Applicable to `MessageType::request_vote` messages only:
+    /// The node requesting the vote
+    pub candidate_id: Option<String>,
+    /// Applicable to `MessageType::request_vote` messages only:
+    /// The index of the candidate's last log entry
+    pub last_log_index: Option<usize>,
+    /// Applicable to `MessageType::request_vote` messages only:
+    /// The term of the candidate's last log entry
+    pub last_log_term: Option<u64>,
+    /// Applicable to `MessageType::request_vote_ok` messages only:
+    /// Whether the candidate received the vote
+    pub vote_granted: Option<bool>,
+    /// Applicable to `MessageType::append_entries` messages only:
+    /// The leader, so that followers can redirect clients to it
+    pub leader_id: Option<String>,
+    /// Applicable to `MessageType::append_entries` messages only:
+    /// The index of the log entry immediately preceding the new ones
+    pub prev_log_index: Option<usize>,
+    /// Applicable to `MessageType::append_entries` messages only:
+    /// The term of the log entry immediately preceding the new ones
+    pub prev_log_term: Option<u64>,
+    /// Applicable to `MessageType::append_entries` messages only:
+    /// The log entries to store, empty for a heartbeat
+    pub entries: Option<Vec<LogEntry>>,
+    /// Applicable to `MessageType::append_entries` messages only:
+    /// The leader's commit index
+    pub leader_commit: Option<usize>,
+    /// Applicable to `MessageType::append_entries_ok` messages only:
+    /// Whether the follower's log contained the entry preceding the new ones
+    pub success: Option<bool>,
+    /// Applicable to `MessageType::append_entries_ok` messages only:
+    /// The index of the last entry the follower's log is known to share with the leader's
+    pub match_index: Option<usize>,
+
+    // transaction fields
+    /// Applicable to `MessageType::txn` and `MessageType::txn_ok` messages only:
+    /// The operations to perform, and in the response, the operations performed including the
+    /// values read
+    pub txn: Option<Vec<MicroOp>>,
+
+    // timestamp oracle fields
+    /// Applicable to `MessageType::ts_ok` messages only:
+    /// A timestamp greater than any previously issued
+    pub ts: Option<u64>,
+
+    /// Any fields not modelled above, by name. They are kept so that handlers can read the fields
+    /// of workloads this library does not know, and are sent along with the modelled fields. A
+    /// modelled field must not be added here, as it would be sent twice, which
+    /// `Message::with_extra` checks in debug builds.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A single operation within a transaction, serialised as a `[function, key, value]` array
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "(String, Value, Value)", into = "(String, Value, Value)")]
+pub enum MicroOp {
+    /// `["r", key, null]` in a request, `["r", key, value]` in a response where the value is
+    /// `null` if the key has not been written
+    Read {
+        /// The key to read
+        key: Value,
+        /// The value read, absent from requests
+        value: Option<Value>,
+    },
+    /// `["w", key, value]`
+    Write {
+        /// The key to write
+        key: Value,
+        /// The new value of the key
+        value: Value,
+    },
+    /// `["append", key, value]` appends the value to the list stored under the key
+    Append {
+        /// The key of the list
+        key: Value,
+        /// The element to append
+        value: Value,
+    },
+}
+
+impl TryFrom<(String, Value, Value)> for MicroOp {
+    type Error = String;
+
+    fn try_from((function, key, value): (String, Value, Value)) -> Result<Self, Self::Error> {
+        match function.as_str() {
+            "r" => Ok(MicroOp::Read {
+                key,
+                value: Some(value).filter(|value| !value.is_null()),
+            }),
+            "w" => Ok(MicroOp::Write { key, value }),
+            "append" => Ok(MicroOp::Append { key, value }),
+            other => Err(format!("Unknown micro-operation: {}", other)),
+        }
+    }
+}
+
+impl From<MicroOp> for (String, Value, Value) {
+    fn from(operation: MicroOp) -> Self {
+        match operation {
+            MicroOp::Read { key, value } => ("r".to_string(), key, value.unwrap_or(Value::Null)),
+            MicroOp::Write { key, value } => ("w".to_string(), key, value),
+            MicroOp::Append { key, value } => ("append".to_string(), key, value),
+        }
+    }
+}
+
+/// An entry in a replicated log
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    /// The term in which the leader received the entry
+    pub term: u64,
+    /// The operation to apply once the entry is committed, in a form specific to the workload
+    pub command: Value,
+}
+
+impl MessageBody {
+    /// Returns: a body of the given type with every other field absent
+    pub fn new(message_type: MessageType) -> Self {
+        Self {
+            message_type,
+            msg_id: None,
+            in_reply_to: None,
+            node_id: None,
+            node_ids: None,
+            echo: None,
+            code: None,
+            text: None,
+            topology: None,
+            message: None,
+            messages: None,
+            offset: None,
+            incarnation: None,
+            delta: None,
+            value: None,
+            element: None,
+            key: None,
+            msg: None,
+            offsets: None,
+            msgs: None,
+            keys: None,
+            from: None,
+            to: None,
+            create_if_not_exists: None,
+            term: None,
+            candidate_id: None,
+            last_log_index: None,
+            last_log_term: None,
+            vote_granted: None,
+            leader_id: None,
+            prev_log_index: None,
+            prev_log_term: None,
+            entries: None,
+            leader_commit: None,
+            success: None,
+            match_index: None,
+            txn: None,
+            ts: None,
+            extra: Map::new(),
+        }
+    }
 }
 
 impl PartialEq for MessageBody {
@@ -81,51 +308,199 @@ impl PartialEq for MessageBody {
 
 impl Eq for MessageBody {}
 
-/// For more details, see https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md
-#[derive(Deserialize, Serialize, Debug, Hash, Eq, PartialEq, Copy, Clone)]
+/// For more details, see <https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md>
+#[derive(Deserialize, Serialize, Debug, Hash, Eq, PartialEq, Clone)]
 #[allow(non_camel_case_types)]
 pub enum MessageType {
+    /// "At the start of a test, Maelstrom issues a single init message to each node." It informs
+    /// the node of its ID and of every node in the cluster.
     init,
+    /// Acknowledges `init`
     init_ok,
+    /// Indicates that a request failed, see `AppError`
     error,
     /// "A simple echo workload: sends a message, and expects to get that same message back."
     echo,
+    /// Acknowledges `echo`
     echo_ok,
     /// "Sends a single message into the broadcast system, and requests that it be broadcast to
     /// everyone."
     broadcast,
+    /// Acknowledges `broadcast`
     broadcast_ok,
     /// "A topology message is sent at the start of the test, after initialization, and informs the
     /// node of an optional network topology to use for broadcast. The topology consists of a map of
     /// node IDs to lists of neighbor node IDs."
     topology,
+    /// Acknowledges `topology`
     topology_ok,
     /// "Requests all messages present on a node."
+    /// Key/value services: reads the value of a key
     read,
+    /// Acknowledges `read`
     read_ok,
+    /// Requests all messages a node has received since a given offset, used by nodes to catch up
+    /// on broadcasts they may have missed.
+    sync,
+    /// Acknowledges `sync`
+    sync_ok,
+    /// Counters: "Adds a (potentially negative) integer, called delta, to the counter."
+    /// Sets: "Requests that a server add a single element to the set."
+    add,
+    /// Acknowledges `add`
+    add_ok,
+    /// Sends a node's state to another node so that it can be merged into the recipient's state
+    replicate,
+    /// "Requests that a "msg" value be appended to a log identified by "key"."
+    send,
+    /// Acknowledges `send`
+    send_ok,
+    /// "Requests that a node return messages from a set of logs starting from the given offset in
+    /// each log."
+    poll,
+    /// Acknowledges `poll`
+    poll_ok,
+    /// "Informs the node that messages have been successfully processed up to and including the
+    /// given offset."
+    commit_offsets,
+    /// Acknowledges `commit_offsets`
+    commit_offsets_ok,
+    /// "Returns a map of committed offsets for a given set of logs."
+    list_committed_offsets,
+    /// Acknowledges `list_committed_offsets`
+    list_committed_offsets_ok,
+    /// Key/value services: sets the value of a key
+    write,
+    /// Acknowledges `write`
+    write_ok,
+    /// Key/value services: sets the value of a key only if it currently has a given value
+    cas,
+    /// Acknowledges `cas`
+    cas_ok,
+    /// Timestamp oracle: requests a timestamp greater than any previously issued
+    ts,
+    /// Acknowledges `ts`
+    ts_ok,
+    /// "Requests that the node execute a single transaction."
+    txn,
+    /// Acknowledges `txn`
+    txn_ok,
+    /// Raft: a candidate asks for a node's vote in a leader election
+    request_vote,
+    /// Acknowledges `request_vote`
+    request_vote_ok,
+    /// Raft: the leader replicates its log to a follower, also used as a heartbeat
+    append_entries,
+    /// Acknowledges `append_entries`
+    append_entries_ok,
+    /// Plumtree: announces messages a node has received, without sending them in full
+    ihave,
+    /// Plumtree: asks a node to stop eagerly pushing messages to the sender
+    prune,
+    /// Plumtree: asks a node for messages it announced and to eagerly push messages to the sender
+    /// from now on
+    graft,
+    /// Any message type not listed above, as it appears on the wire, so that handlers can serve
+    /// workloads this library does not model and the server can reply that others are not
+    /// supported
+    #[serde(untagged)]
+    Other(String),
+}
+
+/// Who sent a message, following Maelstrom's naming conventions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// A client of the system under test, with an ID such as `c1`
+    Client,
+    /// A member of the cluster, with an ID such as `n1`
+    Node,
+    /// One of Maelstrom's services, such as `lin-kv`
+    Service,
+}
+
+impl Origin {
+    /// Returns: the kind of participant with the given ID
+    pub fn of(id: &str) -> Self {
+        let numbered = |prefix| {
+            id.strip_prefix(prefix).is_some_and(|number| {
+                !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+            })
+        };
+        if numbered('c') {
+            Origin::Client
+        } else if numbered('n') {
+            Origin::Node
+        } else {
+            Origin::Service
+        }
+    }
 }
 
 impl Message {
-    pub fn init_ok(source: &str, destination: &str, message_id: usize, in_reply_to: usize) -> Self {
+    /// Returns: who sent the message
+    pub fn origin(&self) -> Origin {
+        Origin::of(&self.src)
+    }
+
+    /// Returns: a message of the given type with every other field of the body absent. Requests
+    /// to send with `Node::rpc` can be built this way, it assigns the message ID.
+    pub fn new(source: &str, destination: &str, message_type: MessageType) -> Self {
         Self {
             src: source.to_owned(),
             dest: destination.to_owned(),
-            body: MessageBody {
-                message_type: MessageType::init_ok,
-                node_id: None,
-                node_ids: None,
-                echo: None,
-                code: None,
-                msg_id: Some(message_id),
-                in_reply_to: Some(in_reply_to),
-                text: None,
-                topology: None,
-                message: None,
-                messages: None,
-            },
+            body: MessageBody::new(message_type),
         }
     }
 
+    /// Returns: a new request from the node with the next message ID
+    ///
+    /// Parameters:
+    /// - `node` - the sender
+    /// - `destination` - the recipient, another node or a service
+    /// - `message_type` - the type of request
+    pub fn request(node: &Node, destination: &str, message_type: MessageType) -> Self {
+        Self::new(&node.node_id, destination, message_type)
+            .with_msg_id(node.get_and_increment_message_id())
+    }
+
+    /// Returns: a response from the node to this message with the next message ID. Any fields
+    /// other than the type are left for the caller to set, for example
+    /// `request.reply(node, MessageType::echo_ok).with_echo(text)`.
+    pub fn reply(&self, node: &Node, message_type: MessageType) -> Self {
+        let mut reply = Self::request(node, &self.src, message_type);
+        reply.body.in_reply_to = self.body.msg_id;
+        reply
+    }
+
+    /// Returns: a response from the node with the next message ID, for when only the sender and
+    /// ID of the request are known, as in `Response::to_messages`
+    ///
+    /// Parameters:
+    /// - `node` - the node that processed the request
+    /// - `destination` - the sender of the request
+    /// - `in_reply_to` - the request message ID
+    /// - `message_type` - the type of response
+    pub fn reply_to(
+        node: &Node,
+        destination: &str,
+        in_reply_to: usize,
+        message_type: MessageType,
+    ) -> Self {
+        Self::request(node, destination, message_type).with_in_reply_to(in_reply_to)
+    }
+
+    /// Returns: the response to a successful `init` request
+    pub fn init_ok(source: &str, destination: &str, message_id: usize, in_reply_to: usize) -> Self {
+        Self::new(source, destination, MessageType::init_ok)
+            .with_msg_id(message_id)
+            .with_in_reply_to(in_reply_to)
+    }
+
+    /// Returns: a response indicating that a request failed
+    ///
+    /// Parameters:
+    /// - `code` - the Maelstrom error code, see `AppError::code`
+    /// - `text` - a human-readable description of the error
     pub fn error(
         source: &str,
         destination: &str,
@@ -133,22 +508,171 @@ impl Message {
         code: u16,
         text: &str,
     ) -> Self {
-        Self {
-            src: source.to_owned(),
-            dest: destination.to_owned(),
-            body: MessageBody {
-                message_type: MessageType::error,
-                node_id: None,
-                node_ids: None,
-                echo: None,
-                code: Some(code),
-                msg_id: None,
-                in_reply_to: Some(in_reply_to),
-                text: Some(text.to_owned()),
-                topology: None,
-                message: None,
-                messages: None,
-            },
+        Self::new(source, destination, MessageType::error)
+            .with_in_reply_to(in_reply_to)
+            .with_code(code)
+            .with_text(text)
+    }
+
+    /// Returns: the message with a field that is not modelled by `MessageBody` set, see
+    /// `MessageBody::extra`. Modelled fields are set with their own methods instead.
+    pub fn with_extra(mut self, name: &str, value: impl Into<Value>) -> Self {
+        debug_assert!(
+            !MessageBody::MODELLED_FIELDS.contains(&name),
+            "body.{} is modelled, set it with its own method",
+            name
+        );
+        self.body.extra.insert(name.to_owned(), value.into());
+        self
+    }
+}
+
+/// Generates a builder method on `Message` for each field of the body, and the list of the fields
+macro_rules! body_setters {
+    ($($setter:ident => $field:ident: $type:ty,)*) => {
+        impl MessageBody {
+            /// The names of the fields that are not kept in `extra`
+            const MODELLED_FIELDS: &'static [&'static str] = &["type", $(stringify!($field),)*];
+        }
+
+        impl Message {
+            $(
+                #[doc = concat!("Returns: the message with `body.", stringify!($field), "` set")]
+                pub fn $setter(mut self, $field: impl Into<$type>) -> Self {
+                    self.body.$field = Some($field.into());
+                    self
+                }
+            )*
         }
+    };
+}
+
+body_setters! {
+    with_msg_id => msg_id: usize,
+    with_in_reply_to => in_reply_to: usize,
+    with_node_id => node_id: String,
+    with_node_ids => node_ids: Vec<String>,
+    with_echo => echo: String,
+    with_code => code: u16,
+    with_text => text: String,
+    with_topology => topology: HashMap<String, Vec<String>>,
+    with_message => message: Value,
+    with_messages => messages: Vec<Arc<RawValue>>,
+    with_offset => offset: usize,
+    with_incarnation => incarnation: u64,
+    with_delta => delta: i64,
+    with_value => value: Value,
+    with_element => element: Value,
+    with_key => key: Value,
+    with_msg => msg: Value,
+    with_offsets => offsets: HashMap<String, usize>,
+    with_msgs => msgs: HashMap<String, Vec<(usize, Value)>>,
+    with_keys => keys: Vec<String>,
+    with_from => from: Value,
+    with_to => to: Value,
+    with_create_if_not_exists => create_if_not_exists: bool,
+    with_term => term: u64,
+    with_candidate_id => candidate_id: String,
+    with_last_log_index => last_log_index: usize,
+    with_last_log_term => last_log_term: u64,
+    with_vote_granted => vote_granted: bool,
+    with_leader_id => leader_id: String,
+    with_prev_log_index => prev_log_index: usize,
+    with_prev_log_term => prev_log_term: u64,
+    with_entries => entries: Vec<LogEntry>,
+    with_leader_commit => leader_commit: usize,
+    with_success => success: bool,
+    with_match_index => match_index: usize,
+    with_txn => txn: Vec<MicroOp>,
+    with_ts => ts: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unmodelled_types_and_fields_survive_a_round_trip() {
+        let json = json!({
+            "src": "c1",
+            "dest": "n1",
+            "body": {"type": "lock", "msg_id": 1, "lock_id": "l1", "ttl": 30}
+        });
+        let message: Message = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(
+            message.body.message_type,
+            MessageType::Other("lock".to_string())
+        );
+        assert_eq!(message.body.msg_id, Some(1));
+        assert_eq!(message.body.extra.get("lock_id"), Some(&json!("l1")));
+        assert_eq!(message.body.extra.get("ttl"), Some(&json!(30)));
+        assert_eq!(serde_json::to_value(&message).unwrap(), json);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "body.msg_id is modelled")]
+    fn modelled_fields_cannot_be_set_as_extra() {
+        let _ = Message::new("n1", "c1", MessageType::echo_ok).with_extra("msg_id", 1);
+    }
+
+    #[test]
+    fn micro_ops_are_read_and_written_as_arrays() {
+        let json = json!([["r", 1, null], ["w", 2, 3], ["append", 4, 5], ["r", 6, [7]]]);
+        let txn: Vec<MicroOp> = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(
+            txn,
+            vec![
+                MicroOp::Read {
+                    key: json!(1),
+                    value: None
+                },
+                MicroOp::Write {
+                    key: json!(2),
+                    value: json!(3)
+                },
+                MicroOp::Append {
+                    key: json!(4),
+                    value: json!(5)
+                },
+                MicroOp::Read {
+                    key: json!(6),
+                    value: Some(json!([7]))
+                },
+            ]
+        );
+        assert_eq!(serde_json::to_value(&txn).unwrap(), json);
+    }
+
+    #[test]
+    fn unknown_micro_ops_are_rejected() {
+        let error = serde_json::from_value::<MicroOp>(json!(["cas", 1, 2])).unwrap_err();
+        assert!(error.to_string().contains("Unknown micro-operation: cas"));
+    }
+
+    #[test]
+    fn modelled_types_are_not_read_as_other() {
+        let body: MessageBody = serde_json::from_value(json!({"type": "echo_ok"})).unwrap();
+        assert_eq!(body.message_type, MessageType::echo_ok);
+        assert!(body.extra.is_empty());
+    }
+
+    #[test]
+    fn replies_of_unmodelled_types_carry_extra_fields() {
+        let request =
+            Message::new("c1", "n1", MessageType::Other("lock".to_string())).with_msg_id(1_usize);
+        let node = Node::new(
+            "n1".to_string(),
+            vec!["n1".to_string()],
+            std::sync::mpsc::channel().0,
+        );
+        let reply = request
+            .reply(&node, MessageType::Other("lock_ok".to_string()))
+            .with_extra("lock_id", "l1");
+        let json = serde_json::to_value(&reply).unwrap();
+        assert_eq!(json["body"]["type"], json!("lock_ok"));
+        assert_eq!(json["body"]["in_reply_to"], json!(1));
+        assert_eq!(json["body"]["lock_id"], json!("l1"));
     }
 }
@@ -1,30 +1,57 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::io::Write;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::sync::{mpsc, Arc, Mutex};
-use std::{io, thread};
+use std::time::{Duration, Instant};
+use std::{io, iter, thread};
 
 use rayon::{ThreadPool, ThreadPoolBuilder};
 use statsd::Client;
 
-use crate::AppError::{AlreadyInitialised, MissingField};
-use crate::{AppError, Message, MessageType, Node};
+use crate::context::{AsyncRequestContext, RequestContext};
+use crate::executor::{self, BoxFuture, Executor};
+use crate::middleware::{Middleware, Timing};
+use crate::node::AppError::{AlreadyInitialised, MissingField, Unavailable};
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType};
+
+/// How often to forward messages that a handler sends after it has finished processing a request
+const LATE_RESPONSE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many requests to keep for processing once the node has been initialised. Later requests
+/// received before initialisation are rejected as temporarily unavailable.
+const MAX_PENDING_REQUESTS: usize = 1024;
 
 /// A server plugin that responds to Maelstrom [workload](https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md) requests
 /// and may initialise daemons
 pub trait Module: Sync + Send {
-    /// Initialise the module. This may be used to set up any daemon workers.
+    /// Initialise the module. This may be used to set up any daemon workers. By default, this does
+    /// nothing.
     /// Parameters:
     /// - `response_sender` - a channel for sending network messages asynchronously, outside the scope of a single request
-    fn init(&mut self, response_sender: Sender<Message>);
+    fn init(&mut self, _response_sender: Sender<Message>) {}
+
+    /// Prepare the module once the node has been initialised, which happens after `init`. This is
+    /// called before the module processes any requests and before any timer first ticks, so it
+    /// may be used to load state kept under the node's ID or to start daemons that need the node.
+    /// By default, this does nothing.
+    ///
+    /// Parameters:
+    /// - `node` - the initialised node
+    fn on_init(&self, _node: &Arc<Node>) {}
 
     /// Process a workload request.
     ///
     /// Parameters:
-    /// - `response_sender` - a channel for sending any responses in response to the workload request
-    /// - `node` - the node in the cluster on which the request is being processed
-    /// - `request` - a message received from either a client or another cluster member
-    fn handle_request(&self, response_sender: Sender<Message>, node: &Node, request: &Message);
+    /// - `response_sender` - a channel for sending any responses in response to the workload
+    ///   request, which passes them through the middleware. Messages sent once this returns are
+    ///   sent without passing through the middleware, but the channel passed to `init` is
+    ///   intended for that.
+    /// - `context` - the request, received from either a client or another cluster member, and
+    ///   the node in the cluster on which it is being processed
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext);
 }
 
 /// A Maelstrom [workload](https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md)
@@ -33,13 +60,13 @@ pub trait Module: Sync + Send {
 pub trait RequestHandler: Sync + Send {
     /// Process the workload request.
     /// Parameters:
-    /// - `node` - the node in the cluster on which the request is being processed
-    /// - `request` - a message received from either a client or another cluster member
+    /// - `context` - the request, received from either a client or another cluster member, and
+    ///   the node in the cluster on which it is being processed
+    ///
     /// Returns:
     /// - `Box<dyn Response>` - if the request was successfully processed
     /// - `AppError` - if the request could not be processed
-    fn handle_request(&self, node: &Node, request: &Message)
-        -> Result<Box<dyn Response>, AppError>;
+    fn handle(&self, context: &RequestContext) -> Result<Box<dyn Response>, AppError>;
 }
 
 impl<F, R> RequestHandler for F
@@ -47,12 +74,8 @@ where
     F: Fn(&Node, &Message) -> Result<R, AppError> + Sync + Send,
     R: Response + 'static,
 {
-    fn handle_request(
-        &self,
-        node: &Node,
-        request: &Message,
-    ) -> Result<Box<dyn Response>, AppError> {
-        let response = self(node, request)?;
+    fn handle(&self, context: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        let response = self(context.node(), context.request())?;
         Ok(Box::new(response))
     }
 }
@@ -62,12 +85,13 @@ struct RequestHandlerModule {
 }
 
 impl Module for RequestHandlerModule {
-    fn init(&mut self, _response_sender: Sender<Message>) {}
-
-    fn handle_request(&self, response_sender: Sender<Message>, node: &Node, request: &Message) {
-        let result = self.delegate.handle_request(node, request);
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+        let (node, request) = (context.node(), context.request());
+        let result = self.delegate.handle(context);
         let messages = match result {
-            Ok(response) => response.to_messages(node, &request.src, request.body.msg_id.unwrap()),
+            Ok(response) => {
+                response.into_messages(node, &request.src, request.body.msg_id.unwrap())
+            }
             Err(error) => {
                 vec![error.to_message(&node.node_id, &request.src, request.body.msg_id.unwrap())]
             }
@@ -84,6 +108,238 @@ impl From<Box<dyn RequestHandler>> for RequestHandlerModule {
     }
 }
 
+/// The async equivalent of `Module`. Rather than occupying a thread until a request has been
+/// processed, the module returns a future which the server runs on its executor. While the future
+/// awaits, for example a response to `Node::rpc_async`, the thread is free to process other
+/// requests.
+pub trait AsyncModule: Sync + Send {
+    /// Initialise the module. This may be used to set up any daemon workers. By default, this does
+    /// nothing.
+    /// Parameters:
+    /// - `response_sender` - a channel for sending network messages asynchronously, outside the scope of a single request
+    fn init(&mut self, _response_sender: Sender<Message>) {}
+
+    /// Prepare the module once the node has been initialised, which happens after `init`. This is
+    /// called before the module processes any requests and before any timer first ticks, so it
+    /// may be used to load state kept under the node's ID or to start daemons that need the node.
+    /// By default, this does nothing.
+    ///
+    /// Parameters:
+    /// - `node` - the initialised node
+    fn on_init(&self, _node: &Arc<Node>) {}
+
+    /// Process a workload request. Any state the future needs should be shared with the module,
+    /// for example through an `Arc`.
+    ///
+    /// Parameters:
+    /// - `response_sender` - a channel for sending any responses in response to the workload
+    ///   request, which passes them through the middleware. Messages sent once the future has
+    ///   completed are sent without passing through the middleware, but the channel passed to
+    ///   `init` is intended for that.
+    /// - `context` - the request, received from either a client or another cluster member, and
+    ///   the node in the cluster on which it is being processed
+    fn handle(
+        &self,
+        response_sender: Sender<Message>,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, ()>;
+}
+
+/// The async equivalent of `RequestHandler`. This is implemented for any function taking the node
+/// and the request that returns a future, such as an `async fn`.
+pub trait AsyncRequestHandler: Sync + Send {
+    /// Process the workload request.
+    /// Parameters:
+    /// - `context` - the request, received from either a client or another cluster member, and
+    ///   the node in the cluster on which it is being processed
+    ///
+    /// Returns: a future resolving to
+    /// - `Box<dyn Response>` - if the request was successfully processed
+    /// - `AppError` - if the request could not be processed
+    fn handle(
+        &self,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, Result<Box<dyn Response>, AppError>>;
+}
+
+impl<F, Fut, R> AsyncRequestHandler for F
+where
+    F: Fn(Arc<Node>, Message) -> Fut + Sync + Send,
+    Fut: Future<Output = Result<R, AppError>> + Send + 'static,
+    R: Response + 'static,
+{
+    fn handle(
+        &self,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, Result<Box<dyn Response>, AppError>> {
+        let response = self(context.node().clone(), context.request().clone());
+        Box::pin(async move { Ok(Box::new(response.await?) as Box<dyn Response>) })
+    }
+}
+
+struct AsyncRequestHandlerModule {
+    delegate: Box<dyn AsyncRequestHandler>,
+}
+
+impl AsyncModule for AsyncRequestHandlerModule {
+    fn handle(
+        &self,
+        response_sender: Sender<Message>,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, ()> {
+        let response = self.delegate.handle(context.clone());
+        Box::pin(async move {
+            let (node, request) = (context.node(), context.request());
+            let request_id = request.body.msg_id.unwrap();
+            let messages = match response.await {
+                Ok(response) => response.into_messages(node, &request.src, request_id),
+                Err(error) => vec![error.to_message(&node.node_id, &request.src, request_id)],
+            };
+            for message in messages {
+                response_sender.send(message).unwrap();
+            }
+        })
+    }
+}
+
+/// How requests of a given type are processed
+enum Handler {
+    Blocking(Box<dyn Module>),
+    Async(Box<dyn AsyncModule>),
+}
+
+impl Handler {
+    fn init(&mut self, response_sender: Sender<Message>) {
+        match self {
+            Handler::Blocking(module) => module.init(response_sender),
+            Handler::Async(module) => module.init(response_sender),
+        }
+    }
+
+    fn on_init(&self, node: &Arc<Node>) {
+        match self {
+            Handler::Blocking(module) => module.on_init(node),
+            Handler::Async(module) => module.on_init(node),
+        }
+    }
+}
+
+/// Decides whether a handler should process a request
+pub type Predicate = Box<dyn Fn(&Message) -> bool + Sync + Send>;
+
+/// A handler and the requests of its type that it processes
+struct Route {
+    /// Accepts the requests to process, or `None` to process all of them
+    predicate: Option<Predicate>,
+    handler: Handler,
+}
+
+/// The handlers installed for each message type
+struct Routes {
+    by_type: HashMap<MessageType, Vec<Route>>,
+    /// Processes requests that no route accepts
+    fallback: Handler,
+}
+
+impl Routes {
+    /// Let every handler prepare for the node, see `Module::on_init`
+    fn on_init(&self, node: &Arc<Node>) {
+        for route in self.by_type.values().flatten() {
+            route.handler.on_init(node);
+        }
+        self.fallback.on_init(node);
+    }
+
+    /// Returns: every handler that accepts the request, in the order they were installed, or the
+    /// fallback if there are none
+    fn handlers(&self, request: &Message) -> Vec<&Handler> {
+        let handlers: Vec<&Handler> = self
+            .by_type
+            .get(&request.body.message_type)
+            .into_iter()
+            .flatten()
+            .filter(|route| {
+                route
+                    .predicate
+                    .as_ref()
+                    .is_none_or(|accepts| accepts(request))
+            })
+            .map(|route| &route.handler)
+            .collect();
+        if handlers.is_empty() {
+            vec![&self.fallback]
+        } else {
+            handlers
+        }
+    }
+}
+
+/// The default fallback, which replies that the request is not supported
+struct Unsupported;
+
+impl Module for Unsupported {
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+        let (node, request) = (context.node(), context.request());
+        eprintln!("No handler for: {:?}", request.body.message_type);
+        let response = Message::error(
+            &node.node_id,
+            &request.src,
+            request.body.msg_id.unwrap(),
+            10,
+            &format!("Not yet implemented: {:?}", request.body.message_type),
+        );
+        response_sender
+            .send(response)
+            .expect("Message receiver has been closed");
+    }
+}
+
+/// How the server schedules requests on its thread pool. Responses to `Node::rpc` calls are not
+/// requests and are always delivered immediately. Async handlers are started in order, but their
+/// futures may complete in any order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Execution {
+    /// Requests are processed concurrently, in no particular order
+    #[default]
+    Parallel,
+    /// Requests are processed one at a time, in the order they arrive
+    Ordered,
+    /// Requests from the same sender are processed one at a time, in the order they arrive.
+    /// Requests from different senders are processed concurrently. A blocking handler must not
+    /// wait for a later request from the sender of the request it is processing, as that request
+    /// will not be processed until the handler returns.
+    OrderedPerSender,
+}
+
+impl Execution {
+    /// Returns: the key identifying the requests which must be processed in order with this one,
+    /// or `None` if it can be processed at any time
+    fn ordering_key(&self, request: &Message) -> Option<String> {
+        match self {
+            Execution::Parallel => None,
+            Execution::Ordered => Some(String::new()),
+            Execution::OrderedPerSender => Some(request.src.clone()),
+        }
+    }
+}
+
+/// The requests waiting for an earlier request with the same ordering key to be processed. A key
+/// is present while one of its requests is being processed.
+type RequestQueues = Mutex<HashMap<String, VecDeque<Message>>>;
+
+/// A periodic task, such as anti-entropy, that runs once the node has been initialised.
+pub trait Timer: Sync + Send {
+    /// How long to wait between invocations
+    fn interval(&self) -> Duration;
+
+    /// Perform the periodic task.
+    ///
+    /// Parameters:
+    /// - `response_sender` - a channel for sending network messages
+    /// - `node` - the node on which the task is running
+    fn tick(&self, response_sender: Sender<Message>, node: &Node);
+}
+
 /// The result of processing a workload request.
 /// This may result in zero or one responses to the caller. This may also produce zero or more
 /// messages to other cluster members.
@@ -95,8 +351,20 @@ pub trait Response {
     /// - `node` - the node that processed the request
     /// - `caller` - the node that initiated the request
     /// - `in_reply_to` - the request message ID, unique to `caller`
+    ///
     /// Returns: the Maelstrom messages to send over the network in the order they should be sent
     fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message>;
+
+    /// The same as `to_messages`, but consumes the response so that an implementation can move
+    /// large values into the messages rather than clone them. This is what the server calls.
+    fn into_messages(
+        self: Box<Self>,
+        node: &Node,
+        caller: &str,
+        in_reply_to: usize,
+    ) -> Vec<Message> {
+        self.to_messages(node, caller, in_reply_to)
+    }
 }
 
 /// The main entity responsible for listening on the Maelstrom network and sending out messages. It
@@ -104,62 +372,251 @@ pub trait Response {
 /// specific handlers. Once all handlers are installed, call `run()` to listen for requests.
 pub struct Server {
     /// A pool on which actual requests will be processed
-    pool: ThreadPool,
+    pool: Arc<ThreadPool>,
+    execution: Execution,
+    /// Runs the futures of async handlers on the pool
+    executor: Executor,
     /// The client-defined handlers for each message type
-    handlers: Arc<HashMap<MessageType, Box<dyn Module>>>,
+    handlers: Arc<Routes>,
+    /// The client-defined periodic tasks
+    timers: Vec<Arc<dyn Timer>>,
     response_sender: Sender<Message>,
     response_receiver: Arc<Mutex<Receiver<Message>>>,
-    stats: Arc<Client>,
+    /// The middleware wrapping every handler, outermost first
+    middlewares: Arc<Vec<Box<dyn Middleware>>>,
 }
 
+/// Configures a `Server`. Obtain one with `Server::builder()`, install the workload's handlers,
+/// modules and timers, then call `build()`.
 #[derive(Default)]
 pub struct ServerBuilder {
-    handlers: HashMap<MessageType, Box<dyn Module>>,
-    thread_pool_builder: ThreadPoolBuilder,
+    handlers: HashMap<MessageType, Vec<Route>>,
+    fallback: Option<Handler>,
+    timers: Vec<Arc<dyn Timer>>,
+    /// The number of worker threads, `0` for rayon's default
+    threads: usize,
+    thread_name: Option<String>,
+    execution: Execution,
+    middlewares: Vec<Box<dyn Middleware>>,
     stats: Option<Arc<Client>>,
 }
 
 impl ServerBuilder {
+    /// Initialise the installed modules and create the server
     pub fn build(mut self) -> Server {
         let (response_sender, response_receiver) = mpsc::channel();
-        for handler in self.handlers.values_mut() {
-            handler.init(response_sender.clone());
+        let mut fallback = self
+            .fallback
+            .unwrap_or_else(|| Handler::Blocking(Box::new(Unsupported)));
+        fallback.init(response_sender.clone());
+        for route in self.handlers.values_mut().flatten() {
+            route.handler.init(response_sender.clone());
         }
-        let pool = self
-            .thread_pool_builder
-            .build()
-            .expect("Unable to create thread pool");
-        let handlers = Arc::new(self.handlers);
+        let mut thread_pool_builder = ThreadPoolBuilder::new().num_threads(self.threads);
+        if let Some(thread_name) = self.thread_name {
+            thread_pool_builder =
+                thread_pool_builder.thread_name(move |index| format!("{}-{}", thread_name, index));
+        }
+        let pool = Arc::new(
+            thread_pool_builder
+                .build()
+                .expect("Unable to create thread pool"),
+        );
+        let executor = Executor::new(pool.clone());
+        let handlers = Arc::new(Routes {
+            by_type: self.handlers,
+            fallback,
+        });
         let stats = self
             .stats
             .unwrap_or_else(|| Arc::new(Client::new("localhost:8125", "maelstrom").unwrap()));
+        let mut middlewares: Vec<Box<dyn Middleware>> = vec![Box::new(Timing::new(stats))];
+        middlewares.extend(self.middlewares);
         Server {
             pool,
+            execution: self.execution,
+            executor,
             handlers,
+            timers: self.timers,
             response_sender,
             response_receiver: Arc::new(Mutex::new(response_receiver)),
-            stats,
+            middlewares: Arc::new(middlewares),
         }
     }
 
+    /// Process requests of the given type with a handler. The handler's response, or error, is
+    /// sent to the caller.
+    ///
+    /// Several handlers and modules may be installed for the same type, for example to observe
+    /// requests as well as respond to them. Each request is processed by all of them, in the order
+    /// they were installed.
     pub fn with_handler(self, message_type: MessageType, handler: Box<dyn RequestHandler>) -> Self {
         let module = RequestHandlerModule::from(handler);
         self.with_module(message_type, Box::new(module))
     }
 
-    pub fn with_module(mut self, message_type: MessageType, module: Box<dyn Module>) -> Self {
-        self.handlers.insert(message_type, module);
+    /// Process requests of the given type with a module, which is responsible for sending any
+    /// responses. See `with_handler` for how several may be installed for the same type.
+    pub fn with_module(self, message_type: MessageType, module: Box<dyn Module>) -> Self {
+        self.route(message_type, None, Handler::Blocking(module))
+    }
+
+    /// Process requests of the given type with an async handler. The handler's response, or
+    /// error, is sent to the caller. See `with_handler` for how several may be installed for the
+    /// same type.
+    pub fn with_async_handler(
+        self,
+        message_type: MessageType,
+        handler: Box<dyn AsyncRequestHandler>,
+    ) -> Self {
+        let module = AsyncRequestHandlerModule { delegate: handler };
+        self.with_async_module(message_type, Box::new(module))
+    }
+
+    /// Process requests of the given type with an async module, which is responsible for sending
+    /// any responses. See `with_handler` for how several may be installed for the same type.
+    pub fn with_async_module(
+        self,
+        message_type: MessageType,
+        module: Box<dyn AsyncModule>,
+    ) -> Self {
+        self.route(message_type, None, Handler::Async(module))
+    }
+
+    /// Install handlers that only process the requests the predicate accepts, for example
+    /// `builder.when(|request| request.origin() == Origin::Client).with_handler(...)` for those
+    /// from clients.
+    pub fn when(
+        self,
+        predicate: impl Fn(&Message) -> bool + Sync + Send + 'static,
+    ) -> ConditionalBuilder {
+        ConditionalBuilder {
+            builder: self,
+            predicate: Box::new(predicate),
+        }
+    }
+
+    /// Process requests that no other handler or module processes with this module, rather than
+    /// replying that they are not supported. These include requests of types that have no handler,
+    /// those that no installed predicate accepts, and those of types this library does not model,
+    /// which have the type `MessageType::Other`, unless a handler is installed for that type.
+    pub fn with_fallback(mut self, module: Box<dyn Module>) -> Self {
+        self.fallback = Some(Handler::Blocking(module));
+        self
+    }
+
+    fn route(
+        mut self,
+        message_type: MessageType,
+        predicate: Option<Predicate>,
+        handler: Handler,
+    ) -> Self {
+        self.handlers
+            .entry(message_type)
+            .or_default()
+            .push(Route { predicate, handler });
+        self
+    }
+
+    /// Returns: the number of handlers installed for each message type
+    pub(crate) fn handler_counts(&self) -> HashMap<MessageType, usize> {
+        self.handlers
+            .iter()
+            .map(|(message_type, routes)| (message_type.clone(), routes.len()))
+            .collect()
+    }
+
+    /// Run a task periodically once the node is initialised
+    pub fn with_timer(mut self, timer: Arc<dyn Timer>) -> Self {
+        self.timers.push(timer);
         self
     }
 
+    /// Report metrics to the given StatsD client, rather than one sending to `localhost:8125`
     pub fn with_stats(mut self, stats: Arc<Client>) -> Self {
         self.stats = Some(stats);
         self
     }
-    // TODO make ThreadPool configurable
+
+    /// Wrap every handler with a middleware. Middlewares see requests in the order they are
+    /// installed, after the built-in `middleware::Timing`.
+    pub fn with_middleware(mut self, middleware: Box<dyn Middleware>) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Process requests on the given number of worker threads. By default, this is the value of
+    /// the `RAYON_NUM_THREADS` environment variable or, failing that, the number of CPUs. Input is
+    /// read on the thread that calls `Server::run`, so even a single worker is never starved.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Name the worker threads `<prefix>-<index>`, as shown in debuggers and panic messages
+    pub fn with_thread_name(mut self, prefix: &str) -> Self {
+        self.thread_name = Some(prefix.to_string());
+        self
+    }
+
+    /// Change how requests are scheduled on the worker threads, by default they are processed in
+    /// parallel
+    pub fn with_execution(mut self, execution: Execution) -> Self {
+        self.execution = execution;
+        self
+    }
+}
+
+/// Installs handlers that only process the requests a predicate accepts. Obtain one with
+/// `ServerBuilder::when`.
+pub struct ConditionalBuilder {
+    builder: ServerBuilder,
+    predicate: Predicate,
+}
+
+impl ConditionalBuilder {
+    /// See `ServerBuilder::with_handler`
+    pub fn with_handler(
+        self,
+        message_type: MessageType,
+        handler: Box<dyn RequestHandler>,
+    ) -> ServerBuilder {
+        let module = RequestHandlerModule::from(handler);
+        self.with_module(message_type, Box::new(module))
+    }
+
+    /// See `ServerBuilder::with_module`
+    pub fn with_module(self, message_type: MessageType, module: Box<dyn Module>) -> ServerBuilder {
+        self.builder.route(
+            message_type,
+            Some(self.predicate),
+            Handler::Blocking(module),
+        )
+    }
+
+    /// See `ServerBuilder::with_async_handler`
+    pub fn with_async_handler(
+        self,
+        message_type: MessageType,
+        handler: Box<dyn AsyncRequestHandler>,
+    ) -> ServerBuilder {
+        let module = AsyncRequestHandlerModule { delegate: handler };
+        self.with_async_module(message_type, Box::new(module))
+    }
+
+    /// See `ServerBuilder::with_async_module`
+    pub fn with_async_module(
+        self,
+        message_type: MessageType,
+        module: Box<dyn AsyncModule>,
+    ) -> ServerBuilder {
+        self.builder
+            .route(message_type, Some(self.predicate), Handler::Async(module))
+    }
 }
 
 impl Server {
+    /// Returns: a builder for a server with no workload handlers installed
     pub fn builder() -> ServerBuilder {
         ServerBuilder::default()
     }
@@ -169,115 +626,224 @@ impl Server {
     pub fn run(&self) {
         let responder = self.spawn_message_receiver();
 
-        let mut node = Node {
-            node_id: "Uninitialised Node".to_string(),
-            next_message_id: Default::default(),
-            node_ids: vec![],
+        let mut input = Self::read_requests();
+        let Some((node, pending)) = self.initialise(&mut input) else {
+            // there is nothing to respond to
+            return;
         };
+        let node = Arc::new(node);
+        self.handlers.on_init(&node);
+        let running = Arc::new(AtomicBool::new(true));
+        self.spawn_timers(&node, &running);
 
-        // listen for initial input sequentially
-        loop {
-            let mut buffer = String::new();
-            match io::stdin().read_line(&mut buffer) {
-                Err(e) => {
-                    eprintln!("Input Error, quitting: {}", e);
-                    panic!();
+        let message_sender = self.response_sender.clone();
+        let handlers = self.handlers.clone();
+        let executor = self.executor.clone();
+        let middlewares = self.middlewares.clone();
+        let execution = self.execution;
+        let request_queues: Arc<RequestQueues> = Default::default();
+
+        // Input is read on this thread rather than a worker, so that every worker is available to
+        // process requests
+        self.pool.in_place_scope(move |scope| {
+            // process the requests received before initialisation, then the remaining input
+            for (request, received) in pending.into_iter().chain(input) {
+                // Responses to outstanding requests are delivered here rather than on a worker
+                // thread. Otherwise, if every worker were waiting for a response, none would be
+                // available to deliver them.
+                let Some(request) = node.complete_rpc(request) else {
+                    continue;
+                };
+                // record the topology before any handler can see it, or a later request
+                if let Some(topology) = request
+                    .body
+                    .topology
+                    .as_ref()
+                    .filter(|_| request.body.message_type == MessageType::topology)
+                {
+                    node.set_topology(topology.clone());
                 }
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        // EOF
-                        eprintln!("EOF before initialisation, quitting");
-                        break;
-                    }
-                    let request = match serde_json::from_str::<Message>(&buffer) {
-                        Ok(message) => message,
-                        Err(e) => {
-                            // Note: we cannot respond with an `AppError` because we cannot
-                            // know where to send the response if we couldn't parse the
-                            // JSON.
-                            eprintln!("Unable to parse input, not responding: {}", e);
-                            continue;
-                        }
-                    };
-                    if request.body.msg_id.is_none() {
-                        // Note: we cannot respond with an `AppError` because we cannot
-                        // reference the requesting message ID.
-                        eprintln!(
-                            "Unable to extract message ID, not responding: {:?}",
-                            request
-                        );
-                        continue;
-                    }
-                    let request_id = request.body.msg_id.unwrap();
+                // process each input entry on a worker thread
+                let node = node.clone();
+                let message_sender = message_sender.clone();
+                let handlers = handlers.clone();
+                let executor = executor.clone();
+                let middlewares = middlewares.clone();
+                let process = move |request| {
+                    Self::process_request(
+                        message_sender.clone(),
+                        handlers.clone(),
+                        &executor,
+                        request,
+                        received,
+                        &node,
+                        middlewares.clone(),
+                    )
+                };
 
-                    if request.body.message_type != MessageType::init {
-                        eprintln!("Node is not initialised, dropping request: {:?}", request);
-                        continue;
+                let Some(key) = execution.ordering_key(&request) else {
+                    scope.spawn(move |_| process(request));
+                    continue;
+                };
+                let Some(request) = Self::enqueue(&request_queues, &key, request) else {
+                    continue;
+                };
+                // process the request, then any that were queued behind it meanwhile
+                let request_queues = request_queues.clone();
+                scope.spawn(move |_| {
+                    let mut next = Some(request);
+                    while let Some(request) = next {
+                        process(request);
+                        next = Self::dequeue(&request_queues, &key);
                     }
+                });
+            }
+        });
+        // All inputs have been received
+        running.store(false, Ordering::Release);
+        // Wait for all pending responses to be sent
+        responder.join().unwrap();
+    }
 
-                    let result = if request.body.node_id.is_none() {
-                        Some(MissingField(String::from("body.node_id")))
-                    } else if request.body.node_ids.is_none() {
-                        Some(MissingField(String::from("body.node_ids")))
-                    } else {
-                        None
-                    };
-                    node.node_id = request.body.node_id.unwrap();
-                    node.node_ids = request.body.node_ids.unwrap();
-                    let response_message = if let Some(err) = result {
-                        err.to_message(&node.node_id, &request.src, request_id)
+    /// Read input until a valid `init` request arrives, replying to every `init` request. Up to
+    /// `MAX_PENDING_REQUESTS` other requests received meanwhile are kept so that they can be
+    /// processed once the node is initialised, and any more are rejected.
+    ///
+    /// Parameters:
+    /// - `input` - the messages received, with when each was received. Those after the `init`
+    ///   request are left in it.
+    ///
+    /// Returns: the initialised node and the requests received before it, in order, or `None` if
+    /// the input ended first
+    fn initialise(
+        &self,
+        input: &mut impl Iterator<Item = (Message, Instant)>,
+    ) -> Option<(Node, Vec<(Message, Instant)>)> {
+        let mut pending = vec![];
+        for (request, received) in input {
+            if request.body.message_type != MessageType::init {
+                if pending.len() < MAX_PENDING_REQUESTS {
+                    pending.push((request, received));
+                } else if let Some(request_id) = request.body.msg_id {
+                    // the node has no ID yet, so respond as the node the request was sent to
+                    let error = Unavailable("Node is not yet initialised".to_string());
+                    self.response_sender
+                        .send(error.to_message(&request.dest, &request.src, request_id))
+                        .unwrap();
+                }
+                continue;
+            }
+            let Some(request_id) = request.body.msg_id else {
+                // Note: we cannot respond with an `AppError` because we cannot
+                // reference the requesting message ID.
+                eprintln!(
+                    "Unable to extract message ID, not responding: {:?}",
+                    request
+                );
+                continue;
+            };
+            // the node has no ID until the request is accepted, so any error is sent from the ID
+            // it is being given if there is one, otherwise from the node the request was sent to
+            let source = request.body.node_id.clone().unwrap_or(request.dest);
+            let (node_id, node_ids) = match (request.body.node_id, request.body.node_ids) {
+                (Some(node_id), Some(node_ids)) => (node_id, node_ids),
+                (node_id, _) => {
+                    let field = if node_id.is_none() {
+                        "body.node_id"
                     } else {
-                        Message::init_ok(
-                            &node.node_id,
-                            &request.src,
-                            node.get_and_increment_message_id(),
-                            request_id,
-                        )
+                        "body.node_ids"
                     };
-                    self.response_sender.send(response_message).unwrap();
-                    // once the node is initialised, the remaining inputs can be processed
-                    // concurrently
-                    break;
+                    let error = MissingField(field.to_string());
+                    self.response_sender
+                        .send(error.to_message(&source, &request.src, request_id))
+                        .unwrap();
+                    continue;
                 }
+            };
+            let node = Node::new(node_id, node_ids, self.response_sender.clone());
+            self.response_sender
+                .send(Message::init_ok(
+                    &node.node_id,
+                    &request.src,
+                    node.get_and_increment_message_id(),
+                    request_id,
+                ))
+                .unwrap();
+            if !pending.is_empty() {
+                eprintln!(
+                    "Processing {} requests received before initialisation",
+                    pending.len()
+                );
             }
+            return Some((node, pending));
         }
+        eprintln!("EOF before initialisation, quitting");
+        None
+    }
 
-        let node = Arc::new(node);
+    /// Returns: the messages read from standard input, with when each was read, until it ends.
+    /// Lines that are not valid messages are skipped.
+    fn read_requests() -> impl Iterator<Item = (Message, Instant)> {
+        iter::from_fn(|| loop {
+            let mut buffer = String::new();
+            match io::stdin().read_line(&mut buffer) {
+                Err(e) => {
+                    eprintln!("Input Error, quitting: {}", e);
+                    panic!();
+                }
+                // EOF
+                Ok(0) => return None,
+                Ok(_) => {}
+            }
+            let received = Instant::now();
+            if let Some(request) = Self::parse_line(&buffer) {
+                return Some((request, received));
+            }
+        })
+    }
 
-        let message_sender = self.response_sender.clone();
-        let handlers = self.handlers.clone();
-        let stats = self.stats.clone();
+    /// Queue a request behind any others with the same ordering key that are being processed.
+    ///
+    /// Returns: the request if none with the key are being processed, in which case the caller
+    /// must process it and then the queue, see `dequeue`
+    fn enqueue(request_queues: &RequestQueues, key: &str, request: Message) -> Option<Message> {
+        let mut request_queues = request_queues
+            .lock()
+            .expect("Unable to queue request: lock poisoned");
+        if let Some(queue) = request_queues.get_mut(key) {
+            queue.push_back(request);
+            return None;
+        }
+        request_queues.insert(key.to_string(), VecDeque::new());
+        Some(request)
+    }
 
-        self.pool.scope(move |scope| {
-            // listen for remaining input
-            loop {
-                let mut buffer = String::new();
-                match io::stdin().read_line(&mut buffer) {
-                    Err(e) => {
-                        eprintln!("Input Error: {}", e);
-                        panic!();
-                    }
-                    Ok(bytes_read) => {
-                        if bytes_read == 0 {
-                            // EOF
-                            break;
-                        }
-                        // process each input entry on a worker thread
-                        let node = node.clone();
-                        let message_sender = message_sender.clone();
-                        let handlers = handlers.clone();
-                        let stats = stats.clone();
-
-                        scope.spawn(move |_| {
-                            Self::process_line(message_sender, handlers, &mut buffer, &node, stats)
-                        });
-                    }
+    /// Returns: the next queued request with the ordering key, or `None` if the queue is empty, in
+    /// which case the next request with the key will be returned by `enqueue`
+    fn dequeue(request_queues: &RequestQueues, key: &str) -> Option<Message> {
+        let mut request_queues = request_queues
+            .lock()
+            .expect("Unable to dequeue request: lock poisoned");
+        let next = request_queues.get_mut(key).and_then(VecDeque::pop_front);
+        if next.is_none() {
+            request_queues.remove(key);
+        }
+        next
+    }
+
+    fn spawn_timers(&self, node: &Arc<Node>, running: &Arc<AtomicBool>) {
+        for timer in &self.timers {
+            let timer = timer.clone();
+            let node = node.clone();
+            let running = running.clone();
+            let response_sender = self.response_sender.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::Acquire) {
+                    thread::sleep(timer.interval());
+                    timer.tick(response_sender.clone(), &node);
                 }
-            }
-        });
-        // All inputs have been received
-        // Wait for all pending responses to be sent
-        responder.join().unwrap();
+            });
+        }
     }
 
     fn spawn_message_receiver(&self) -> thread::JoinHandle<()> {
@@ -309,23 +875,28 @@ impl Server {
         })
     }
 
-    fn process_line(
-        sender: Sender<Message>,
-        handlers: Arc<HashMap<MessageType, Box<dyn Module>>>,
-        buffer: &mut str,
-        node: &Arc<Node>,
-        stats: Arc<Client>,
-    ) {
-        let request = match serde_json::from_str::<Message>(buffer) {
-            Ok(message) => message,
+    fn parse_line(buffer: &str) -> Option<Message> {
+        match serde_json::from_str::<Message>(buffer) {
+            Ok(message) => Some(message),
             Err(e) => {
                 // Note: we cannot respond with an `AppError` because we cannot
                 // know where to send the response if we couldn't parse the
                 // JSON.
                 eprintln!("Unable to parse input, not responding: {}", e);
-                return;
+                None
             }
-        };
+        }
+    }
+
+    fn process_request(
+        sender: Sender<Message>,
+        handlers: Arc<Routes>,
+        executor: &Executor,
+        request: Message,
+        received: Instant,
+        node: &Arc<Node>,
+        middlewares: Arc<Vec<Box<dyn Middleware>>>,
+    ) {
         if request.body.msg_id.is_none() {
             // Note: we cannot respond with an `AppError` because we cannot
             // reference the requesting message ID.
@@ -346,53 +917,136 @@ impl Server {
         Self::run_custom_handler(
             sender,
             handlers,
+            executor,
             node,
-            &request,
-            request_id,
-            &request.body.message_type,
-            stats,
+            request,
+            received,
+            middlewares,
         );
     }
 
-    /// Run the custom middleware installed by the client
+    /// Run the custom middleware and handler installed by the client. An async handler is only
+    /// started here, its future runs to completion on the executor before the middleware sees its
+    /// responses.
     fn run_custom_handler(
         sender: Sender<Message>,
-        handlers: Arc<HashMap<MessageType, Box<dyn Module>>>,
-        node: &Node,
-        request: &Message,
-        request_id: usize,
-        message_type: &MessageType,
-        stats: Arc<Client>,
+        handlers: Arc<Routes>,
+        executor: &Executor,
+        node: &Arc<Node>,
+        request: Message,
+        received: Instant,
+        middlewares: Arc<Vec<Box<dyn Middleware>>>,
     ) {
-        stats.time(&format!("server.handler.{:?}", message_type), || {
-            let handler = handlers.get(message_type);
-            if let Some(handler) = handler {
-                handler.handle_request(sender, node, request);
-            } else {
-                eprintln!("No handler for: {:?}", message_type);
-                let response = Message::error(
-                    &node.node_id,
-                    &request.src,
-                    request_id,
-                    10,
-                    &format!("Not yet implemented: {:?}", request.body.message_type),
-                );
-                sender
-                    .send(response)
-                    .expect("Message receiver has been closed");
+        let context = RequestContext::received_at(node, &request, received);
+        // when each middleware that accepted the request started processing it
+        let mut started = Vec::with_capacity(middlewares.len());
+        for middleware in middlewares.iter() {
+            let now = Instant::now();
+            if let Err(error) = middleware.before(node, &request) {
+                let outgoing =
+                    vec![error.to_message(&node.node_id, &request.src, context.msg_id())];
+                Self::send_responses(&sender, &middlewares, node, &request, outgoing, &started);
+                return;
+            }
+            started.push(now);
+        }
+
+        // collect the handlers' responses so that the middleware can see them
+        let (response_sender, responses) = mpsc::channel();
+        let mut futures = vec![];
+        for handler in handlers.handlers(&request) {
+            match handler {
+                Handler::Blocking(module) => {
+                    module.handle(response_sender.clone(), &context);
+                }
+                Handler::Async(module) => futures.push(module.handle(
+                    response_sender.clone(),
+                    AsyncRequestContext::received_at(node.clone(), request.clone(), received),
+                )),
+            }
+        }
+        // only the handlers may still send responses
+        drop(response_sender);
+        if !futures.is_empty() {
+            let node = node.clone();
+            let executor = executor.clone();
+            executor.clone().spawn(async move {
+                for future in futures {
+                    future.await;
+                }
+                let outgoing = responses.try_iter().collect();
+                Self::send_responses(&sender, &middlewares, &node, &request, outgoing, &started);
+                Self::forward_late_responses(&executor, sender, responses);
+            });
+            return;
+        }
+        let outgoing = responses.try_iter().collect();
+        Self::send_responses(&sender, &middlewares, node, &request, outgoing, &started);
+        Self::forward_late_responses(executor, sender, responses);
+    }
+
+    /// Send any messages that handlers send after they have finished processing a request, for
+    /// example from a thread that kept the request's channel. The middleware has already seen the
+    /// request's responses, so these are sent as they are. The channel is usually closed by the
+    /// time the handlers finish, otherwise it is checked periodically on the executor until it is.
+    fn forward_late_responses(
+        executor: &Executor,
+        sender: Sender<Message>,
+        responses: Receiver<Message>,
+    ) {
+        if !Self::forward(&sender, &responses) {
+            return;
+        }
+        executor.spawn(async move {
+            while Self::forward(&sender, &responses) {
+                executor::sleep(LATE_RESPONSE_INTERVAL).await;
             }
         });
     }
+
+    /// Send the messages received on a channel so far
+    ///
+    /// Returns: `true` if more messages may be received on the channel
+    fn forward(sender: &Sender<Message>, responses: &Receiver<Message>) -> bool {
+        loop {
+            match responses.try_recv() {
+                Ok(message) => {
+                    if sender.send(message).is_err() {
+                        return false;
+                    }
+                }
+                Err(TryRecvError::Disconnected) => return false,
+                Err(TryRecvError::Empty) => return true,
+            }
+        }
+    }
+
+    /// Pass the responses to a request through the middlewares that accepted it, in the reverse
+    /// order to which they saw the request, then send them
+    fn send_responses(
+        sender: &Sender<Message>,
+        middlewares: &[Box<dyn Middleware>],
+        node: &Node,
+        request: &Message,
+        mut outgoing: Vec<Message>,
+        started: &[Instant],
+    ) {
+        for (middleware, started) in middlewares.iter().zip(started).rev() {
+            middleware.after(node, request, &mut outgoing, started.elapsed());
+        }
+        for message in outgoing {
+            sender
+                .send(message)
+                .expect("Message receiver has been closed");
+        }
+    }
 }
 
+/// A handler that accepts every request without responding
 pub struct NoOpHandler;
 
 impl RequestHandler for NoOpHandler {
-    fn handle_request(
-        &self,
-        _node: &Node,
-        _request: &Message,
-    ) -> Result<Box<dyn Response>, AppError> {
+    fn handle(&self, _context: &RequestContext) -> Result<Box<dyn Response>, AppError> {
         Ok(Box::new(NoOpResponse {}))
     }
 }
@@ -404,3 +1058,440 @@ impl Response for NoOpResponse {
         vec![]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::AppError::NotSupported;
+
+    fn request(src: &str, msg_id: usize) -> Message {
+        Message::new(src, "n1", MessageType::echo)
+            .with_msg_id(msg_id)
+            .with_echo(format!("{}#{}", src, msg_id))
+    }
+
+    /// Replies to every request with its own name as the `echo`
+    struct Tag(&'static str);
+
+    impl Module for Tag {
+        fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+            let reply = context.reply(MessageType::echo_ok).with_echo(self.0);
+            response_sender.send(reply).unwrap();
+        }
+    }
+
+    /// Records when it sees requests and responses, and rejects requests if asked to
+    struct Recorder {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+        reject: bool,
+    }
+
+    impl Middleware for Recorder {
+        fn before(&self, _: &Node, _: &Message) -> Result<(), AppError> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            if self.reject {
+                return Err(NotSupported(self.name.to_string()));
+            }
+            Ok(())
+        }
+
+        fn after(&self, _: &Node, _: &Message, outgoing: &mut Vec<Message>, _: Duration) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+            for message in outgoing {
+                let echo = message.body.echo.take().unwrap_or_default();
+                message.body.echo = Some(format!("{} {}", echo, self.name));
+            }
+        }
+    }
+
+    /// Replies from another thread once it has returned from processing the request
+    struct Late;
+
+    impl Module for Late {
+        fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+            let reply = context.reply(MessageType::echo_ok).with_echo("late");
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                response_sender.send(reply).unwrap();
+            });
+        }
+    }
+
+    /// Replies from a future, with its tag and whether the request came from a client
+    struct AsyncTag(&'static str);
+
+    impl AsyncModule for AsyncTag {
+        fn handle(
+            &self,
+            response_sender: Sender<Message>,
+            context: AsyncRequestContext,
+        ) -> BoxFuture<'static, ()> {
+            let tag = self.0;
+            Box::pin(async move {
+                let origin = context.borrowed().origin();
+                let reply = context
+                    .reply(MessageType::echo_ok)
+                    .with_echo(format!("{} {:?}", tag, origin));
+                response_sender.send(reply).unwrap();
+            })
+        }
+    }
+
+    fn node() -> Arc<Node> {
+        let (sender, _) = mpsc::channel();
+        Arc::new(Node::new("n1".to_string(), vec!["n1".to_string()], sender))
+    }
+
+    /// Process a request with the server's handlers and middleware
+    ///
+    /// Returns: the messages sent in response
+    fn process(server: &Server, request: Message) -> Vec<Message> {
+        Server::run_custom_handler(
+            server.response_sender.clone(),
+            server.handlers.clone(),
+            &server.executor,
+            &node(),
+            request,
+            Instant::now(),
+            server.middlewares.clone(),
+        );
+        server
+            .response_receiver
+            .lock()
+            .unwrap()
+            .try_iter()
+            .collect()
+    }
+
+    fn echoes(responses: &[Message]) -> Vec<&str> {
+        responses
+            .iter()
+            .map(|response| response.body.echo.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    fn msg_id(request: Option<Message>) -> Option<usize> {
+        request.and_then(|request| request.body.msg_id)
+    }
+
+    #[test]
+    fn ordering_keys_group_the_requests_processed_in_order() {
+        let (c1, c2) = (request("c1", 1), request("c2", 1));
+        assert_eq!(Execution::Parallel.ordering_key(&c1), None);
+        assert_eq!(
+            Execution::Ordered.ordering_key(&c1),
+            Execution::Ordered.ordering_key(&c2)
+        );
+        assert_ne!(
+            Execution::OrderedPerSender.ordering_key(&c1),
+            Execution::OrderedPerSender.ordering_key(&c2)
+        );
+    }
+
+    #[test]
+    fn requests_are_queued_behind_the_one_being_processed_in_arrival_order() {
+        let queues = RequestQueues::default();
+        // the first request with a key is processed straight away
+        assert_eq!(
+            msg_id(Server::enqueue(&queues, "c1", request("c1", 1))),
+            Some(1)
+        );
+        assert_eq!(
+            msg_id(Server::enqueue(&queues, "c1", request("c1", 2))),
+            None
+        );
+        assert_eq!(
+            msg_id(Server::enqueue(&queues, "c1", request("c1", 3))),
+            None
+        );
+        // other keys are not held up
+        assert_eq!(
+            msg_id(Server::enqueue(&queues, "c2", request("c2", 1))),
+            Some(1)
+        );
+
+        assert_eq!(msg_id(Server::dequeue(&queues, "c1")), Some(2));
+        assert_eq!(msg_id(Server::dequeue(&queues, "c1")), Some(3));
+        assert_eq!(msg_id(Server::dequeue(&queues, "c1")), None);
+        // once the queue has drained, the next request is processed straight away again
+        assert_eq!(
+            msg_id(Server::enqueue(&queues, "c1", request("c1", 4))),
+            Some(4)
+        );
+        assert_eq!(msg_id(Server::dequeue(&queues, "c2")), None);
+    }
+
+    #[test]
+    fn middleware_sees_responses_in_the_reverse_order_to_requests() {
+        let events: Arc<Mutex<Vec<String>>> = Default::default();
+        let recorder = |name| Recorder {
+            name,
+            events: events.clone(),
+            reject: false,
+        };
+        let server = Server::builder()
+            .with_threads(1)
+            .with_middleware(Box::new(recorder("outer")))
+            .with_middleware(Box::new(recorder("inner")))
+            .with_module(MessageType::echo, Box::new(Tag("handler")))
+            .build();
+
+        let responses = process(&server, request("c1", 1));
+        assert_eq!(echoes(&responses), vec!["handler inner outer"]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["before outer", "before inner", "after inner", "after outer"]
+        );
+    }
+
+    #[test]
+    fn middleware_that_rejects_a_request_skips_the_handler() {
+        let events: Arc<Mutex<Vec<String>>> = Default::default();
+        let recorder = |name, reject| Recorder {
+            name,
+            events: events.clone(),
+            reject,
+        };
+        let server = Server::builder()
+            .with_threads(1)
+            .with_middleware(Box::new(recorder("outer", false)))
+            .with_middleware(Box::new(recorder("guard", true)))
+            .with_middleware(Box::new(recorder("inner", false)))
+            .with_module(MessageType::echo, Box::new(Tag("handler")))
+            .build();
+
+        let responses = process(&server, request("c1", 1));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].body.message_type, MessageType::error);
+        assert_eq!(responses[0].body.code, Some(10));
+        assert_eq!(responses[0].body.in_reply_to, Some(1));
+        // only the middleware that accepted the request sees the error
+        assert_eq!(echoes(&responses), vec![" outer"]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["before outer", "before guard", "after outer"]
+        );
+    }
+
+    #[test]
+    fn messages_sent_after_a_handler_returns_are_still_sent() {
+        let server = Server::builder()
+            .with_threads(1)
+            .with_module(MessageType::echo, Box::new(Late))
+            .build();
+
+        assert!(process(&server, request("c1", 1)).is_empty());
+        let response = server
+            .response_receiver
+            .lock()
+            .unwrap()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(response.body.echo.as_deref(), Some("late"));
+    }
+
+    #[test]
+    fn async_handlers_reply_through_the_middleware_from_their_context() {
+        let events: Arc<Mutex<Vec<String>>> = Default::default();
+        let server = Server::builder()
+            .with_threads(1)
+            .with_middleware(Box::new(Recorder {
+                name: "outer",
+                events: events.clone(),
+                reject: false,
+            }))
+            .with_async_module(MessageType::echo, Box::new(AsyncTag("async")))
+            .build();
+
+        process(&server, request("c1", 1));
+        let response = server
+            .response_receiver
+            .lock()
+            .unwrap()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(response.dest, "c1");
+        assert_eq!(response.body.in_reply_to, Some(1));
+        assert_eq!(response.body.echo.as_deref(), Some("async Client outer"));
+    }
+
+    #[test]
+    fn every_handler_for_a_type_processes_the_request_in_installation_order() {
+        let server = Server::builder()
+            .with_threads(1)
+            .with_module(MessageType::echo, Box::new(Tag("first")))
+            .with_module(MessageType::echo, Box::new(Tag("second")))
+            .build();
+
+        let responses = process(&server, request("c1", 1));
+        assert_eq!(echoes(&responses), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn predicates_select_the_handlers_for_a_request() {
+        let server = Server::builder()
+            .with_threads(1)
+            .when(|request| request.src == "c1")
+            .with_module(MessageType::echo, Box::new(Tag("c1 only")))
+            .with_module(MessageType::echo, Box::new(Tag("everyone")))
+            .when(|request| request.src == "c2")
+            .with_module(MessageType::echo, Box::new(Tag("c2 only")))
+            .build();
+
+        let responses = process(&server, request("c1", 1));
+        assert_eq!(echoes(&responses), vec!["c1 only", "everyone"]);
+        let responses = process(&server, request("c2", 1));
+        assert_eq!(echoes(&responses), vec!["everyone", "c2 only"]);
+    }
+
+    #[test]
+    fn requests_no_handler_accepts_go_to_the_fallback() {
+        let server = Server::builder()
+            .with_threads(1)
+            .when(|request| request.src == "c1")
+            .with_module(MessageType::echo, Box::new(Tag("c1 only")))
+            .with_fallback(Box::new(Tag("fallback")))
+            .build();
+        assert_eq!(
+            echoes(&process(&server, request("c2", 1))),
+            vec!["fallback"]
+        );
+        let read = Message::new("c1", "n1", MessageType::read).with_msg_id(2_usize);
+        assert_eq!(echoes(&process(&server, read)), vec!["fallback"]);
+
+        // without a fallback, the request is not supported
+        let server = Server::builder().with_threads(1).build();
+        let responses = process(&server, request("c1", 1));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].body.code, Some(10));
+    }
+
+    fn init(msg_id: usize, node_id: Option<&str>) -> Message {
+        let mut init = Message::new("c0", "n1", MessageType::init)
+            .with_msg_id(msg_id)
+            .with_node_ids(vec!["n1".to_string(), "n2".to_string()]);
+        init.body.node_id = node_id.map(str::to_string);
+        init
+    }
+
+    #[test]
+    fn requests_before_init_are_kept_in_order_for_afterwards() {
+        let server = Server::builder().with_threads(1).build();
+        let received = Instant::now();
+        let mut input = vec![
+            (request("c1", 1), received),
+            (request("c2", 1), received),
+            (request("c1", 2), received),
+            (init(1, Some("n1")), received),
+            (request("c1", 3), received),
+        ]
+        .into_iter();
+
+        let (node, pending) = server.initialise(&mut input).expect("Not initialised");
+        assert_eq!(node.node_id, "n1");
+        let pending: Vec<String> = pending
+            .into_iter()
+            .map(|(request, _)| request.body.echo.unwrap())
+            .collect();
+        assert_eq!(pending, vec!["c1#1", "c2#1", "c1#2"]);
+        // later requests are left to be processed as usual
+        assert_eq!(
+            input.next().map(|(request, _)| request.body.msg_id),
+            Some(Some(3))
+        );
+
+        let responses: Vec<Message> = server
+            .response_receiver
+            .lock()
+            .unwrap()
+            .try_iter()
+            .collect();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].body.message_type, MessageType::init_ok);
+        assert_eq!(responses[0].body.in_reply_to, Some(1));
+        assert_eq!(responses[0].dest, "c0");
+    }
+
+    #[test]
+    fn an_init_without_a_node_id_is_rejected_until_a_valid_one_arrives() {
+        let server = Server::builder().with_threads(1).build();
+        let received = Instant::now();
+        let mut input =
+            vec![(init(1, None), received), (init(2, Some("n1")), received)].into_iter();
+
+        let (node, pending) = server.initialise(&mut input).expect("Not initialised");
+        assert_eq!(node.node_id, "n1");
+        assert!(pending.is_empty());
+        let responses: Vec<Message> = server
+            .response_receiver
+            .lock()
+            .unwrap()
+            .try_iter()
+            .collect();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].body.message_type, MessageType::error);
+        assert_eq!(responses[0].body.code, Some(12));
+        assert_eq!(responses[0].body.in_reply_to, Some(1));
+        assert!(responses[0]
+            .body
+            .text
+            .as_ref()
+            .unwrap()
+            .contains("body.node_id"));
+        assert_eq!(responses[1].body.message_type, MessageType::init_ok);
+        assert_eq!(responses[1].body.in_reply_to, Some(2));
+    }
+
+    #[test]
+    fn an_init_without_node_ids_is_rejected_from_the_id_it_gives() {
+        let server = Server::builder().with_threads(1).build();
+        let mut init = init(1, Some("n2"));
+        init.body.node_ids = None;
+        let mut input = vec![(init, Instant::now())].into_iter();
+
+        assert!(server.initialise(&mut input).is_none());
+        let response = server.response_receiver.lock().unwrap().recv().unwrap();
+        assert_eq!(response.body.code, Some(12));
+        assert_eq!(response.src, "n2");
+        assert_eq!(response.dest, "c0");
+    }
+
+    #[test]
+    fn requests_before_init_beyond_the_limit_are_rejected_as_unavailable() {
+        let server = Server::builder().with_threads(1).build();
+        let received = Instant::now();
+        let mut input = (1..=MAX_PENDING_REQUESTS + 2)
+            .map(|msg_id| (request("c1", msg_id), received))
+            .chain(iter::once((init(1, Some("n1")), received)));
+
+        let (_, pending) = server.initialise(&mut input).expect("Not initialised");
+        assert_eq!(pending.len(), MAX_PENDING_REQUESTS);
+        let responses: Vec<Message> = server
+            .response_receiver
+            .lock()
+            .unwrap()
+            .try_iter()
+            .collect();
+        assert_eq!(responses.len(), 3);
+        for (response, msg_id) in responses[..2].iter().zip(MAX_PENDING_REQUESTS + 1..) {
+            assert_eq!(response.body.code, Some(11));
+            assert_eq!(response.body.in_reply_to, Some(msg_id));
+            assert_eq!(response.dest, "c1");
+        }
+        assert_eq!(responses[2].body.message_type, MessageType::init_ok);
+    }
+
+    #[test]
+    fn input_ending_before_init_leaves_the_node_uninitialised() {
+        let server = Server::builder().with_threads(1).build();
+        let mut input = vec![(request("c1", 1), Instant::now())].into_iter();
+        assert!(server.initialise(&mut input).is_none());
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::{mpsc, Arc};
+#[cfg(test)]
+use std::thread;
+
+use rand::Rng;
+use serde_json::Value;
+
+use crate::node::AppError;
+#[cfg(test)]
+use crate::node::Node;
+use crate::protocol::{Message, MessageType};
+
+/// An in-process stand-in for one of Maelstrom's
+/// [services](https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md), so that workloads
+/// which depend on them can be run without Maelstrom.
+pub trait Service: Send {
+    /// The name nodes use to address the service, such as `lin-kv`
+    fn name(&self) -> &str;
+
+    /// Process a request from a node.
+    ///
+    /// Returns: the response to send back to the node
+    fn handle(&mut self, request: &Message) -> Message;
+}
+
+/// A key/value operation requested of a service
+enum Operation {
+    Read,
+    Write(Value),
+    Cas {
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+}
+
+/// Parse a key/value request.
+///
+/// Returns: the key in its JSON representation, and the operation
+fn parse(request: &Message) -> Result<(String, Operation), AppError> {
+    let body = &request.body;
+    let key = body
+        .key
+        .as_ref()
+        .ok_or_else(|| AppError::MissingField("body.key".to_string()))?
+        .to_string();
+    let operation = match &body.message_type {
+        MessageType::read => Operation::Read,
+        MessageType::write => Operation::Write(
+            body.value
+                .clone()
+                .ok_or_else(|| AppError::MissingField("body.value".to_string()))?,
+        ),
+        MessageType::cas => Operation::Cas {
+            from: body
+                .from
+                .clone()
+                .ok_or_else(|| AppError::MissingField("body.from".to_string()))?,
+            to: body
+                .to
+                .clone()
+                .ok_or_else(|| AppError::MissingField("body.to".to_string()))?,
+            create_if_not_exists: body.create_if_not_exists.unwrap_or(false),
+        },
+        other => return Err(AppError::NotSupported(format!("{:?}", other))),
+    };
+    Ok((key, operation))
+}
+
+/// Apply a key/value operation to the current value of a key.
+///
+/// Returns: the value to read, or to store if it changed
+fn apply(key: &str, current: Option<&Value>, operation: Operation) -> Result<Value, AppError> {
+    match (operation, current) {
+        (Operation::Read, Some(current)) => Ok(current.clone()),
+        (Operation::Read, None) => Err(AppError::KeyDoesNotExist(key.to_string())),
+        (Operation::Write(value), _) => Ok(value),
+        (
+            Operation::Cas {
+                create_if_not_exists: false,
+                ..
+            },
+            None,
+        ) => Err(AppError::KeyDoesNotExist(key.to_string())),
+        (Operation::Cas { from, .. }, Some(current)) if *current != from => Err(
+            AppError::PreconditionFailed(format!("expected {}, but had {}", from, current)),
+        ),
+        (Operation::Cas { to, .. }, _) => Ok(to),
+    }
+}
+
+/// Build the response to a key/value request
+fn respond(service: &str, request: &Message, result: Result<Option<Value>, AppError>) -> Message {
+    let in_reply_to = request.body.msg_id.unwrap_or_default();
+    let message_type = match request.body.message_type {
+        MessageType::read => MessageType::read_ok,
+        MessageType::write => MessageType::write_ok,
+        MessageType::cas => MessageType::cas_ok,
+        _ => MessageType::error,
+    };
+    match result {
+        Ok(value) => {
+            let mut response = reply(service, request, message_type);
+            response.body.value = value;
+            response
+        }
+        Err(e) => e.to_message(service, &request.src, in_reply_to),
+    }
+}
+
+/// A linearizable key/value store, the equivalent of `lin-kv`. Every operation takes effect
+/// immediately, in the order the requests arrive.
+#[derive(Default)]
+pub struct LinKv {
+    store: HashMap<String, Value>,
+}
+
+impl Service for LinKv {
+    fn name(&self) -> &str {
+        "lin-kv"
+    }
+
+    fn handle(&mut self, request: &Message) -> Message {
+        let result = parse(request).and_then(|(key, operation)| {
+            let is_read = matches!(operation, Operation::Read);
+            let value = apply(&key, self.store.get(&key), operation)?;
+            if is_read {
+                return Ok(Some(value));
+            }
+            self.store.insert(key, value);
+            Ok(None)
+        });
+        respond(self.name(), request, result)
+    }
+}
+
+/// A sequentially consistent key/value store, the equivalent of `seq-kv`. Writes take effect in
+/// the order they arrive, but reads may return a stale value: any of the latest `staleness + 1`
+/// versions of the key. Each node observes the versions of a key in order, it never reads a
+/// version older than one it has already read or written.
+pub struct SeqKv {
+    staleness: usize,
+    /// Every version of each key, oldest first
+    versions: HashMap<String, Vec<Value>>,
+    /// For each node and key, the index of the latest version the node has observed
+    observed: HashMap<(String, String), usize>,
+}
+
+impl SeqKv {
+    /// Parameters:
+    /// - `staleness` - how many versions behind the latest a read may be, `0` behaves like `LinKv`
+    pub fn new(staleness: usize) -> Self {
+        Self {
+            staleness,
+            versions: Default::default(),
+            observed: Default::default(),
+        }
+    }
+}
+
+impl Service for SeqKv {
+    fn name(&self) -> &str {
+        "seq-kv"
+    }
+
+    fn handle(&mut self, request: &Message) -> Message {
+        let result = parse(request).and_then(|(key, operation)| {
+            let client_key = (request.src.clone(), key.clone());
+            let observed = self.observed.get(&client_key).copied().unwrap_or(0);
+            let versions = self.versions.entry(key.clone()).or_default();
+            if let Operation::Read = operation {
+                if versions.is_empty() {
+                    return Err(AppError::KeyDoesNotExist(key));
+                }
+                let latest = versions.len() - 1;
+                let oldest = latest.saturating_sub(self.staleness).max(observed);
+                let index = rand::rng().random_range(oldest..=latest);
+                self.observed.insert(client_key, index);
+                return Ok(Some(versions[index].clone()));
+            }
+            // writes and compare-and-set always act on the latest version
+            let value = apply(&key, versions.last(), operation)?;
+            versions.push(value);
+            self.observed.insert(client_key, versions.len() - 1);
+            Ok(None)
+        });
+        respond(self.name(), request, result)
+    }
+}
+
+/// An eventually consistent key/value store, the equivalent of `lww-kv`. The store has several
+/// replicas and each node always talks to the same one. Replicas only exchange writes
+/// periodically, so nodes may read stale values and concurrent compare-and-set operations on
+/// different replicas may both succeed. When replicas exchange writes, the one with the latest
+/// timestamp wins.
+pub struct LwwKv {
+    /// For each replica, each key's value and the timestamp it was written with
+    replicas: Vec<HashMap<String, (u64, Value)>>,
+    /// How many operations to process between exchanges of writes
+    sync_interval: usize,
+    operations: usize,
+    clock: u64,
+}
+
+impl LwwKv {
+    /// Parameters:
+    /// - `replicas` - the number of independent replicas, `1` behaves like `LinKv`
+    /// - `sync_interval` - how many operations to process between exchanges of writes
+    pub fn new(replicas: usize, sync_interval: usize) -> Self {
+        Self {
+            replicas: vec![Default::default(); replicas.max(1)],
+            sync_interval: sync_interval.max(1),
+            operations: 0,
+            clock: 0,
+        }
+    }
+
+    fn replica(&self, node_id: &str) -> usize {
+        node_id.bytes().map(usize::from).sum::<usize>() % self.replicas.len()
+    }
+
+    /// Bring every replica up to date with the latest write to each key
+    fn sync(&mut self) {
+        let mut latest: HashMap<String, (u64, Value)> = HashMap::new();
+        for replica in &self.replicas {
+            for (key, (timestamp, value)) in replica {
+                if latest
+                    .get(key)
+                    .is_none_or(|(latest_timestamp, _)| timestamp > latest_timestamp)
+                {
+                    latest.insert(key.clone(), (*timestamp, value.clone()));
+                }
+            }
+        }
+        for replica in &mut self.replicas {
+            replica.clone_from(&latest);
+        }
+    }
+}
+
+impl Service for LwwKv {
+    fn name(&self) -> &str {
+        "lww-kv"
+    }
+
+    fn handle(&mut self, request: &Message) -> Message {
+        self.operations += 1;
+        if self.operations.is_multiple_of(self.sync_interval) {
+            self.sync();
+        }
+        let index = self.replica(&request.src);
+        self.clock += 1;
+        let timestamp = self.clock;
+        let replica = &mut self.replicas[index];
+        let result = parse(request).and_then(|(key, operation)| {
+            let is_read = matches!(operation, Operation::Read);
+            let value = apply(&key, replica.get(&key).map(|(_, value)| value), operation)?;
+            if is_read {
+                return Ok(Some(value));
+            }
+            replica.insert(key, (timestamp, value));
+            Ok(None)
+        });
+        respond(self.name(), request, result)
+    }
+}
+
+/// A linearizable timestamp oracle, the equivalent of `lin-tso`
+#[derive(Default)]
+pub struct LinTso {
+    last: u64,
+}
+
+impl Service for LinTso {
+    fn name(&self) -> &str {
+        "lin-tso"
+    }
+
+    fn handle(&mut self, request: &Message) -> Message {
+        if request.body.message_type != MessageType::ts {
+            let error = AppError::NotSupported(format!("{:?}", request.body.message_type));
+            return error.to_message(self.name(), &request.src, request.body.msg_id.unwrap_or(0));
+        }
+        self.last += 1;
+        reply(self.name(), request, MessageType::ts_ok).with_ts(self.last)
+    }
+}
+
+fn reply(service: &str, request: &Message, message_type: MessageType) -> Message {
+    let mut response = Message::new(service, &request.src, message_type);
+    response.body.in_reply_to = request.body.msg_id;
+    response
+}
+
+/// Returns: a node `n1`, in a cluster with `n2`, whose requests are answered by the service. It
+/// does not respond to requests meant for any other service.
+#[cfg(test)]
+pub(crate) fn node_with(mut service: Box<dyn Service>) -> Arc<Node> {
+    let (sender, requests) = mpsc::channel::<Message>();
+    let node_ids = vec!["n1".to_string(), "n2".to_string()];
+    let node = Arc::new(Node::new("n1".to_string(), node_ids, sender));
+    let weak = Arc::downgrade(&node);
+    thread::spawn(move || {
+        for request in requests {
+            let Some(node) = weak.upgrade() else { return };
+            if request.dest == service.name() {
+                node.complete_rpc(service.handle(&request));
+            }
+        }
+    });
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    fn request(src: &str, service: &str, message_type: MessageType) -> Message {
+        Message::new(src, service, message_type)
+            .with_msg_id(1_usize)
+            .with_key("k")
+    }
+
+    fn write(service: &mut dyn Service, src: &str, value: Value) -> Message {
+        let name = service.name().to_string();
+        service.handle(&request(src, &name, MessageType::write).with_value(value))
+    }
+
+    fn cas(service: &mut dyn Service, src: &str, from: Value, to: Value) -> Message {
+        let name = service.name().to_string();
+        service.handle(
+            &request(src, &name, MessageType::cas)
+                .with_from(from)
+                .with_to(to),
+        )
+    }
+
+    /// Returns: the value read, or `None` if the read failed
+    fn read(service: &mut dyn Service, src: &str) -> Option<Value> {
+        let name = service.name().to_string();
+        service
+            .handle(&request(src, &name, MessageType::read))
+            .body
+            .value
+    }
+
+    #[test]
+    fn seq_kv_without_staleness_reads_the_latest_version() {
+        let mut seq_kv = SeqKv::new(0);
+        for version in 0..10 {
+            write(&mut seq_kv, "n1", json!(version));
+            assert_eq!(read(&mut seq_kv, "n2"), Some(json!(version)));
+        }
+    }
+
+    #[test]
+    fn seq_kv_reads_are_at_most_staleness_versions_behind() {
+        let mut seq_kv = SeqKv::new(2);
+        for version in 0..10 {
+            write(&mut seq_kv, "n1", json!(version));
+        }
+        let mut values = HashSet::new();
+        for reader in 0..200 {
+            // every reader is new, so has not observed any version yet
+            let value = read(&mut seq_kv, &format!("c{}", reader)).unwrap();
+            values.insert(value.as_u64().unwrap());
+        }
+        assert_eq!(values, HashSet::from([7, 8, 9]));
+    }
+
+    #[test]
+    fn seq_kv_reads_never_go_back_in_time() {
+        let mut seq_kv = SeqKv::new(5);
+        for version in 0..10 {
+            write(&mut seq_kv, "n1", json!(version));
+        }
+        let mut latest = 0;
+        for _ in 0..100 {
+            let value = read(&mut seq_kv, "n2").unwrap().as_u64().unwrap();
+            assert!(value >= latest, "read {} after {}", value, latest);
+            latest = value;
+        }
+    }
+
+    #[test]
+    fn seq_kv_reads_its_own_writes() {
+        let mut seq_kv = SeqKv::new(5);
+        for version in 0..10 {
+            write(&mut seq_kv, "n1", json!(version));
+            assert_eq!(read(&mut seq_kv, "n1"), Some(json!(version)));
+        }
+    }
+
+    #[test]
+    fn seq_kv_compare_and_set_uses_the_latest_version() {
+        let mut seq_kv = SeqKv::new(5);
+        write(&mut seq_kv, "n1", json!(1));
+        write(&mut seq_kv, "n1", json!(2));
+        let stale = cas(&mut seq_kv, "n2", json!(1), json!(3));
+        assert_eq!(stale.body.code, Some(22));
+        let latest = cas(&mut seq_kv, "n2", json!(2), json!(3));
+        assert_eq!(latest.body.message_type, MessageType::cas_ok);
+    }
+
+    #[test]
+    fn lww_kv_replicas_diverge_until_they_exchange_writes() {
+        let mut lww_kv = LwwKv::new(2, 100);
+        // the tests rely on these nodes using different replicas
+        assert_ne!(lww_kv.replica("n1"), lww_kv.replica("n2"));
+        write(&mut lww_kv, "n1", json!(1));
+        assert_eq!(read(&mut lww_kv, "n2"), None);
+        lww_kv.sync();
+        assert_eq!(read(&mut lww_kv, "n2"), Some(json!(1)));
+    }
+
+    #[test]
+    fn lww_kv_concurrent_compare_and_set_on_different_replicas_both_succeed() {
+        let mut lww_kv = LwwKv::new(2, 100);
+        write(&mut lww_kv, "n1", json!(0));
+        lww_kv.sync();
+        let first = cas(&mut lww_kv, "n1", json!(0), json!("n1"));
+        let second = cas(&mut lww_kv, "n2", json!(0), json!("n2"));
+        assert_eq!(first.body.message_type, MessageType::cas_ok);
+        assert_eq!(second.body.message_type, MessageType::cas_ok);
+        // the later write wins on every replica
+        lww_kv.sync();
+        assert_eq!(read(&mut lww_kv, "n1"), Some(json!("n2")));
+        assert_eq!(read(&mut lww_kv, "n2"), Some(json!("n2")));
+    }
+
+    #[test]
+    fn lww_kv_keeps_the_latest_write_regardless_of_replica_order() {
+        let mut lww_kv = LwwKv::new(2, 100);
+        write(&mut lww_kv, "n2", json!("early"));
+        write(&mut lww_kv, "n1", json!("late"));
+        lww_kv.sync();
+        assert_eq!(read(&mut lww_kv, "n2"), Some(json!("late")));
+    }
+
+    #[test]
+    fn lww_kv_exchanges_writes_every_sync_interval() {
+        let mut lww_kv = LwwKv::new(2, 3);
+        write(&mut lww_kv, "n1", json!(1));
+        assert_eq!(read(&mut lww_kv, "n2"), None);
+        // the third operation triggers an exchange before it is processed
+        assert_eq!(read(&mut lww_kv, "n2"), Some(json!(1)));
+    }
+}
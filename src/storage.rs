@@ -0,0 +1,334 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// State that can be persisted by `Storage`. The state is saved periodically as a snapshot, and
+/// every change in between is recorded in a write-ahead log.
+pub trait Recoverable: Default + Serialize + DeserializeOwned {
+    /// A change to the state, as recorded in the write-ahead log
+    type Entry: Serialize + DeserializeOwned;
+
+    /// Apply a change that was recorded in the write-ahead log
+    fn apply(&mut self, entry: Self::Entry);
+}
+
+/// Durable local storage for a module's state, so that it survives the node being restarted. The
+/// state is kept in two files: a JSON snapshot and a write-ahead log with one JSON entry per line
+/// for the changes made since the snapshot was taken.
+///
+/// Each snapshot has a generation number, and the write-ahead log starts with a header naming the
+/// generation it follows. A node stopped after replacing the snapshot but before emptying the log
+/// leaves a log of the previous generation, whose entries are already in the snapshot, so it is
+/// discarded rather than replayed.
+pub struct Storage<S: Recoverable> {
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    log: Mutex<File>,
+    /// The generation of the current snapshot
+    generation: AtomicU64,
+    /// A second handle to the write-ahead log, so that it can be flushed to disk without
+    /// preventing further entries from being appended
+    flush_handle: File,
+    /// The number of entries appended so far
+    appended: AtomicU64,
+    /// The number of entries known to be on disk. This is locked for the duration of a flush.
+    flushed: Mutex<u64>,
+    state: PhantomData<S>,
+}
+
+impl<S: Recoverable> Storage<S> {
+    /// Open the storage, creating it if it does not exist.
+    ///
+    /// Parameters:
+    /// - `directory` - where to keep the files, this will be created if necessary
+    /// - `name` - a name unique to the node and module, such as the node ID
+    pub fn open(directory: &Path, name: &str) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let snapshot_path = directory.join(format!("{}.snapshot.json", name));
+        let log_path = directory.join(format!("{}.wal.jsonl", name));
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        Ok(Self {
+            snapshot_path,
+            log_path,
+            flush_handle: log.try_clone()?,
+            log: Mutex::new(log),
+            generation: AtomicU64::new(0),
+            appended: AtomicU64::new(0),
+            flushed: Mutex::new(0),
+            state: PhantomData,
+        })
+    }
+
+    /// Reconstruct the state by loading the latest snapshot and replaying the write-ahead log.
+    /// If the node was stopped part-way through writing a log entry, that entry is removed from
+    /// the log so that later entries are not appended to it. The same applies to an entry that
+    /// cannot be read and to everything after it. This must be called before any entry is
+    /// appended.
+    ///
+    /// Returns: the restored state, which is the default state if nothing was persisted
+    pub fn restore(&self) -> io::Result<S> {
+        let (generation, mut state) = match fs::read(&self.snapshot_path) {
+            Ok(bytes) => {
+                let snapshot: Snapshot<S> = serde_json::from_slice(&bytes).map_err(invalid_data)?;
+                (snapshot.generation, snapshot.state)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (0, S::default()),
+            Err(e) => return Err(e),
+        };
+        self.generation.store(generation, Ordering::Release);
+        let log = fs::read(&self.log_path)?;
+        let mut lines = log.split_inclusive(|byte| *byte == b'\n');
+        let mut valid_length = 0;
+        if let Some(line) = lines.next().filter(|line| line.ends_with(b"\n")) {
+            match serde_json::from_slice::<Header>(line) {
+                Ok(header) if header.generation == generation => valid_length = line.len(),
+                Ok(_) => eprintln!("Discarding write-ahead log of an earlier snapshot"),
+                Err(e) => eprintln!("Discarding write-ahead log with unreadable header: {}", e),
+            }
+        }
+        if valid_length > 0 {
+            // an entry is complete once the newline that ends it has been written
+            for line in lines {
+                if !line.ends_with(b"\n") {
+                    eprintln!("Discarding incomplete log entry");
+                    break;
+                }
+                match serde_json::from_slice(line) {
+                    Ok(entry) => state.apply(entry),
+                    Err(e) => {
+                        eprintln!("Discarding unreadable log entry and any after it: {}", e);
+                        break;
+                    }
+                }
+                valid_length += line.len();
+            }
+        }
+        let mut log_file = self
+            .log
+            .lock()
+            .expect("Unable to truncate write-ahead log: lock poisoned");
+        if valid_length < log.len() {
+            log_file.set_len(valid_length as u64)?;
+        }
+        if valid_length == 0 {
+            start_log(&mut log_file, generation)?;
+        }
+        Ok(state)
+    }
+
+    /// Record a change. The entry is only durable once `sync` has returned, which allows the
+    /// caller to append while holding a lock that orders its changes, and to wait for the disk
+    /// once the lock has been released.
+    pub fn append(&self, entry: &S::Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(invalid_data)?;
+        line.push(b'\n');
+        let mut log = self
+            .log
+            .lock()
+            .expect("Unable to append to write-ahead log: lock poisoned");
+        log.write_all(&line)?;
+        self.appended.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Wait until every entry appended so far has been written to disk. Callers that arrive while
+    /// the log is being flushed wait for that flush, and a single further flush then covers all of
+    /// their entries.
+    pub fn sync(&self) -> io::Result<()> {
+        let target = self.appended.load(Ordering::Acquire);
+        let mut flushed = self
+            .flushed
+            .lock()
+            .expect("Unable to flush write-ahead log: lock poisoned");
+        if *flushed >= target {
+            return Ok(());
+        }
+        // entries appended since `target` was read are covered too
+        let appended = self.appended.load(Ordering::Acquire);
+        self.flush_handle.sync_data()?;
+        *flushed = appended;
+        Ok(())
+    }
+
+    /// Replace the snapshot and discard the write-ahead log. The caller must ensure that no
+    /// entries are appended between capturing `state` and this returning, otherwise they will be
+    /// lost.
+    pub fn snapshot(&self, state: &S) -> io::Result<()> {
+        let generation = self.generation.load(Ordering::Acquire) + 1;
+        let bytes = serde_json::to_vec(&Snapshot { generation, state }).map_err(invalid_data)?;
+        // write to a temporary file first so that a crash cannot leave a partial snapshot
+        let temporary_path = self.snapshot_path.with_extension("tmp");
+        {
+            let mut temporary = File::create(&temporary_path)?;
+            temporary.write_all(&bytes)?;
+            temporary.sync_all()?;
+        }
+        fs::rename(&temporary_path, &self.snapshot_path)?;
+        // the rename is only durable once the directory has been written to disk
+        if let Some(directory) = self.snapshot_path.parent() {
+            File::open(directory)?.sync_all()?;
+        }
+        self.generation.store(generation, Ordering::Release);
+
+        let mut log = self
+            .log
+            .lock()
+            .expect("Unable to truncate write-ahead log: lock poisoned");
+        log.set_len(0)?;
+        start_log(&mut log, generation)
+    }
+}
+
+/// The contents of the snapshot file
+#[derive(Serialize, Deserialize)]
+struct Snapshot<S> {
+    generation: u64,
+    state: S,
+}
+
+/// The first line of the write-ahead log
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// The generation of the snapshot that the entries follow
+    generation: u64,
+}
+
+/// Write the header of an empty write-ahead log
+fn start_log(log: &mut File, generation: u64) -> io::Result<()> {
+    let mut line = serde_json::to_vec(&Header { generation }).map_err(invalid_data)?;
+    line.push(b'\n');
+    log.write_all(&line)?;
+    log.sync_all()
+}
+
+fn invalid_data(error: serde_json::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[derive(Default, Serialize, Deserialize)]
+    struct Numbers(Vec<u64>);
+
+    impl Recoverable for Numbers {
+        type Entry = u64;
+
+        fn apply(&mut self, entry: u64) {
+            self.0.push(entry);
+        }
+    }
+
+    /// Returns: an empty directory for a test to keep its files in
+    fn directory(test: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("storage-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    /// Open the storage as a restarted node would
+    fn reopen(directory: &Path) -> (Storage<Numbers>, Vec<u64>) {
+        let storage = Storage::open(directory, "n1").unwrap();
+        let state = storage.restore().unwrap();
+        (storage, state.0)
+    }
+
+    fn append(storage: &Storage<Numbers>, entries: &[u64]) {
+        for entry in entries {
+            storage.append(entry).unwrap();
+        }
+        storage.sync().unwrap();
+    }
+
+    fn append_bytes(directory: &Path, bytes: &[u8]) {
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(directory.join("n1.wal.jsonl"))
+            .unwrap();
+        log.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn the_log_is_replayed_over_the_snapshot() {
+        let directory = directory("replay");
+        let (storage, state) = reopen(&directory);
+        assert!(state.is_empty());
+        append(&storage, &[1, 2]);
+        storage.snapshot(&Numbers(vec![1, 2])).unwrap();
+        append(&storage, &[3]);
+
+        assert_eq!(reopen(&directory).1, vec![1, 2, 3]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_is_restored_without_a_log() {
+        let directory = directory("snapshot");
+        let (storage, _) = reopen(&directory);
+        append(&storage, &[1]);
+        storage.snapshot(&Numbers(vec![7])).unwrap();
+
+        assert_eq!(reopen(&directory).1, vec![7]);
+        // restoring does not change what is persisted
+        assert_eq!(reopen(&directory).1, vec![7]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn an_incomplete_last_entry_is_cut_off() {
+        let directory = directory("incomplete");
+        let (storage, _) = reopen(&directory);
+        append(&storage, &[1, 2]);
+        append_bytes(&directory, b"3");
+
+        let (storage, state) = reopen(&directory);
+        assert_eq!(state, vec![1, 2]);
+        append(&storage, &[4]);
+        assert_eq!(reopen(&directory).1, vec![1, 2, 4]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn an_unreadable_entry_and_those_after_it_are_discarded() {
+        let directory = directory("unreadable");
+        let (storage, _) = reopen(&directory);
+        append(&storage, &[1]);
+        append_bytes(&directory, b"not json\n2\n");
+
+        let (storage, state) = reopen(&directory);
+        assert_eq!(state, vec![1]);
+        append(&storage, &[3]);
+        assert_eq!(reopen(&directory).1, vec![1, 3]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn a_log_already_in_the_snapshot_is_not_replayed() {
+        let directory = directory("stale");
+        let (storage, _) = reopen(&directory);
+        append(&storage, &[1, 2]);
+        let log_path = directory.join("n1.wal.jsonl");
+        let log = fs::read(&log_path).unwrap();
+        storage.snapshot(&Numbers(vec![1, 2])).unwrap();
+        // the node stopped after replacing the snapshot but before emptying the log
+        fs::write(&log_path, log).unwrap();
+
+        let (storage, state) = reopen(&directory);
+        assert_eq!(state, vec![1, 2]);
+        append(&storage, &[3]);
+        assert_eq!(reopen(&directory).1, vec![1, 2, 3]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,1937 @@
+use rand::seq::IndexedRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use statsd::Client;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::context::RequestContext;
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType, Origin};
+use crate::server::{Module, RequestHandler, Response, ServerBuilder, Timer};
+use crate::storage::{Recoverable, Storage};
+use crate::workloads::ConfigError;
+
+#[derive(Default)]
+struct BroadcastServer {
+    neighbours: Vec<String>,
+    /// Every message in the order in which it was received, so that peers and clients can request
+    /// only the messages added since they last asked. Each is kept as its JSON representation and
+    /// shared with the responses that carry it, so that a read does not copy the messages.
+    log: Vec<Arc<RawValue>>,
+    /// The positions in `log` of the messages whose JSON representation has each hash, so that
+    /// duplicates are found without keeping a second copy of every message
+    index: HashMap<u64, Vec<usize>>,
+    /// For each neighbour, the offset into its log up to which this node has synchronised
+    sync_offsets: HashMap<String, SyncOffset>,
+    /// Identifies this node's log. The log is not persisted across every restart, so each process
+    /// picks a new value and peers synchronise from the beginning when it changes.
+    incarnation: u64,
+    /// Where changes are recorded if persistence is enabled
+    journal: Option<Arc<Storage<BroadcastSnapshot>>>,
+}
+
+impl BroadcastServer {
+    /// Store a message if it has not been seen before. If persistence is enabled, the message is
+    /// only durable once the journal has been synchronised, see `BroadcastServer::sync`.
+    ///
+    /// Returns: `true` if the message is new, or an error if it could not be persisted, in which
+    /// case it is not stored
+    fn record(&mut self, message: &Value) -> Result<bool, AppError> {
+        let message = serde_json::value::to_raw_value(message).expect("Cannot convert to JSON");
+        let key = message.get();
+        if self.contains(key) {
+            return Ok(false);
+        }
+        self.append(&BroadcastEntry::Received(key.to_string()))?;
+        self.index
+            .entry(hash(key))
+            .or_default()
+            .push(self.log.len());
+        self.log.push(message.into());
+        Ok(true)
+    }
+
+    /// Returns: `true` if the message with the given JSON representation has been recorded
+    fn contains(&self, message: &str) -> bool {
+        self.index.get(&hash(message)).is_some_and(|positions| {
+            positions
+                .iter()
+                .any(|position| self.log[*position].get() == message)
+        })
+    }
+
+    /// Returns: the offset into a peer's log from which to request new messages
+    fn sync_offset(&self, peer: &str) -> usize {
+        self.sync_offsets
+            .get(peer)
+            .map(|sync_offset| sync_offset.offset)
+            .unwrap_or_default()
+    }
+
+    fn set_neighbours(&mut self, neighbours: Vec<String>) -> Result<(), AppError> {
+        self.append(&BroadcastEntry::Topology(neighbours.clone()))?;
+        self.neighbours = neighbours;
+        Ok(())
+    }
+
+    fn append(&self, entry: &BroadcastEntry) -> Result<(), AppError> {
+        match &self.journal {
+            Some(journal) => journal.append(entry).map_err(persistence_error),
+            None => Ok(()),
+        }
+    }
+}
+
+struct TopologyHandler {
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+}
+
+impl RequestHandler for TopologyHandler {
+    fn handle(&self, context: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        let (node, request) = (context.node(), context.request());
+        if request.body.topology.is_none() {
+            return Err(AppError::MissingField("body.topology".to_string()));
+        }
+        let topology = request.body.topology.clone().unwrap();
+        let neighbours = topology.get(&node.node_id).cloned().unwrap_or_default();
+        let journal = {
+            let mut server = self
+                .broadcast_server
+                .write()
+                .expect("Cannot update topology: broadcast server lock is poisoned");
+            server.set_neighbours(neighbours)?;
+            server.journal.clone()
+        };
+        sync(journal)?;
+        Ok(Box::new(TopologyOk {}))
+    }
+}
+
+struct TopologyOk;
+
+impl Response for TopologyOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![Message::reply_to(
+            node,
+            caller,
+            in_reply_to,
+            MessageType::topology_ok,
+        )]
+    }
+}
+
+struct BroadcastHandler {
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+    response_sender: Arc<Mutex<Sender<Message>>>,
+    outbox: Outbox,
+    dissemination: Arc<dyn Dissemination>,
+    tuning: Arc<Tuning>,
+    running: Arc<AtomicBool>,
+    stats: Arc<Client>,
+    persistence: Arc<Persistence>,
+}
+
+const MAX_ATTEMPTS: u32 = 16;
+const BASELINE_SLEEP_MS: u64 = 2;
+/// The bounds within which adaptive mode keeps the retry base
+const MIN_SLEEP_MS: u64 = 1;
+const MAX_SLEEP_MS: u64 = 100;
+/// The maximum number of unacknowledged broadcasts to a single neighbour. Any further broadcasts
+/// wait in that neighbour's backlog.
+const WINDOW_SIZE: usize = 32;
+/// The number of consecutive unacknowledged transmissions after which a neighbour is considered
+/// unreachable
+const FAILURE_THRESHOLD: u32 = 8;
+/// How long to wait before probing a neighbour that was considered unreachable for the first time
+const BASELINE_COOLDOWN_MS: u64 = 100;
+/// The longest to wait before probing an unreachable neighbour
+const MAX_COOLDOWN_MS: u64 = 2_000;
+/// The number of peers to gossip with when not using the topology
+const DEFAULT_FANOUT: usize = 3;
+/// How often to perform periodic dissemination work, such as pulling from peers or announcing
+/// messages
+const DEFAULT_GOSSIP_INTERVAL_MS: u64 = 200;
+/// The bounds within which adaptive mode keeps the gossip interval
+const MIN_GOSSIP_INTERVAL_MS: u64 = 20;
+const MAX_GOSSIP_INTERVAL_MS: u64 = 1_000;
+/// How long to wait for an announced message to arrive before requesting it from the announcer
+const GRAFT_TIMEOUT_MS: u64 = 400;
+/// The delivery latency adaptive mode aims for by default
+const DEFAULT_TARGET_LATENCY_MS: u64 = 400;
+/// How often adaptive mode re-evaluates the tuning parameters
+const TUNING_INTERVAL_MS: u64 = 1_000;
+/// The weight given to each new observation in the moving averages used by adaptive mode
+const SMOOTHING_FACTOR: f64 = 0.1;
+/// How often to snapshot the persisted state
+const SNAPSHOT_INTERVAL_MS: u64 = 5_000;
+
+impl Module for BroadcastHandler {
+    fn init(&mut self, response_sender: Sender<Message>) {
+        let mut guard = self.response_sender.lock().unwrap();
+        *guard = response_sender.clone();
+
+        // stop the existing daemon
+        self.running
+            .store(false, std::sync::atomic::Ordering::Release);
+        self.outbox
+            .queues
+            .lock()
+            .expect("Unable to reset outbound queues: lock poisoned")
+            .clear();
+
+        // start the new daemon
+        let outbound = self.outbox.queues.clone();
+        self.running
+            .store(true, std::sync::atomic::Ordering::Release);
+        let running = self.running.clone();
+        let mut daemon_lock = self
+            .outbox
+            .daemon
+            .lock()
+            .expect("Unable init daemon: lock poisoned");
+        let stats = self.stats.clone();
+        let tuning = self.tuning.clone();
+        *daemon_lock = thread::spawn(move || {
+            while running.load(std::sync::atomic::Ordering::Acquire) {
+                let next_wakeup = {
+                    let mut outbound = outbound
+                        .lock()
+                        .expect("Unable to transmit broadcasts: outbound lock is poisoned");
+                    let now = Instant::now();
+                    let retry_base = tuning.retry_base();
+                    outbound
+                        .iter_mut()
+                        .filter_map(|(neighbour, queue)| {
+                            queue.transmit(neighbour, now, retry_base, &response_sender, &stats)
+                        })
+                        .min()
+                    // release lock on outbound
+                };
+
+                if let Some(wakeup_time) = next_wakeup {
+                    // park until it's time to send the next message
+                    thread::park_timeout(wakeup_time.saturating_duration_since(Instant::now()));
+                } else {
+                    // park until a message is added
+                    thread::park();
+                }
+            }
+        });
+    }
+
+    fn on_init(&self, node: &Arc<Node>) {
+        self.persistence.restore(node);
+    }
+
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+        let node = context.node();
+        let request = context.request();
+        if request.body.message.is_none() {
+            let error = AppError::MissingField("body.message".to_string());
+            let message = error.to_message(&node.node_id, context.sender(), context.msg_id());
+            response_sender.send(message).unwrap();
+            return;
+        }
+        let acknowledgement = context.reply(MessageType::broadcast_ok);
+        // messages from clients are spread to every peer, those from peers need not be sent back
+        let peer = match context.origin() {
+            Origin::Node => Some(context.sender()),
+            Origin::Client | Origin::Service => None,
+        };
+
+        let message = request.body.message.as_ref().unwrap();
+        let (recorded, journal) = {
+            let mut server = self
+                .broadcast_server
+                .write()
+                .expect("Cannot persist message: broadcast server lock is poisoned");
+            (server.record(message), server.journal.clone())
+        };
+        // wait for the disk once the lock is released, so that other requests are not held up
+        let is_new = match recorded.and_then(|is_new| sync(journal).map(|_| is_new)) {
+            Ok(is_new) => is_new,
+            Err(error) => {
+                // without an acknowledgement, the sender will try again
+                let message = error.to_message(&node.node_id, context.sender(), context.msg_id());
+                response_sender.send(message).unwrap();
+                return;
+            }
+        };
+        let gossip = Gossip {
+            node,
+            broadcast_server: &self.broadcast_server,
+            outbox: &self.outbox,
+            response_sender: &response_sender,
+        };
+        if is_new {
+            self.dissemination
+                .on_new(&gossip, &message.to_string(), peer);
+        } else {
+            // already received this message by other means
+            self.dissemination
+                .on_duplicate(&gossip, &message.to_string(), peer);
+        }
+
+        // confirm receipt of the broadcast message
+        response_sender.send(acknowledgement).unwrap();
+    }
+}
+
+impl Drop for BroadcastHandler {
+    fn drop(&mut self) {
+        self.running
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        {
+            match self.outbox.queues.lock() {
+                Ok(mut guard) => guard.clear(),
+                Err(e) => eprintln!("Unable to clear outbound queues: lock is poisoned: {}", e),
+            }
+        }
+        match self.outbox.daemon.lock() {
+            Ok(guard) => {
+                guard.thread().unpark();
+                // if let Err(e) = guard.join() {
+                //     eprintln!("Unable to shut down daemon thread: {:?}", e);
+                // }
+            }
+            Err(e) => eprintln!("Unable to stop daemon: lock poisoned: {}", e),
+        }
+    }
+}
+
+/// The outbound broadcasts for every neighbour, along with the daemon that transmits them
+#[derive(Clone)]
+struct Outbox {
+    queues: Arc<Mutex<HashMap<String, NeighbourQueue>>>,
+    daemon: Arc<Mutex<JoinHandle<()>>>,
+}
+
+impl Outbox {
+    fn gossip(&self, broadcast: Message) {
+        // queue the message for delivery
+        {
+            let mut guard = self
+                .queues
+                .lock()
+                .expect("Unable to queue broadcast: lock is poisoned");
+            guard
+                .entry(broadcast.dest.clone())
+                .or_default()
+                .enqueue(broadcast);
+        }
+        self.wake();
+    }
+
+    /// All broadcasts that have not yet been acknowledged
+    ///
+    /// Returns: the neighbour and the JSON representation of the message for each broadcast
+    fn pending(&self) -> Vec<(String, String)> {
+        let queues = self
+            .queues
+            .lock()
+            .expect("Unable to list pending broadcasts: lock is poisoned");
+        queues
+            .iter()
+            .flat_map(|(neighbour, queue)| {
+                queue
+                    .backlog
+                    .iter()
+                    .chain(queue.retries.iter())
+                    .chain(queue.in_flight.values())
+                    .filter_map(|pending| pending.broadcast.body.message.as_ref())
+                    .map(|message| (neighbour.clone(), message.to_string()))
+            })
+            .collect()
+    }
+
+    /// Wake the messenger daemon
+    fn wake(&self) {
+        let guard = self
+            .daemon
+            .lock()
+            .expect("Unable to wake messenger daemon: mutex is poisoned");
+        guard.thread().unpark();
+    }
+}
+
+struct Broadcast {
+    node: String,
+    message: String,
+}
+
+struct PendingBroadcast {
+    broadcast: Message,
+    attempts: u32,
+    created: Instant,
+    /// When the broadcast should be considered lost if it has not been acknowledged
+    deadline: Instant,
+}
+
+impl PendingBroadcast {
+    fn message_id(&self) -> usize {
+        self.broadcast
+            .body
+            .msg_id
+            .expect("Pending broadcast is missing a message ID")
+    }
+}
+
+/// Whether broadcasts should be sent to a neighbour
+#[derive(Default)]
+enum Circuit {
+    /// The neighbour is reachable, send as many broadcasts as the window allows
+    #[default]
+    Closed,
+    /// The neighbour appears to be unreachable, do not send anything until the cooldown elapses
+    Open { until: Instant },
+    /// The cooldown has elapsed, send a single probe to see if the neighbour is reachable again
+    HalfOpen,
+}
+
+/// The outbound broadcasts for a single neighbour
+#[derive(Default)]
+struct NeighbourQueue {
+    /// Broadcasts that have not yet been sent
+    backlog: VecDeque<PendingBroadcast>,
+    /// Broadcasts that were sent but not acknowledged in time, these take priority over the
+    /// backlog
+    retries: VecDeque<PendingBroadcast>,
+    /// Broadcasts that were sent and are awaiting acknowledgement, keyed by message ID
+    in_flight: HashMap<usize, PendingBroadcast>,
+    circuit: Circuit,
+    /// The number of transmissions that timed out since the last acknowledgement
+    consecutive_failures: u32,
+    /// How long to leave the circuit open the next time it trips
+    cooldown: Option<Duration>,
+}
+
+impl NeighbourQueue {
+    fn is_reachable(&self) -> bool {
+        matches!(self.circuit, Circuit::Closed)
+    }
+
+    fn enqueue(&mut self, broadcast: Message) {
+        let now = Instant::now();
+        self.backlog.push_back(PendingBroadcast {
+            broadcast,
+            attempts: 0,
+            created: now,
+            deadline: now,
+        });
+    }
+
+    /// Record that the neighbour received a broadcast.
+    ///
+    /// Parameters:
+    /// - `message_id` - the ID of the broadcast message being acknowledged
+    ///
+    /// Returns: the acknowledged broadcast or `None` if it was not pending (e.g. the acknowledgement
+    /// is a duplicate or the broadcast was abandoned)
+    fn acknowledge(&mut self, message_id: usize) -> Option<PendingBroadcast> {
+        // any response means the neighbour is reachable
+        self.consecutive_failures = 0;
+        self.circuit = Circuit::Closed;
+        self.cooldown = None;
+
+        self.in_flight.remove(&message_id).or_else(|| {
+            self.retries
+                .iter()
+                .position(|pending| pending.message_id() == message_id)
+                .and_then(|index| self.retries.remove(index))
+        })
+    }
+
+    /// Send as many broadcasts as the neighbour's window and circuit allow.
+    ///
+    /// Parameters:
+    /// - `neighbour` - the node ID of the neighbour
+    /// - `now` - the current time
+    /// - `retry_base` - the basis for the exponential backoff between attempts
+    /// - `response_sender` - the channel on which to send broadcasts
+    /// - `stats` - where to record delivery metrics
+    ///
+    /// Returns: when this queue next needs attention or `None` if it is waiting for new broadcasts
+    fn transmit(
+        &mut self,
+        neighbour: &str,
+        now: Instant,
+        retry_base: Duration,
+        response_sender: &Sender<Message>,
+        stats: &Client,
+    ) -> Option<Instant> {
+        match self.circuit {
+            Circuit::Open { until } if now < until => return Some(until),
+            Circuit::Open { .. } => {
+                // probe with the oldest broadcast, a single additional failure re-opens the circuit
+                self.circuit = Circuit::HalfOpen;
+                self.consecutive_failures = FAILURE_THRESHOLD - 1;
+                let mut in_flight: Vec<PendingBroadcast> =
+                    self.in_flight.drain().map(|(_, pending)| pending).collect();
+                in_flight.sort_by_key(|pending| pending.created);
+                for pending in in_flight.into_iter().rev() {
+                    self.retries.push_front(pending);
+                }
+            }
+            Circuit::Closed | Circuit::HalfOpen => {}
+        }
+
+        // detect lost transmissions
+        let expired: Vec<usize> = self
+            .in_flight
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        for message_id in expired {
+            let pending = self.in_flight.remove(&message_id).unwrap();
+            self.consecutive_failures += 1;
+            if pending.attempts >= MAX_ATTEMPTS {
+                stats.incr("broadcast.undelivered_messages");
+                stats.incr(&format!("broadcast.{}.undelivered_messages", neighbour));
+                eprintln!(
+                    "Unable to deliver message after {} attempts: {}",
+                    MAX_ATTEMPTS, pending.broadcast
+                );
+                continue;
+            }
+            self.retries.push_back(pending);
+        }
+
+        if self.consecutive_failures >= FAILURE_THRESHOLD {
+            // stop sending doomed messages until the neighbour is likely to be reachable again
+            let cooldown = self
+                .cooldown
+                .unwrap_or(Duration::from_millis(BASELINE_COOLDOWN_MS));
+            let until = now + cooldown;
+            self.circuit = Circuit::Open { until };
+            self.cooldown = Some((cooldown * 2).min(Duration::from_millis(MAX_COOLDOWN_MS)));
+            stats.incr(&format!("broadcast.{}.circuit_opened", neighbour));
+            eprintln!(
+                "Neighbour {} appears unreachable, pausing broadcasts for {:?}",
+                neighbour, cooldown
+            );
+            self.report(neighbour, stats);
+            return Some(until);
+        }
+
+        let window = match self.circuit {
+            Circuit::HalfOpen => 1,
+            _ => WINDOW_SIZE,
+        };
+        let mut rand = rand::rng();
+        while self.in_flight.len() < window {
+            let Some(mut pending) = self
+                .retries
+                .pop_front()
+                .or_else(|| self.backlog.pop_front())
+            else {
+                break;
+            };
+            stats.incr("broadcast.delivery_attempts");
+            stats.incr(&format!("broadcast.{}.delivery_attempts", neighbour));
+            response_sender
+                .send(pending.broadcast.clone())
+                .expect("Broadcast channel is closed.");
+
+            // implement exponential backoff with jitter
+            pending.attempts += 1;
+            let sleep_time = retry_base
+                .mul_f64(2u32.pow(pending.attempts) as f64 * rand.random_range(0.95..1.05f64));
+            pending.deadline = now.checked_add(sleep_time).expect("Temporal overflow");
+            self.in_flight.insert(pending.message_id(), pending);
+        }
+        self.report(neighbour, stats);
+
+        self.in_flight
+            .values()
+            .map(|pending| pending.deadline)
+            .min()
+    }
+
+    fn report(&self, neighbour: &str, stats: &Client) {
+        stats.gauge(
+            &format!("broadcast.{}.in_flight", neighbour),
+            self.in_flight.len() as f64,
+        );
+        stats.gauge(
+            &format!("broadcast.{}.backlog", neighbour),
+            (self.backlog.len() + self.retries.len()) as f64,
+        );
+        stats.gauge(
+            &format!("broadcast.{}.reachable", neighbour),
+            match self.circuit {
+                Circuit::Open { .. } => 0.0,
+                _ => 1.0,
+            },
+        );
+    }
+}
+
+impl Broadcast {
+    fn to_message(&self, node: &Node) -> Message {
+        Message::request(node, &self.node, MessageType::broadcast)
+            .with_message(from_json(&self.message))
+    }
+}
+
+/// Responds with the messages received since the requested offset, or all of them if no offset is
+/// specified. This serves both `read` requests from clients and `sync` requests from peers.
+struct ReadHandler {
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+    response_type: MessageType,
+}
+
+impl RequestHandler for ReadHandler {
+    fn handle(&self, context: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        let request = context.request();
+        let server = self
+            .broadcast_server
+            .read()
+            .expect("Cannot read messages: broadcast server lock is poisoned");
+        let offset = request
+            .body
+            .offset
+            .unwrap_or_default()
+            .min(server.log.len());
+        Ok(Box::new(ReadOk {
+            message_type: self.response_type.clone(),
+            messages: server.log[offset..].to_vec(),
+            offset: server.log.len(),
+            incarnation: server.incarnation,
+        }))
+    }
+}
+
+struct ReadOk {
+    message_type: MessageType,
+    messages: Vec<Arc<RawValue>>,
+    offset: usize,
+    incarnation: u64,
+}
+
+impl Response for ReadOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, self.message_type.clone())
+                .with_messages(self.messages.clone())
+                .with_offset(self.offset)
+                .with_incarnation(self.incarnation),
+        ]
+    }
+
+    fn into_messages(
+        self: Box<Self>,
+        node: &Node,
+        caller: &str,
+        in_reply_to: usize,
+    ) -> Vec<Message> {
+        // a node may hold many messages, so they are moved rather than copied a second time
+        vec![
+            Message::reply_to(node, caller, in_reply_to, self.message_type)
+                .with_messages(self.messages)
+                .with_offset(self.offset)
+                .with_incarnation(self.incarnation),
+        ]
+    }
+}
+
+/// Stores the messages a peer sent in response to a `sync` request and passes on any that are new
+struct SyncOkHandler {
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+    outbox: Outbox,
+    dissemination: Arc<dyn Dissemination>,
+}
+
+impl Module for SyncOkHandler {
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+        let (node, request) = (context.node(), context.request());
+        let peer = &request.src;
+        let (new_messages, restarted) = {
+            let mut server = self
+                .broadcast_server
+                .write()
+                .expect("Unable to synchronise messages: broadcast server lock poisoned");
+            let previous = server.sync_offsets.get(peer).copied().unwrap_or_default();
+            let incarnation = request.body.incarnation.unwrap_or(previous.incarnation);
+            // the peer's log started again, so it did not send the messages before the offset
+            // this node asked for
+            let restarted = incarnation != previous.incarnation && previous.offset > 0;
+            if restarted {
+                let sync_offset = SyncOffset {
+                    incarnation,
+                    offset: 0,
+                };
+                server.sync_offsets.insert(peer.clone(), sync_offset);
+            } else if let Some(offset) = request.body.offset {
+                let sync_offset = SyncOffset {
+                    incarnation,
+                    offset: offset.max(previous.offset),
+                };
+                server.sync_offsets.insert(peer.clone(), sync_offset);
+            }
+            let mut new_messages = vec![];
+            for message in request.body.messages.iter().flatten() {
+                let message = from_json(message.get());
+                match server.record(&message) {
+                    Ok(true) => new_messages.push(message.to_string()),
+                    Ok(false) => {}
+                    Err(error) => {
+                        // a later synchronisation will ask for this message again
+                        eprintln!("Unable to record synchronised message: {:?}", error);
+                        server.sync_offsets.insert(peer.clone(), previous);
+                        break;
+                    }
+                }
+            }
+            (new_messages, restarted)
+        };
+        if restarted {
+            response_sender.send(sync_request(node, peer, 0)).unwrap();
+        }
+        let gossip = Gossip {
+            node,
+            broadcast_server: &self.broadcast_server,
+            outbox: &self.outbox,
+            response_sender: &response_sender,
+        };
+        for message in new_messages {
+            self.dissemination.on_new(&gossip, &message, Some(peer));
+        }
+    }
+}
+
+/// Passes strategy-specific messages, such as Plumtree's `ihave`, `prune` and `graft`, to the
+/// dissemination strategy
+struct ControlHandler {
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+    outbox: Outbox,
+    dissemination: Arc<dyn Dissemination>,
+}
+
+impl Module for ControlHandler {
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+        let (node, request) = (context.node(), context.request());
+        let gossip = Gossip {
+            node,
+            broadcast_server: &self.broadcast_server,
+            outbox: &self.outbox,
+            response_sender: &response_sender,
+        };
+        self.dissemination.on_control(&gossip, request);
+    }
+}
+
+/// Periodically gives the dissemination strategy the opportunity to perform background work
+struct DisseminationTimer {
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+    outbox: Outbox,
+    dissemination: Arc<dyn Dissemination>,
+    tuning: Arc<Tuning>,
+}
+
+impl Timer for DisseminationTimer {
+    fn interval(&self) -> Duration {
+        self.tuning.gossip_interval()
+    }
+
+    fn tick(&self, response_sender: Sender<Message>, node: &Node) {
+        let gossip = Gossip {
+            node,
+            broadcast_server: &self.broadcast_server,
+            outbox: &self.outbox,
+            response_sender: &response_sender,
+        };
+        self.dissemination.on_tick(&gossip);
+    }
+}
+
+/// Everything a dissemination strategy may use to spread messages through the cluster
+struct Gossip<'a> {
+    node: &'a Node,
+    broadcast_server: &'a RwLock<BroadcastServer>,
+    outbox: &'a Outbox,
+    response_sender: &'a Sender<Message>,
+}
+
+impl Gossip<'_> {
+    /// The neighbours assigned to this node by the topology
+    fn neighbours(&self) -> Vec<String> {
+        self.broadcast_server
+            .read()
+            .expect("Cannot find neighbours: broadcast server lock is poisoned")
+            .neighbours
+            .clone()
+    }
+
+    /// Choose up to `amount` cluster members at random, excluding this node and `excluded`
+    fn random_peers(&self, amount: usize, excluded: Option<&str>) -> Vec<String> {
+        let candidates: Vec<&String> = self
+            .node
+            .peers()
+            .filter(|peer| Some(peer.as_str()) != excluded)
+            .collect();
+        candidates
+            .choose_multiple(&mut rand::rng(), amount)
+            .map(|peer| peer.to_string())
+            .collect()
+    }
+
+    fn is_known(&self, message: &str) -> bool {
+        self.broadcast_server
+            .read()
+            .expect("Cannot look up message: broadcast server lock is poisoned")
+            .contains(message)
+    }
+
+    /// Deliver a message to a peer, retrying until the peer acknowledges it
+    fn push(&self, peer: &str, message: &str) {
+        let broadcast = Broadcast {
+            node: peer.to_string(),
+            message: message.to_string(),
+        };
+        self.outbox.gossip(broadcast.to_message(self.node));
+    }
+
+    /// Send a message to a peer once, without expecting a response
+    fn notify(&self, peer: &str, message_type: MessageType, messages: &[String]) {
+        let messages: Vec<_> = messages.iter().map(|message| to_raw(message)).collect();
+        let notification = Message::request(self.node, peer, message_type).with_messages(messages);
+        self.response_sender.send(notification).unwrap();
+    }
+
+    /// Ask a peer for everything it received since this node last synchronised with it
+    fn pull(&self, peer: &str) {
+        let offset = self
+            .broadcast_server
+            .read()
+            .expect("Unable to determine sync offset: broadcast server lock poisoned")
+            .sync_offset(peer);
+        self.response_sender
+            .send(sync_request(self.node, peer, offset))
+            .unwrap();
+    }
+}
+
+/// A strategy for spreading broadcast messages through the cluster
+trait Dissemination: Sync + Send {
+    /// Spread a message that this node received for the first time.
+    ///
+    /// Parameters:
+    /// - `gossip` - the means of reaching other nodes
+    /// - `message` - the JSON representation of the broadcast message
+    /// - `peer` - the peer from which the message was received, or `None` if it came from a client
+    fn on_new(&self, gossip: &Gossip, message: &str, peer: Option<&str>);
+
+    /// React to receiving a message that this node already had
+    fn on_duplicate(&self, _gossip: &Gossip, _message: &str, _peer: Option<&str>) {}
+
+    /// React to a strategy-specific message from a peer
+    fn on_control(&self, _gossip: &Gossip, _request: &Message) {}
+
+    /// Perform periodic background work
+    fn on_tick(&self, _gossip: &Gossip) {}
+}
+
+/// Push every new message to all the neighbours in the topology
+struct Flooding;
+
+impl Dissemination for Flooding {
+    fn on_new(&self, gossip: &Gossip, message: &str, peer: Option<&str>) {
+        // except the neighbour that sent us the message to begin with
+        gossip
+            .neighbours()
+            .iter()
+            .filter(|neighbour| Some(neighbour.as_str()) != peer)
+            .for_each(|neighbour| gossip.push(neighbour, message));
+    }
+}
+
+/// Push every new message to a few random peers and periodically pull anything missed from a few
+/// random peers, ignoring the topology
+struct PushPull {
+    tuning: Arc<Tuning>,
+}
+
+impl Dissemination for PushPull {
+    fn on_new(&self, gossip: &Gossip, message: &str, sender: Option<&str>) {
+        for peer in gossip.random_peers(self.tuning.fanout(), sender) {
+            gossip.push(&peer, message);
+        }
+    }
+
+    fn on_tick(&self, gossip: &Gossip) {
+        for peer in gossip.random_peers(self.tuning.fanout(), None) {
+            gossip.pull(&peer);
+        }
+    }
+}
+
+/// Epidemic broadcast trees: push new messages eagerly along a spanning tree that is pruned from
+/// the topology as duplicates are detected, and announce them lazily to the remaining neighbours
+/// so that the tree can be repaired when a branch fails.
+#[derive(Default)]
+struct Plumtree {
+    state: Mutex<PlumtreeState>,
+}
+
+#[derive(Default)]
+struct PlumtreeState {
+    initialised: bool,
+    /// Peers to which new messages are pushed in full
+    eager: HashSet<String>,
+    /// Peers to which new messages are only announced
+    lazy: HashSet<String>,
+    /// Messages that were announced but not yet received, with when they were first announced
+    /// and by whom
+    missing: HashMap<String, (Instant, String)>,
+    /// Announcements to send on the next tick, by peer
+    announcements: HashMap<String, Vec<String>>,
+}
+
+impl Plumtree {
+    fn state(&self, gossip: &Gossip) -> std::sync::MutexGuard<'_, PlumtreeState> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Unable to access Plumtree state: lock poisoned");
+        if !state.initialised {
+            // start with every neighbour on the tree
+            let neighbours = gossip.neighbours();
+            if !neighbours.is_empty() {
+                state.eager = neighbours.into_iter().collect();
+                state.initialised = true;
+            }
+        }
+        state
+    }
+}
+
+impl PlumtreeState {
+    fn make_eager(&mut self, peer: &str) {
+        self.lazy.remove(peer);
+        self.eager.insert(peer.to_string());
+    }
+
+    fn make_lazy(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.insert(peer.to_string());
+    }
+}
+
+impl Dissemination for Plumtree {
+    fn on_new(&self, gossip: &Gossip, message: &str, sender: Option<&str>) {
+        let eager: Vec<String> = {
+            let mut state = self.state(gossip);
+            state.missing.remove(message);
+            if let Some(sender) = sender {
+                state.make_eager(sender);
+            }
+            let lazy: Vec<String> = state
+                .lazy
+                .iter()
+                .filter(|peer| Some(peer.as_str()) != sender)
+                .cloned()
+                .collect();
+            for peer in lazy {
+                state
+                    .announcements
+                    .entry(peer)
+                    .or_default()
+                    .push(message.to_string());
+            }
+            state
+                .eager
+                .iter()
+                .filter(|peer| Some(peer.as_str()) != sender)
+                .cloned()
+                .collect()
+        };
+        for peer in eager {
+            gossip.push(&peer, message);
+        }
+    }
+
+    fn on_duplicate(&self, gossip: &Gossip, _message: &str, sender: Option<&str>) {
+        let Some(sender) = sender else {
+            return;
+        };
+        // the sender is a redundant path to this node, remove it from the tree
+        self.state(gossip).make_lazy(sender);
+        gossip.notify(sender, MessageType::prune, &[]);
+    }
+
+    fn on_control(&self, gossip: &Gossip, request: &Message) {
+        let peer = &request.src;
+        let messages: Vec<String> = request
+            .body
+            .messages
+            .iter()
+            .flatten()
+            .map(|message| from_json(message.get()).to_string())
+            .collect();
+        match &request.body.message_type {
+            MessageType::prune => self.state(gossip).make_lazy(peer),
+            MessageType::ihave => {
+                let now = Instant::now();
+                let mut state = self.state(gossip);
+                for message in messages {
+                    if !gossip.is_known(&message) {
+                        state
+                            .missing
+                            .entry(message)
+                            .or_insert_with(|| (now, peer.clone()));
+                    }
+                }
+            }
+            MessageType::graft => {
+                self.state(gossip).make_eager(peer);
+                for message in messages {
+                    gossip.push(peer, &message);
+                }
+            }
+            other => eprintln!("Plumtree cannot handle: {:?}", other),
+        }
+    }
+
+    fn on_tick(&self, gossip: &Gossip) {
+        let (announcements, grafts) = {
+            let mut state = self.state(gossip);
+            let announcements: Vec<(String, Vec<String>)> = state.announcements.drain().collect();
+
+            // request messages that were announced but never arrived, repairing the tree
+            let now = Instant::now();
+            let mut grafts: HashMap<String, Vec<String>> = HashMap::new();
+            for (message, (announced, announcer)) in state.missing.iter_mut() {
+                if now.duration_since(*announced) >= Duration::from_millis(GRAFT_TIMEOUT_MS) {
+                    grafts
+                        .entry(announcer.clone())
+                        .or_default()
+                        .push(message.clone());
+                    *announced = now;
+                }
+            }
+            for peer in grafts.keys() {
+                state.make_eager(peer);
+            }
+            (announcements, grafts)
+        };
+        for (peer, messages) in announcements {
+            gossip.notify(&peer, MessageType::ihave, &messages);
+        }
+        for (peer, messages) in grafts {
+            gossip.notify(&peer, MessageType::graft, &messages);
+        }
+    }
+}
+
+/// Choose the dissemination strategy using the `BROADCAST_DISSEMINATION` environment variable:
+/// `flooding` (the default), `push-pull` or `plumtree`.
+///
+/// Returns: the strategy, or an error if the variable has another value
+fn dissemination_from_env(tuning: &Arc<Tuning>) -> Result<Arc<dyn Dissemination>, ConfigError> {
+    match env::var("BROADCAST_DISSEMINATION").as_deref() {
+        Err(_) | Ok("flooding") => Ok(Arc::new(Flooding)),
+        Ok("push-pull") => Ok(Arc::new(PushPull {
+            tuning: tuning.clone(),
+        })),
+        Ok("plumtree") => Ok(Arc::new(Plumtree::default())),
+        Ok(other) => Err(ConfigError::InvalidSetting {
+            variable: "BROADCAST_DISSEMINATION".to_string(),
+            value: other.to_string(),
+        }),
+    }
+}
+
+/// Read a setting from the environment
+///
+/// Returns: the setting, `default` if it is not set, or an error if it cannot be parsed
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, ConfigError> {
+    match env::var(name) {
+        Ok(value) => value.parse().map_err(|_| ConfigError::InvalidSetting {
+            variable: name.to_string(),
+            value,
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// The parameters that trade delivery latency off against the number of messages sent. In
+/// adaptive mode, these are adjusted periodically based on the latencies and retries observed
+/// when delivering broadcasts. Otherwise, they keep their configured values.
+///
+/// The fanout and gossip interval are only used by `PushPull` and `Plumtree`. `Flooding` pushes
+/// each message to every neighbour as soon as it arrives, so only the retry base affects it.
+struct Tuning {
+    adaptive: bool,
+    target_latency_ms: f64,
+    /// The basis for the exponential backoff between delivery attempts
+    retry_base_ms: AtomicU64,
+    /// The number of peers to gossip with when not using the topology
+    fanout: AtomicUsize,
+    /// How often to pull from peers or to send batched announcements
+    gossip_interval_ms: AtomicU64,
+    observations: Mutex<Observations>,
+}
+
+/// Exponential moving averages of delivery measurements
+#[derive(Default)]
+struct Observations {
+    /// The time between enqueueing a broadcast and its acknowledgement
+    latency_ms: Option<f64>,
+    /// The latency of broadcasts that were acknowledged on the first attempt, which approximates
+    /// the round-trip time to a neighbour
+    round_trip_ms: Option<f64>,
+    attempts: Option<f64>,
+}
+
+fn smooth(average: &mut Option<f64>, sample: f64) {
+    *average = Some(match average {
+        Some(average) => *average + SMOOTHING_FACTOR * (sample - *average),
+        None => sample,
+    });
+}
+
+impl Tuning {
+    /// Configure the tuning parameters using the environment: `BROADCAST_ADAPTIVE`,
+    /// `BROADCAST_TARGET_LATENCY_MS`, `BROADCAST_RETRY_BASE_MS`, `BROADCAST_FANOUT` and
+    /// `BROADCAST_GOSSIP_INTERVAL_MS`.
+    ///
+    /// Returns: the tuning, or an error for the first setting that cannot be parsed
+    fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            adaptive: env_or("BROADCAST_ADAPTIVE", false)?,
+            target_latency_ms: env_or("BROADCAST_TARGET_LATENCY_MS", DEFAULT_TARGET_LATENCY_MS)?
+                as f64,
+            retry_base_ms: AtomicU64::new(env_or("BROADCAST_RETRY_BASE_MS", BASELINE_SLEEP_MS)?),
+            fanout: AtomicUsize::new(env_or("BROADCAST_FANOUT", DEFAULT_FANOUT)?),
+            gossip_interval_ms: AtomicU64::new(env_or(
+                "BROADCAST_GOSSIP_INTERVAL_MS",
+                DEFAULT_GOSSIP_INTERVAL_MS,
+            )?),
+            observations: Default::default(),
+        })
+    }
+
+    fn retry_base(&self) -> Duration {
+        Duration::from_millis(self.retry_base_ms.load(Ordering::Relaxed))
+    }
+
+    fn fanout(&self) -> usize {
+        self.fanout.load(Ordering::Relaxed)
+    }
+
+    fn gossip_interval(&self) -> Duration {
+        Duration::from_millis(self.gossip_interval_ms.load(Ordering::Relaxed))
+    }
+
+    /// Record the outcome of delivering a broadcast to a neighbour
+    fn observe(&self, latency_ms: f64, attempts: u32) {
+        if !self.adaptive {
+            return;
+        }
+        let mut observations = self
+            .observations
+            .lock()
+            .expect("Unable to record observation: lock poisoned");
+        smooth(&mut observations.latency_ms, latency_ms);
+        smooth(&mut observations.attempts, attempts as f64);
+        if attempts == 1 {
+            smooth(&mut observations.round_trip_ms, latency_ms);
+        }
+    }
+
+    /// Re-evaluate the tuning parameters based on the observations so far.
+    ///
+    /// Parameters:
+    /// - `peers` - the number of other nodes in the cluster, which bounds the fanout
+    /// - `stats` - where to record the chosen parameters
+    fn adjust(&self, peers: usize, stats: &Client) {
+        let (latency_ms, round_trip_ms, attempts) = {
+            let observations = self
+                .observations
+                .lock()
+                .expect("Unable to read observations: lock poisoned");
+            match observations.latency_ms {
+                Some(latency_ms) => (
+                    latency_ms,
+                    observations.round_trip_ms,
+                    observations.attempts.unwrap_or(1.0),
+                ),
+                // nothing has been delivered yet
+                None => return,
+            }
+        };
+
+        // The first retry is sent after twice the retry base. Retrying before an acknowledgement
+        // could arrive only adds messages, while waiting much longer than that delays recovery
+        // from loss.
+        if let Some(round_trip_ms) = round_trip_ms {
+            let retry_base_ms = ((round_trip_ms * 0.75) as u64).clamp(MIN_SLEEP_MS, MAX_SLEEP_MS);
+            self.retry_base_ms.store(retry_base_ms, Ordering::Relaxed);
+        }
+
+        let fanout = self.fanout();
+        let gossip_interval_ms = self.gossip_interval_ms.load(Ordering::Relaxed);
+        if latency_ms > self.target_latency_ms {
+            // too slow: reach more peers and gossip more often
+            self.fanout
+                .store((fanout + 1).min(peers.max(1)), Ordering::Relaxed);
+            self.gossip_interval_ms.store(
+                (gossip_interval_ms / 2).max(MIN_GOSSIP_INTERVAL_MS),
+                Ordering::Relaxed,
+            );
+        } else if latency_ms < self.target_latency_ms / 2.0 && attempts < 1.5 {
+            // comfortably fast: send fewer, larger batches to fewer peers
+            self.fanout
+                .store(fanout.saturating_sub(1).max(1), Ordering::Relaxed);
+            self.gossip_interval_ms.store(
+                (gossip_interval_ms * 2).min(MAX_GOSSIP_INTERVAL_MS),
+                Ordering::Relaxed,
+            );
+        }
+
+        stats.gauge(
+            "broadcast.tuning.retry_base_ms",
+            self.retry_base_ms.load(Ordering::Relaxed) as f64,
+        );
+        stats.gauge("broadcast.tuning.fanout", self.fanout() as f64);
+        stats.gauge(
+            "broadcast.tuning.gossip_interval_ms",
+            self.gossip_interval_ms.load(Ordering::Relaxed) as f64,
+        );
+    }
+}
+
+/// Periodically re-evaluates the tuning parameters in adaptive mode
+struct TuningTimer {
+    tuning: Arc<Tuning>,
+    stats: Arc<Client>,
+}
+
+impl Timer for TuningTimer {
+    fn interval(&self) -> Duration {
+        Duration::from_millis(TUNING_INTERVAL_MS)
+    }
+
+    fn tick(&self, _: Sender<Message>, node: &Node) {
+        self.tuning
+            .adjust(node.node_ids.len().saturating_sub(1), &self.stats);
+    }
+}
+
+struct BroadcastAcknowledgementHandler {
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+    outbox: Outbox,
+    tuning: Arc<Tuning>,
+    stats: Arc<Client>,
+}
+
+impl Module for BroadcastAcknowledgementHandler {
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+        let (node, request) = (context.node(), context.request());
+        let broadcast_message_id = request.body.in_reply_to.unwrap();
+        let (recovered, delivered) = {
+            let mut guard = self
+                .outbox
+                .queues
+                .lock()
+                .expect("Unable to process broadcast acknowledgement: outbound lock poisoned");
+            match guard.get_mut(&request.src) {
+                Some(queue) => (
+                    !queue.is_reachable(),
+                    queue.acknowledge(broadcast_message_id),
+                ),
+                None => (false, None),
+            }
+        };
+        if recovered {
+            // the neighbour was unreachable, catch up on anything it received in the meantime
+            let offset = self
+                .broadcast_server
+                .read()
+                .expect("Unable to determine sync offset: broadcast server lock poisoned")
+                .sync_offset(&request.src);
+            response_sender
+                .send(sync_request(node, &request.src, offset))
+                .unwrap();
+        }
+        let Some(pending_broadcast) = delivered else {
+            // duplicate acknowledgement
+            return;
+        };
+
+        let neighbour = &request.src;
+        let latency = pending_broadcast.created.elapsed().as_millis() as f64;
+        self.stats.incr("broadcast.delivered_messages");
+        self.stats
+            .incr(&format!("broadcast.{}.delivered_messages", neighbour));
+        self.stats
+            .histogram("attempts_per_message", pending_broadcast.attempts as f64);
+        self.stats
+            .gauge("attempts_per_message", pending_broadcast.attempts as f64);
+        self.stats.timer("delivery_latency", latency);
+        self.stats.timer(
+            &format!("broadcast.{}.delivery_latency", neighbour),
+            latency,
+        );
+        self.tuning.observe(latency, pending_broadcast.attempts);
+
+        // a slot in the neighbour's window may have opened up
+        self.outbox.wake();
+    }
+}
+
+/// The persisted state of a broadcast node
+#[derive(Default, Serialize, Deserialize)]
+struct BroadcastSnapshot {
+    neighbours: Vec<String>,
+    /// The JSON representation of every message received, in the order in which they were
+    /// received
+    messages: Vec<String>,
+    sync_offsets: HashMap<String, SyncOffset>,
+    /// Broadcasts that had not been acknowledged, as pairs of neighbour and message
+    pending: Vec<(String, String)>,
+}
+
+/// How far this node has synchronised with a peer's log
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+struct SyncOffset {
+    /// The peer's incarnation when the offset was recorded
+    incarnation: u64,
+    /// The offset into that incarnation's log
+    offset: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+enum BroadcastEntry {
+    Topology(Vec<String>),
+    Received(String),
+}
+
+impl Recoverable for BroadcastSnapshot {
+    type Entry = BroadcastEntry;
+
+    fn apply(&mut self, entry: BroadcastEntry) {
+        match entry {
+            BroadcastEntry::Topology(neighbours) => self.neighbours = neighbours,
+            BroadcastEntry::Received(message) => {
+                // whether the message was gossiped before the node stopped is unknown, so err on
+                // the side of sending it again
+                for neighbour in &self.neighbours {
+                    self.pending.push((neighbour.clone(), message.clone()));
+                }
+                self.messages.push(message);
+            }
+        }
+    }
+}
+
+/// Saves the messages, topology and pending gossip so that they survive the node being restarted,
+/// for example by Maelstrom's process-kill nemesis
+struct Persistence {
+    /// Where to keep the state, persistence is disabled if this is `None`
+    directory: Option<PathBuf>,
+    broadcast_server: Arc<RwLock<BroadcastServer>>,
+    outbox: Outbox,
+}
+
+impl Persistence {
+    /// Load the state saved by a previous incarnation of this node. This must be done once the
+    /// node is initialised, as the state is kept under its ID, and before any request is processed.
+    /// If the storage cannot be opened, the node starts empty without persistence, and if the
+    /// state cannot be read, the node starts empty.
+    fn restore(&self, node: &Node) {
+        let Some(directory) = &self.directory else {
+            return;
+        };
+        let storage: Storage<BroadcastSnapshot> = match Storage::open(directory, &node.node_id) {
+            Ok(storage) => storage,
+            Err(e) => {
+                eprintln!(
+                    "Unable to open broadcast storage, persistence is disabled: {}",
+                    e
+                );
+                return;
+            }
+        };
+        let snapshot = storage.restore().unwrap_or_else(|e| {
+            eprintln!("Unable to restore broadcast state, starting empty: {}", e);
+            BroadcastSnapshot::default()
+        });
+        let mut server = self
+            .broadcast_server
+            .write()
+            .expect("Unable to restore state: broadcast server lock is poisoned");
+        server.neighbours = snapshot.neighbours;
+        server.sync_offsets = snapshot.sync_offsets;
+        for message in snapshot.messages {
+            // the journal is only attached afterwards, so this does not write to it
+            if let Err(error) = server.record(&from_json(&message)) {
+                eprintln!("Unable to restore message {}: {:?}", message, error);
+            }
+        }
+        for (neighbour, message) in snapshot.pending {
+            let broadcast = Broadcast {
+                node: neighbour,
+                message,
+            };
+            self.outbox.gossip(broadcast.to_message(node));
+        }
+        server.journal = Some(Arc::new(storage));
+    }
+
+    /// Save the current state and discard the write-ahead log
+    fn snapshot(&self) {
+        // block further changes until the snapshot is complete so that none are lost
+        let server = self
+            .broadcast_server
+            .write()
+            .expect("Unable to snapshot state: broadcast server lock is poisoned");
+        let Some(journal) = &server.journal else {
+            return;
+        };
+        let snapshot = BroadcastSnapshot {
+            neighbours: server.neighbours.clone(),
+            messages: server
+                .log
+                .iter()
+                .map(|message| message.get().to_string())
+                .collect(),
+            sync_offsets: server.sync_offsets.clone(),
+            pending: self.outbox.pending(),
+        };
+        if let Err(e) = journal.snapshot(&snapshot) {
+            eprintln!("Unable to snapshot broadcast state: {}", e);
+        }
+    }
+}
+
+/// Periodically snapshots the persisted state so that the write-ahead log stays short
+struct SnapshotTimer {
+    persistence: Arc<Persistence>,
+}
+
+impl Timer for SnapshotTimer {
+    fn interval(&self) -> Duration {
+        Duration::from_millis(SNAPSHOT_INTERVAL_MS)
+    }
+
+    fn tick(&self, _: Sender<Message>, _: &Node) {
+        self.persistence.snapshot();
+    }
+}
+
+/// Request everything a peer has received since `offset`
+fn sync_request(node: &Node, peer: &str, offset: usize) -> Message {
+    Message::request(node, peer, MessageType::sync).with_offset(offset)
+}
+
+/// Wait until the changes recorded in the journal, if any, are on disk
+fn sync(journal: Option<Arc<Storage<BroadcastSnapshot>>>) -> Result<(), AppError> {
+    match journal {
+        Some(journal) => journal.sync().map_err(persistence_error),
+        None => Ok(()),
+    }
+}
+
+/// A change that could not be persisted may or may not survive a restart
+fn persistence_error(error: io::Error) -> AppError {
+    AppError::Crash(format!("Unable to persist change: {}", error))
+}
+
+/// Returns: the hash of a message's JSON representation
+fn hash(message: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    message.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Parse a message stored in its JSON representation
+fn from_json(message: &str) -> Value {
+    serde_json::from_str(message).expect("Cannot convert back to JSON")
+}
+
+/// Returns: a message stored in its JSON representation, ready to send without parsing it
+fn to_raw(message: &str) -> Arc<RawValue> {
+    RawValue::from_string(message.to_string())
+        .expect("Cannot convert back to JSON")
+        .into()
+}
+
+/// Install the `broadcast` workload
+pub fn register(mut builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let stats = Arc::new(Client::new("localhost:8125", "broadcast").unwrap());
+    let broadcast_server = Arc::new(RwLock::new(BroadcastServer {
+        incarnation: rand::rng().random(),
+        ..Default::default()
+    }));
+    let topology_handler = TopologyHandler {
+        broadcast_server: broadcast_server.clone(),
+    };
+    let (placeholder_sender, _receiver) = mpsc::channel::<Message>();
+    let outbox = Outbox {
+        queues: Default::default(),
+        daemon: Arc::new(Mutex::new(thread::spawn(|| {}))),
+    };
+    let tuning = Arc::new(Tuning::from_env()?);
+    let dissemination = dissemination_from_env(&tuning)?;
+    let persistence = Arc::new(Persistence {
+        directory: env::var_os("MAELSTROM_STATE_DIR").map(PathBuf::from),
+        broadcast_server: broadcast_server.clone(),
+        outbox: outbox.clone(),
+    });
+    let broadcast_handler = BroadcastHandler {
+        broadcast_server: broadcast_server.clone(),
+        response_sender: Arc::new(Mutex::new(placeholder_sender)),
+        outbox: outbox.clone(),
+        dissemination: dissemination.clone(),
+        tuning: tuning.clone(),
+        running: Arc::new(AtomicBool::new(false)),
+        stats: stats.clone(),
+        persistence: persistence.clone(),
+    };
+    let read_handler = ReadHandler {
+        broadcast_server: broadcast_server.clone(),
+        response_type: MessageType::read_ok,
+    };
+    let sync_handler = ReadHandler {
+        broadcast_server: broadcast_server.clone(),
+        response_type: MessageType::sync_ok,
+    };
+    let sync_ok_handler = SyncOkHandler {
+        broadcast_server: broadcast_server.clone(),
+        outbox: outbox.clone(),
+        dissemination: dissemination.clone(),
+    };
+    let timer = DisseminationTimer {
+        broadcast_server: broadcast_server.clone(),
+        outbox: outbox.clone(),
+        dissemination: dissemination.clone(),
+        tuning: tuning.clone(),
+    };
+    let control_handler = || ControlHandler {
+        broadcast_server: broadcast_server.clone(),
+        outbox: outbox.clone(),
+        dissemination: dissemination.clone(),
+    };
+    let (ihave_handler, prune_handler, graft_handler) =
+        (control_handler(), control_handler(), control_handler());
+    let broadcast_ok_handler = BroadcastAcknowledgementHandler {
+        broadcast_server: broadcast_server.clone(),
+        outbox: outbox.clone(),
+        tuning: tuning.clone(),
+        stats: stats.clone(),
+    };
+
+    if tuning.adaptive {
+        builder = builder.with_timer(Arc::new(TuningTimer {
+            tuning,
+            stats: stats.clone(),
+        }));
+    }
+    if persistence.directory.is_some() {
+        builder = builder.with_timer(Arc::new(SnapshotTimer {
+            persistence: persistence.clone(),
+        }));
+    }
+    Ok(builder
+        .with_stats(stats)
+        .with_handler(MessageType::topology, Box::new(topology_handler))
+        .with_module(MessageType::broadcast, Box::new(broadcast_handler))
+        .with_handler(MessageType::read, Box::new(read_handler))
+        .with_module(MessageType::broadcast_ok, Box::new(broadcast_ok_handler))
+        .with_handler(MessageType::sync, Box::new(sync_handler))
+        .with_module(MessageType::sync_ok, Box::new(sync_ok_handler))
+        .with_module(MessageType::ihave, Box::new(ihave_handler))
+        .with_module(MessageType::prune, Box::new(prune_handler))
+        .with_module(MessageType::graft, Box::new(graft_handler))
+        .with_timer(Arc::new(timer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::mpsc::Receiver;
+
+    /// Spreads nothing, for tests of how messages are stored
+    struct Silent;
+
+    impl Dissemination for Silent {
+        fn on_new(&self, _: &Gossip, _: &str, _: Option<&str>) {}
+    }
+
+    fn node(node_id: &str) -> (Node, Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel();
+        let node_ids = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        (Node::new(node_id.to_string(), node_ids, sender), receiver)
+    }
+
+    fn outbox() -> Outbox {
+        Outbox {
+            queues: Default::default(),
+            daemon: Arc::new(Mutex::new(thread::spawn(|| {}))),
+        }
+    }
+
+    fn stats() -> Client {
+        Client::new("localhost:8125", "broadcast-test").unwrap()
+    }
+
+    fn broadcast(msg_id: usize) -> Message {
+        Message::new("n1", "n2", MessageType::broadcast)
+            .with_msg_id(msg_id)
+            .with_message(json!(msg_id))
+    }
+
+    /// A queue holding the given number of broadcasts
+    fn queue(broadcasts: usize) -> NeighbourQueue {
+        let mut queue = NeighbourQueue::default();
+        for msg_id in 0..broadcasts {
+            queue.enqueue(broadcast(msg_id));
+        }
+        queue
+    }
+
+    fn tuning(fanout: usize) -> Tuning {
+        Tuning {
+            adaptive: false,
+            target_latency_ms: DEFAULT_TARGET_LATENCY_MS as f64,
+            retry_base_ms: AtomicU64::new(BASELINE_SLEEP_MS),
+            fanout: AtomicUsize::new(fanout),
+            gossip_interval_ms: AtomicU64::new(DEFAULT_GOSSIP_INTERVAL_MS),
+            observations: Default::default(),
+        }
+    }
+
+    /// A server for node `n1` of a five node cluster, with the given neighbours
+    fn server(neighbours: &[&str]) -> RwLock<BroadcastServer> {
+        RwLock::new(BroadcastServer {
+            neighbours: neighbours.iter().map(|peer| peer.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn cluster_node() -> (Node, Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel();
+        let node_ids = (1..=5).map(|id| format!("n{}", id)).collect();
+        (Node::new("n1".to_string(), node_ids, sender), receiver)
+    }
+
+    /// Returns: the peers to which a broadcast has been queued
+    fn pushed_to(outbox: &Outbox) -> HashSet<String> {
+        outbox.pending().into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    fn peers(peers: &[&str]) -> HashSet<String> {
+        peers.iter().map(|peer| peer.to_string()).collect()
+    }
+
+    fn sync_ok(messages: Vec<Value>, offset: usize, incarnation: u64) -> Message {
+        Message::new("n2", "n1", MessageType::sync_ok)
+            .with_in_reply_to(1_usize)
+            .with_messages(
+                messages
+                    .iter()
+                    .map(|message| to_raw(&message.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+            .with_offset(offset)
+            .with_incarnation(incarnation)
+    }
+
+    #[test]
+    fn a_restarted_peer_is_synchronised_from_the_beginning() {
+        let (n1, _) = node("n1");
+        let broadcast_server = Arc::new(RwLock::new(BroadcastServer::default()));
+        let handler = SyncOkHandler {
+            broadcast_server: broadcast_server.clone(),
+            outbox: outbox(),
+            dissemination: Arc::new(Silent),
+        };
+        let (sender, requests) = mpsc::channel();
+
+        let sync = sync_ok(vec![json!(1), json!(2)], 2, 7);
+        handler.handle(sender.clone(), &RequestContext::new(&n1, &sync));
+        assert_eq!(broadcast_server.read().unwrap().sync_offset("n2"), 2);
+        assert!(requests.try_recv().is_err());
+
+        // the peer lost its log and has received a new message since
+        handler.handle(sender, &RequestContext::new(&n1, &sync_ok(vec![], 1, 8)));
+        assert_eq!(broadcast_server.read().unwrap().sync_offset("n2"), 0);
+        let request = requests.try_recv().expect("No sync request");
+        assert_eq!(request.dest, "n2");
+        assert_eq!(request.body.message_type, MessageType::sync);
+        assert_eq!(request.body.offset, Some(0));
+    }
+
+    #[test]
+    fn sync_offsets_only_advance_within_an_incarnation() {
+        let (n1, _) = node("n1");
+        let broadcast_server = Arc::new(RwLock::new(BroadcastServer::default()));
+        let handler = SyncOkHandler {
+            broadcast_server: broadcast_server.clone(),
+            outbox: outbox(),
+            dissemination: Arc::new(Silent),
+        };
+        let (sender, _requests) = mpsc::channel();
+
+        handler.handle(
+            sender.clone(),
+            &RequestContext::new(&n1, &sync_ok(vec![json!(1)], 3, 7)),
+        );
+        // a reordered response to an earlier request
+        handler.handle(sender, &RequestContext::new(&n1, &sync_ok(vec![], 1, 7)));
+        assert_eq!(broadcast_server.read().unwrap().sync_offset("n2"), 3);
+        assert!(broadcast_server.read().unwrap().contains("1"));
+        assert_eq!(broadcast_server.read().unwrap().log.len(), 1);
+    }
+
+    #[test]
+    fn a_neighbour_queue_keeps_at_most_a_window_of_broadcasts_in_flight() {
+        let mut queue = queue(WINDOW_SIZE + 2);
+        let (sender, sent) = mpsc::channel();
+        let now = Instant::now();
+        let retry_base = Duration::from_millis(10);
+
+        let next = queue.transmit("n2", now, retry_base, &sender, &stats());
+        assert_eq!(sent.try_iter().count(), WINDOW_SIZE);
+        assert!(next.is_some_and(|deadline| deadline > now));
+        assert_eq!(queue.backlog.len(), 2);
+
+        assert!(queue.acknowledge(0).is_some());
+        assert!(queue.acknowledge(0).is_none(), "Duplicate acknowledgement");
+        queue.transmit("n2", now, retry_base, &sender, &stats());
+        let sent: Vec<Message> = sent.try_iter().collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].body.msg_id, Some(WINDOW_SIZE));
+    }
+
+    #[test]
+    fn a_neighbour_queue_stops_sending_to_an_unreachable_neighbour() {
+        let broadcasts = FAILURE_THRESHOLD as usize;
+        let mut queue = queue(broadcasts);
+        let (sender, sent) = mpsc::channel();
+        let start = Instant::now();
+        let retry_base = Duration::from_millis(10);
+        queue.transmit("n2", start, retry_base, &sender, &stats());
+        assert_eq!(sent.try_iter().count(), broadcasts);
+
+        // every transmission times out, which opens the circuit
+        let later = start + Duration::from_secs(1);
+        let until = queue.transmit("n2", later, retry_base, &sender, &stats());
+        assert!(matches!(queue.circuit, Circuit::Open { .. }));
+        assert_eq!(
+            until,
+            Some(later + Duration::from_millis(BASELINE_COOLDOWN_MS))
+        );
+        assert_eq!(sent.try_iter().count(), 0);
+        // nothing is sent during the cooldown
+        let during = later + Duration::from_millis(BASELINE_COOLDOWN_MS / 2);
+        assert_eq!(
+            queue.transmit("n2", during, retry_base, &sender, &stats()),
+            until
+        );
+        assert_eq!(sent.try_iter().count(), 0);
+
+        // afterwards a single probe is sent, and its loss re-opens the circuit for longer
+        let after = until.unwrap();
+        queue.transmit("n2", after, retry_base, &sender, &stats());
+        assert!(matches!(queue.circuit, Circuit::HalfOpen));
+        assert_eq!(sent.try_iter().count(), 1);
+        let lost = after + Duration::from_secs(1);
+        let until = queue.transmit("n2", lost, retry_base, &sender, &stats());
+        assert_eq!(
+            until,
+            Some(lost + Duration::from_millis(2 * BASELINE_COOLDOWN_MS))
+        );
+
+        // the probe is acknowledged once the neighbour is reachable, which closes the circuit
+        let after = until.unwrap();
+        queue.transmit("n2", after, retry_base, &sender, &stats());
+        let probe = sent.try_iter().last().expect("No probe sent");
+        assert!(queue.acknowledge(probe.body.msg_id.unwrap()).is_some());
+        assert!(queue.is_reachable());
+        queue.transmit("n2", after, retry_base, &sender, &stats());
+        assert_eq!(sent.try_iter().count(), broadcasts - 1);
+    }
+
+    #[test]
+    fn flooding_pushes_to_every_neighbour_but_the_sender() {
+        let (node, _) = cluster_node();
+        let server = server(&["n2", "n3", "n4"]);
+        let (outbox, (sender, _)) = (outbox(), mpsc::channel());
+        let gossip = Gossip {
+            node: &node,
+            broadcast_server: &server,
+            outbox: &outbox,
+            response_sender: &sender,
+        };
+
+        Flooding.on_new(&gossip, "1", Some("n2"));
+        assert_eq!(pushed_to(&outbox), peers(&["n3", "n4"]));
+        Flooding.on_new(&gossip, "2", None);
+        assert_eq!(pushed_to(&outbox), peers(&["n2", "n3", "n4"]));
+    }
+
+    #[test]
+    fn push_pull_gossips_with_fanout_random_peers() {
+        let (node, _) = cluster_node();
+        // the topology is ignored
+        let server = server(&["n2"]);
+        let push_pull = PushPull {
+            tuning: Arc::new(tuning(2)),
+        };
+        for _ in 0..20 {
+            let (outbox, (sender, requests)) = (outbox(), mpsc::channel());
+            let gossip = Gossip {
+                node: &node,
+                broadcast_server: &server,
+                outbox: &outbox,
+                response_sender: &sender,
+            };
+
+            push_pull.on_new(&gossip, "1", Some("n2"));
+            let pushed = pushed_to(&outbox);
+            assert_eq!(pushed.len(), 2);
+            assert!(
+                !pushed.contains("n1") && !pushed.contains("n2"),
+                "{:?}",
+                pushed
+            );
+
+            push_pull.on_tick(&gossip);
+            let pulled: HashSet<String> = requests
+                .try_iter()
+                .inspect(|request| assert_eq!(request.body.message_type, MessageType::sync))
+                .map(|request| request.dest)
+                .collect();
+            assert_eq!(pulled.len(), 2);
+            assert!(!pulled.contains("n1"));
+        }
+    }
+
+    #[test]
+    fn plumtree_prunes_redundant_paths_and_announces_along_them() {
+        let (node, _) = cluster_node();
+        let server = server(&["n2", "n3", "n4"]);
+        let (outbox, (sender, notifications)) = (outbox(), mpsc::channel());
+        let gossip = Gossip {
+            node: &node,
+            broadcast_server: &server,
+            outbox: &outbox,
+            response_sender: &sender,
+        };
+        let plumtree = Plumtree::default();
+
+        // n3 delivered a message that had already arrived from another peer
+        plumtree.on_duplicate(&gossip, "1", Some("n3"));
+        {
+            let state = plumtree.state(&gossip);
+            assert_eq!(state.eager, peers(&["n2", "n4"]));
+            assert_eq!(state.lazy, peers(&["n3"]));
+        }
+        let prune = notifications.try_recv().expect("No prune sent");
+        assert_eq!(prune.dest, "n3");
+        assert_eq!(prune.body.message_type, MessageType::prune);
+
+        plumtree.on_new(&gossip, "2", Some("n2"));
+        assert_eq!(pushed_to(&outbox), peers(&["n4"]));
+        assert_eq!(
+            plumtree.state(&gossip).announcements.get("n3"),
+            Some(&vec!["2".to_string()])
+        );
+
+        // a message first received from a lazy peer repairs the tree through it
+        plumtree.on_new(&gossip, "3", Some("n3"));
+        assert!(plumtree.state(&gossip).eager.contains("n3"));
+    }
+
+    /// Returns: adaptive tuning aiming for 100ms, with a fanout of 3, after observing a single
+    /// delivery
+    fn adaptive_tuning(latency_ms: f64, attempts: u32) -> Tuning {
+        let tuning = Tuning {
+            adaptive: true,
+            target_latency_ms: 100.0,
+            ..tuning(3)
+        };
+        tuning.observe(latency_ms, attempts);
+        tuning
+    }
+
+    fn settings(tuning: &Tuning) -> (u64, usize, u64) {
+        (
+            tuning.retry_base_ms.load(Ordering::Relaxed),
+            tuning.fanout(),
+            tuning.gossip_interval_ms.load(Ordering::Relaxed),
+        )
+    }
+
+    #[test]
+    fn tuning_is_unchanged_until_a_delivery_is_observed() {
+        let adaptive = Tuning {
+            adaptive: true,
+            ..tuning(3)
+        };
+        adaptive.adjust(4, &stats());
+        assert_eq!(
+            settings(&adaptive),
+            (BASELINE_SLEEP_MS, 3, DEFAULT_GOSSIP_INTERVAL_MS)
+        );
+        // deliveries are not observed unless the tuning is adaptive
+        let fixed = tuning(3);
+        fixed.observe(1_000.0, 1);
+        fixed.adjust(4, &stats());
+        assert_eq!(
+            settings(&fixed),
+            (BASELINE_SLEEP_MS, 3, DEFAULT_GOSSIP_INTERVAL_MS)
+        );
+    }
+
+    #[test]
+    fn slow_deliveries_widen_and_speed_up_gossip_within_bounds() {
+        let tuning = adaptive_tuning(400.0, 1);
+        tuning.adjust(4, &stats());
+        assert_eq!(settings(&tuning), (MAX_SLEEP_MS, 4, 100));
+        for _ in 0..10 {
+            tuning.adjust(4, &stats());
+        }
+        assert_eq!(settings(&tuning), (MAX_SLEEP_MS, 4, MIN_GOSSIP_INTERVAL_MS));
+    }
+
+    #[test]
+    fn fast_deliveries_narrow_and_slow_down_gossip_within_bounds() {
+        let tuning = adaptive_tuning(8.0, 1);
+        tuning.adjust(4, &stats());
+        assert_eq!(settings(&tuning), (6, 2, 400));
+        for _ in 0..10 {
+            tuning.adjust(4, &stats());
+        }
+        assert_eq!(settings(&tuning), (6, 1, MAX_GOSSIP_INTERVAL_MS));
+    }
+
+    #[test]
+    fn fast_deliveries_that_needed_retries_keep_the_gossip_settings() {
+        let tuning = adaptive_tuning(8.0, 3);
+        tuning.adjust(4, &stats());
+        // only first attempts measure the round trip
+        assert_eq!(
+            settings(&tuning),
+            (BASELINE_SLEEP_MS, 3, DEFAULT_GOSSIP_INTERVAL_MS)
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use crate::node::AppError::MissingField;
+use crate::node::{AppError, Node};
+use crate::protocol::Message;
+use crate::protocol::MessageType;
+use crate::server::{Response, ServerBuilder};
+use crate::workloads::ConfigError;
+
+/// Install the `echo` workload
+pub fn register(builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    Ok(builder.with_handler(MessageType::echo, Box::new(echo)))
+}
+
+fn echo(_node: &Node, request: &Message) -> Result<EchoResponse, AppError> {
+    if request.body.echo.is_none() {
+        return Err(MissingField(String::from("body.echo")));
+    }
+
+    Ok(EchoResponse {
+        text: request.body.echo.clone().unwrap(),
+    })
+}
+
+struct EchoResponse {
+    text: String,
+}
+
+impl Response for EchoResponse {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, MessageType::echo_ok)
+                .with_echo(self.text.clone()),
+        ]
+    }
+}
@@ -0,0 +1,91 @@
+use serde_json::Value;
+use statsd::Client;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::context::RequestContext;
+use crate::crdt::{GSet, Replicator};
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType};
+use crate::server::{RequestHandler, Response, ServerBuilder};
+use crate::workloads::ConfigError;
+
+/// How often each node sends its state to every other node
+const REPLICATION_INTERVAL_MS: u64 = 500;
+
+/// The elements are kept as their JSON representations because JSON values cannot be hashed
+type Elements = GSet<String>;
+
+struct AddHandler {
+    set: Replicator<Elements>,
+}
+
+impl RequestHandler for AddHandler {
+    fn handle(&self, context: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        let request = context.request();
+        let element = request
+            .body
+            .element
+            .as_ref()
+            .ok_or_else(|| AppError::MissingField("body.element".to_string()))?;
+        self.set.write().insert(element.to_string());
+        Ok(Box::new(AddOk {}))
+    }
+}
+
+struct AddOk;
+
+impl Response for AddOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![Message::reply_to(
+            node,
+            caller,
+            in_reply_to,
+            MessageType::add_ok,
+        )]
+    }
+}
+
+struct ReadHandler {
+    set: Replicator<Elements>,
+}
+
+impl RequestHandler for ReadHandler {
+    fn handle(&self, _: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        let elements = self
+            .set
+            .read()
+            .elements()
+            .map(|element| serde_json::from_str(element).expect("Cannot convert back to JSON"))
+            .collect();
+        Ok(Box::new(ReadOk { elements }))
+    }
+}
+
+struct ReadOk {
+    elements: Vec<Value>,
+}
+
+impl Response for ReadOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, MessageType::read_ok)
+                .with_value(Value::Array(self.elements.clone())),
+        ]
+    }
+}
+
+/// Install the `g-set` workload
+pub fn register(builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let stats = Arc::new(Client::new("localhost:8125", "g-set").unwrap());
+    let set: Replicator<Elements> = Replicator::new(Duration::from_millis(REPLICATION_INTERVAL_MS));
+    let add_handler = AddHandler { set: set.clone() };
+    let read_handler = ReadHandler { set: set.clone() };
+
+    Ok(builder
+        .with_stats(stats)
+        .with_handler(MessageType::add, Box::new(add_handler))
+        .with_handler(MessageType::read, Box::new(read_handler))
+        .with_module(MessageType::replicate, Box::new(set.clone()))
+        .with_timer(Arc::new(set)))
+}
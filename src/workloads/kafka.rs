@@ -0,0 +1,758 @@
+use serde_json::{json, Value};
+use statsd::Client;
+use std::collections::HashMap;
+use std::env;
+use std::future::ready;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::context::AsyncRequestContext;
+use crate::executor::{self, BoxFuture};
+use crate::kv::KvClient;
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType};
+use crate::server::{AsyncRequestHandler, Response, ServerBuilder};
+use crate::workloads::ConfigError;
+
+/// The most messages returned from a single log in response to a poll
+const MAX_POLL_MESSAGES: usize = 64;
+
+/// How long a send keeps retrying the write of a message into the offset it claimed
+const SEND_DEADLINE: Duration = Duration::from_secs(2);
+
+/// How long to wait between attempts to write a message
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a claimed offset may have no message before a poll abandons it
+const ABANDON_AFTER: Duration = Duration::from_secs(5);
+
+/// Where the logs are kept, selected with the `KAFKA_STORAGE` environment variable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StorageMode {
+    /// In the node's memory, which is only correct when there is a single node
+    Memory,
+    /// In Maelstrom's linearizable key/value service
+    LinKv,
+    /// In Maelstrom's sequentially consistent key/value service
+    SeqKv,
+}
+
+impl StorageMode {
+    /// Returns: the mode configured in the environment, `None` to choose based on the cluster
+    /// size, or an error if the mode is not recognised
+    fn from_env() -> Result<Option<Self>, ConfigError> {
+        match env::var("KAFKA_STORAGE").as_deref() {
+            Ok("memory") => Ok(Some(StorageMode::Memory)),
+            Ok("lin-kv") => Ok(Some(StorageMode::LinKv)),
+            Ok("seq-kv") => Ok(Some(StorageMode::SeqKv)),
+            Ok(other) => Err(ConfigError::InvalidSetting {
+                variable: "KAFKA_STORAGE".to_string(),
+                value: other.to_string(),
+            }),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// A set of append-only logs, each identified by a key, along with the offset up to which each
+/// log has been processed by the clients
+trait LogStore: Send + Sync {
+    /// Append a message to a log, creating the log if it does not exist
+    ///
+    /// Returns: the offset assigned to the message
+    fn send<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+        msg: Value,
+    ) -> BoxFuture<'a, Result<usize, AppError>>;
+
+    /// Returns: the messages in the log starting at `offset`, in order and paired with their
+    /// offsets
+    fn poll<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+        offset: usize,
+    ) -> BoxFuture<'a, Result<Vec<(usize, Value)>, AppError>>;
+
+    /// Record that the messages in a log have been processed up to and including `offset`. The
+    /// committed offset never decreases.
+    fn commit_offset<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+        offset: usize,
+    ) -> BoxFuture<'a, Result<(), AppError>>;
+
+    /// Returns: the committed offset of a log, if any have been committed
+    fn committed_offset<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<usize>, AppError>>;
+}
+
+#[derive(Default)]
+struct Log {
+    messages: Vec<Value>,
+    committed: Option<usize>,
+}
+
+/// Logs kept in the node's memory
+#[derive(Default)]
+struct MemoryLogs {
+    logs: Mutex<HashMap<String, Log>>,
+}
+
+impl LogStore for MemoryLogs {
+    fn send<'a>(
+        &'a self,
+        _: &'a Node,
+        key: &'a str,
+        msg: Value,
+    ) -> BoxFuture<'a, Result<usize, AppError>> {
+        let mut logs = self.logs.lock().expect("Unable to append: lock poisoned");
+        let log = logs.entry(key.to_string()).or_default();
+        log.messages.push(msg);
+        Box::pin(ready(Ok(log.messages.len() - 1)))
+    }
+
+    fn poll<'a>(
+        &'a self,
+        _: &'a Node,
+        key: &'a str,
+        offset: usize,
+    ) -> BoxFuture<'a, Result<Vec<(usize, Value)>, AppError>> {
+        let logs = self.logs.lock().expect("Unable to poll: lock poisoned");
+        let msgs = logs
+            .get(key)
+            .map(|log| {
+                log.messages
+                    .iter()
+                    .enumerate()
+                    .skip(offset)
+                    .take(MAX_POLL_MESSAGES)
+                    .map(|(offset, msg)| (offset, msg.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(ready(Ok(msgs)))
+    }
+
+    fn commit_offset<'a>(
+        &'a self,
+        _: &'a Node,
+        key: &'a str,
+        offset: usize,
+    ) -> BoxFuture<'a, Result<(), AppError>> {
+        let mut logs = self.logs.lock().expect("Unable to commit: lock poisoned");
+        let log = logs.entry(key.to_string()).or_default();
+        log.committed = log.committed.max(Some(offset));
+        Box::pin(ready(Ok(())))
+    }
+
+    fn committed_offset<'a>(
+        &'a self,
+        _: &'a Node,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<usize>, AppError>> {
+        let logs = self.logs.lock().expect("Unable to list: lock poisoned");
+        Box::pin(ready(Ok(logs.get(key).and_then(|log| log.committed))))
+    }
+}
+
+/// Logs kept in one of Maelstrom's key/value services so that every node sees the same logs. Each
+/// log is stored as:
+/// - `{key}/next` - the next offset to assign, which is incremented with compare-and-set
+/// - `{key}/{offset}` - each message in a single element array, written once its offset has been
+///   assigned
+/// - `{key}/committed` - the committed offset
+///
+/// A message is only written after its offset has been claimed, so a poll may find an offset
+/// below `next` that has no message yet. It stops there rather than skip over it, unless the
+/// offset has stayed empty for `ABANDON_AFTER`, for example because the node that claimed it
+/// crashed. Then the poll abandons the offset by writing an empty array into it, and polls skip it
+/// from then on. Both are written with a compare-and-set that only creates the key, so an offset
+/// holds either the message or the empty array, and a send that finds its offset abandoned claims
+/// another.
+struct KvLogs {
+    client: KvClient,
+    /// Offsets never change once written, so their contents can be cached
+    cache: Mutex<HashMap<(String, usize), Value>>,
+    /// When each claimed offset was first found to have no message
+    empty_since: Mutex<HashMap<(String, usize), Instant>>,
+}
+
+impl KvLogs {
+    fn new(client: KvClient) -> Self {
+        Self {
+            client,
+            cache: Default::default(),
+            empty_since: Default::default(),
+        }
+    }
+
+    /// Returns: the value of the key, or `None` if it does not exist
+    async fn read(&self, node: &Node, key: String) -> Result<Option<Value>, AppError> {
+        match self.client.read_async(node, key).await {
+            Ok(value) => Ok(Some(value)),
+            Err(AppError::KeyDoesNotExist(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set the key to `to` if it currently has the value `from`, or if it does not exist.
+    ///
+    /// Returns: whether the value was set
+    async fn compare_and_set(
+        &self,
+        node: &Node,
+        key: String,
+        from: Value,
+        to: Value,
+    ) -> Result<bool, AppError> {
+        match self.client.cas_async(node, key, from, to, true).await {
+            Ok(()) => Ok(true),
+            Err(AppError::KeyDoesNotExist(_)) | Err(AppError::PreconditionFailed(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Set the key to `value` if it does not exist. Repeating an attempt that may have succeeded
+    /// is harmless.
+    ///
+    /// Returns: whether the key now has the value, rather than a different one
+    async fn create(&self, node: &Node, key: String, value: Value) -> Result<bool, AppError> {
+        self.compare_and_set(node, key, value.clone(), value).await
+    }
+
+    /// Returns: the value of the key as an offset, or `None` if it does not exist
+    async fn read_offset(&self, node: &Node, key: String) -> Result<Option<usize>, AppError> {
+        match self.read(node, key.clone()).await? {
+            None => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .map(|offset| Some(offset as usize))
+                .ok_or_else(|| AppError::Unavailable(format!("{} is not an offset", key))),
+        }
+    }
+
+    /// Returns: the offset claimed for a new message
+    async fn claim(&self, node: &Node, key: &str) -> Result<usize, AppError> {
+        let next_key = format!("{}/next", key);
+        loop {
+            let next = self.read_offset(node, next_key.clone()).await?.unwrap_or(0);
+            if self
+                .compare_and_set(node, next_key.clone(), next.into(), (next + 1).into())
+                .await?
+            {
+                return Ok(next);
+            }
+            // another node claimed this offset first
+        }
+    }
+
+    /// Write a message into the offset claimed for it, retrying until `deadline` if the service
+    /// fails
+    ///
+    /// Returns: `false` if a poll abandoned the offset first
+    async fn fill(
+        &self,
+        node: &Node,
+        key: &str,
+        offset: usize,
+        msg: &Value,
+        deadline: Instant,
+    ) -> Result<bool, AppError> {
+        loop {
+            match self
+                .create(node, format!("{}/{}", key, offset), json!([msg]))
+                .await
+            {
+                Ok(filled) => return Ok(filled),
+                Err(e) if Instant::now() < deadline => {
+                    eprintln!("Retrying write of {}/{}: {:?}", key, offset, e);
+                    executor::sleep(SEND_RETRY_INTERVAL).await;
+                }
+                // an earlier attempt may have written the message, otherwise a poll will
+                // eventually abandon the offset
+                Err(_) => return Err(AppError::Timeout),
+            }
+        }
+    }
+
+    /// Returns: the contents of the offset, an array of the message or an empty array if the
+    /// offset was abandoned, or `None` if it has no message yet
+    async fn slot(&self, node: &Node, key: &str, offset: usize) -> Result<Option<Value>, AppError> {
+        let id = (key.to_string(), offset);
+        let cached = self
+            .cache
+            .lock()
+            .expect("Unable to read cache: lock poisoned")
+            .get(&id)
+            .cloned();
+        if cached.is_some() {
+            return Ok(cached);
+        }
+        let slot_key = format!("{}/{}", key, offset);
+        let mut contents = self.read(node, slot_key.clone()).await?;
+        if contents.is_none() {
+            let empty_since = *self
+                .empty_since
+                .lock()
+                .expect("Unable to track empty offsets: lock poisoned")
+                .entry(id.clone())
+                .or_insert_with(Instant::now);
+            if empty_since.elapsed() < ABANDON_AFTER {
+                return Ok(None);
+            }
+            contents = if self.create(node, slot_key.clone(), json!([])).await? {
+                Some(json!([]))
+            } else {
+                // the message was written after all
+                self.read(node, slot_key).await?
+            };
+        }
+        if let Some(contents) = &contents {
+            self.empty_since
+                .lock()
+                .expect("Unable to track empty offsets: lock poisoned")
+                .remove(&id);
+            self.cache
+                .lock()
+                .expect("Unable to cache message: lock poisoned")
+                .insert(id, contents.clone());
+        }
+        Ok(contents)
+    }
+}
+
+impl LogStore for KvLogs {
+    fn send<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+        msg: Value,
+    ) -> BoxFuture<'a, Result<usize, AppError>> {
+        Box::pin(async move {
+            let deadline = Instant::now() + SEND_DEADLINE;
+            loop {
+                let offset = self.claim(node, key).await?;
+                if self.fill(node, key, offset, &msg, deadline).await? {
+                    self.cache
+                        .lock()
+                        .expect("Unable to cache message: lock poisoned")
+                        .insert((key.to_string(), offset), json!([msg]));
+                    return Ok(offset);
+                }
+                // a poll abandoned the offset before the message was written
+            }
+        })
+    }
+
+    fn poll<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+        offset: usize,
+    ) -> BoxFuture<'a, Result<Vec<(usize, Value)>, AppError>> {
+        Box::pin(async move {
+            let next = self
+                .read_offset(node, format!("{}/next", key))
+                .await?
+                .unwrap_or(0);
+            let offsets = offset..next.min(offset + MAX_POLL_MESSAGES);
+            let slots = executor::join_all(
+                offsets
+                    .clone()
+                    .map(|offset| Box::pin(self.slot(node, key, offset)) as BoxFuture<_>)
+                    .collect(),
+            )
+            .await;
+            let mut result = vec![];
+            for (offset, slot) in offsets.zip(slots) {
+                match slot? {
+                    // an abandoned offset holds an empty array
+                    Some(Value::Array(mut contents)) => {
+                        result.extend(contents.pop().map(|msg| (offset, msg)))
+                    }
+                    Some(_) => {
+                        return Err(AppError::Unavailable(format!(
+                            "{}/{} is not a message",
+                            key, offset
+                        )))
+                    }
+                    // not written yet, later messages must wait for it
+                    None => break,
+                }
+            }
+            Ok(result)
+        })
+    }
+
+    fn commit_offset<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+        offset: usize,
+    ) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            let committed_key = format!("{}/committed", key);
+            loop {
+                let committed = self.read_offset(node, committed_key.clone()).await?;
+                if committed >= Some(offset) {
+                    return Ok(());
+                }
+                // a missing key is created regardless of `from`
+                let from = committed.unwrap_or(0).into();
+                if self
+                    .compare_and_set(node, committed_key.clone(), from, offset.into())
+                    .await?
+                {
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    fn committed_offset<'a>(
+        &'a self,
+        node: &'a Node,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<usize>, AppError>> {
+        Box::pin(self.read_offset(node, format!("{}/committed", key)))
+    }
+}
+
+/// The logs, kept wherever the configured `StorageMode` dictates
+#[derive(Clone)]
+struct Logs {
+    mode: Option<StorageMode>,
+    memory: Arc<MemoryLogs>,
+    lin_kv: Arc<KvLogs>,
+    seq_kv: Arc<KvLogs>,
+}
+
+impl Logs {
+    fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            mode: StorageMode::from_env()?,
+            memory: Default::default(),
+            lin_kv: Arc::new(KvLogs::new(KvClient::lin_kv())),
+            seq_kv: Arc::new(KvLogs::new(KvClient::seq_kv())),
+        })
+    }
+
+    /// Returns: the configured store, by default memory for a single node, otherwise lin-kv
+    fn store(&self, node: &Node) -> &dyn LogStore {
+        let mode = self.mode.unwrap_or(if node.node_ids.len() <= 1 {
+            StorageMode::Memory
+        } else {
+            StorageMode::LinKv
+        });
+        match mode {
+            StorageMode::Memory => self.memory.as_ref(),
+            StorageMode::LinKv => self.lin_kv.as_ref(),
+            StorageMode::SeqKv => self.seq_kv.as_ref(),
+        }
+    }
+}
+
+/// Logs are identified by string keys, but accept any JSON value
+fn log_key(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        other => other.to_string(),
+    }
+}
+
+struct SendHandler {
+    logs: Logs,
+}
+
+impl SendHandler {
+    async fn execute(
+        logs: Logs,
+        node: &Node,
+        request: &Message,
+    ) -> Result<Box<dyn Response>, AppError> {
+        let key = request
+            .body
+            .key
+            .as_ref()
+            .ok_or_else(|| AppError::MissingField("body.key".to_string()))?;
+        let msg = request
+            .body
+            .msg
+            .clone()
+            .ok_or_else(|| AppError::MissingField("body.msg".to_string()))?;
+        let offset = logs.store(node).send(node, &log_key(key), msg).await?;
+        Ok(Box::new(SendOk { offset }))
+    }
+}
+
+impl AsyncRequestHandler for SendHandler {
+    fn handle(
+        &self,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, Result<Box<dyn Response>, AppError>> {
+        let logs = self.logs.clone();
+        Box::pin(async move { Self::execute(logs, context.node(), context.request()).await })
+    }
+}
+
+struct SendOk {
+    offset: usize,
+}
+
+impl Response for SendOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, MessageType::send_ok)
+                .with_offset(self.offset),
+        ]
+    }
+}
+
+struct PollHandler {
+    logs: Logs,
+}
+
+impl PollHandler {
+    async fn execute(
+        logs: Logs,
+        node: &Node,
+        request: &Message,
+    ) -> Result<Box<dyn Response>, AppError> {
+        let offsets = request
+            .body
+            .offsets
+            .as_ref()
+            .ok_or_else(|| AppError::MissingField("body.offsets".to_string()))?;
+        let store = logs.store(node);
+        // the logs are polled concurrently
+        let polls = executor::join_all(
+            offsets
+                .iter()
+                .map(|(key, offset)| store.poll(node, key, *offset))
+                .collect(),
+        )
+        .await;
+        let mut msgs = HashMap::with_capacity(offsets.len());
+        for (key, poll) in offsets.keys().zip(polls) {
+            msgs.insert(key.clone(), poll?);
+        }
+        Ok(Box::new(PollOk { msgs }))
+    }
+}
+
+impl AsyncRequestHandler for PollHandler {
+    fn handle(
+        &self,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, Result<Box<dyn Response>, AppError>> {
+        let logs = self.logs.clone();
+        Box::pin(async move { Self::execute(logs, context.node(), context.request()).await })
+    }
+}
+
+struct PollOk {
+    msgs: HashMap<String, Vec<(usize, Value)>>,
+}
+
+impl Response for PollOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, MessageType::poll_ok)
+                .with_msgs(self.msgs.clone()),
+        ]
+    }
+}
+
+struct CommitOffsetsHandler {
+    logs: Logs,
+}
+
+impl CommitOffsetsHandler {
+    async fn execute(
+        logs: Logs,
+        node: &Node,
+        request: &Message,
+    ) -> Result<Box<dyn Response>, AppError> {
+        let offsets = request
+            .body
+            .offsets
+            .as_ref()
+            .ok_or_else(|| AppError::MissingField("body.offsets".to_string()))?;
+        let store = logs.store(node);
+        for (key, offset) in offsets {
+            store.commit_offset(node, key, *offset).await?;
+        }
+        Ok(Box::new(CommitOffsetsOk {}))
+    }
+}
+
+impl AsyncRequestHandler for CommitOffsetsHandler {
+    fn handle(
+        &self,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, Result<Box<dyn Response>, AppError>> {
+        let logs = self.logs.clone();
+        Box::pin(async move { Self::execute(logs, context.node(), context.request()).await })
+    }
+}
+
+struct CommitOffsetsOk;
+
+impl Response for CommitOffsetsOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![Message::reply_to(
+            node,
+            caller,
+            in_reply_to,
+            MessageType::commit_offsets_ok,
+        )]
+    }
+}
+
+struct ListCommittedOffsetsHandler {
+    logs: Logs,
+}
+
+impl ListCommittedOffsetsHandler {
+    async fn execute(
+        logs: Logs,
+        node: &Node,
+        request: &Message,
+    ) -> Result<Box<dyn Response>, AppError> {
+        let keys = request
+            .body
+            .keys
+            .as_ref()
+            .ok_or_else(|| AppError::MissingField("body.keys".to_string()))?;
+        let store = logs.store(node);
+        let mut offsets = HashMap::with_capacity(keys.len());
+        for key in keys {
+            // logs without a committed offset are omitted
+            if let Some(offset) = store.committed_offset(node, key).await? {
+                offsets.insert(key.clone(), offset);
+            }
+        }
+        Ok(Box::new(ListCommittedOffsetsOk { offsets }))
+    }
+}
+
+impl AsyncRequestHandler for ListCommittedOffsetsHandler {
+    fn handle(
+        &self,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, Result<Box<dyn Response>, AppError>> {
+        let logs = self.logs.clone();
+        Box::pin(async move { Self::execute(logs, context.node(), context.request()).await })
+    }
+}
+
+struct ListCommittedOffsetsOk {
+    offsets: HashMap<String, usize>,
+}
+
+impl Response for ListCommittedOffsetsOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![Message::reply_to(
+            node,
+            caller,
+            in_reply_to,
+            MessageType::list_committed_offsets_ok,
+        )
+        .with_offsets(self.offsets.clone())]
+    }
+}
+
+/// Install the `kafka` workload
+pub fn register(builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let stats = Arc::new(Client::new("localhost:8125", "kafka").unwrap());
+    let logs = Logs::from_env()?;
+
+    Ok(builder
+        .with_stats(stats)
+        .with_async_handler(
+            MessageType::send,
+            Box::new(SendHandler { logs: logs.clone() }),
+        )
+        .with_async_handler(
+            MessageType::poll,
+            Box::new(PollHandler { logs: logs.clone() }),
+        )
+        .with_async_handler(
+            MessageType::commit_offsets,
+            Box::new(CommitOffsetsHandler { logs: logs.clone() }),
+        )
+        .with_async_handler(
+            MessageType::list_committed_offsets,
+            Box::new(ListCommittedOffsetsHandler { logs }),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::block_on;
+    use crate::services::{node_with, LinKv, SeqKv};
+
+    fn logs(mode: StorageMode) -> Logs {
+        Logs {
+            mode: Some(mode),
+            memory: Default::default(),
+            lin_kv: Arc::new(KvLogs::new(KvClient::lin_kv())),
+            seq_kv: Arc::new(KvLogs::new(KvClient::seq_kv())),
+        }
+    }
+
+    /// A claimed offset that is never written holds up polls until it is abandoned
+    fn abandons_unwritten_offsets(logs: &Logs, kv_logs: &KvLogs, node: &Node) {
+        let store = logs.store(node);
+        assert_eq!(block_on(store.send(node, "k", json!(1))).unwrap(), 0);
+        // a sender that crashed after claiming its offset
+        assert_eq!(block_on(kv_logs.claim(node, "k")).unwrap(), 1);
+        assert_eq!(block_on(store.send(node, "k", json!(3))).unwrap(), 2);
+
+        assert_eq!(
+            block_on(store.poll(node, "k", 0)).unwrap(),
+            vec![(0, json!(1))]
+        );
+        // pretend the offset was found empty long enough ago
+        for empty_since in kv_logs.empty_since.lock().unwrap().values_mut() {
+            *empty_since -= ABANDON_AFTER;
+        }
+        assert_eq!(
+            block_on(store.poll(node, "k", 0)).unwrap(),
+            vec![(0, json!(1)), (2, json!(3))]
+        );
+        assert_eq!(
+            block_on(kv_logs.read(node, "k/1".to_string())).unwrap(),
+            Some(json!([]))
+        );
+        // the sender can no longer fill the abandoned offset
+        let deadline = Instant::now() + SEND_DEADLINE;
+        assert!(!block_on(kv_logs.fill(node, "k", 1, &json!(2), deadline)).unwrap());
+        assert_eq!(
+            block_on(store.poll(node, "k", 1)).unwrap(),
+            vec![(2, json!(3))]
+        );
+    }
+
+    #[test]
+    fn lin_kv_logs_abandon_offsets_that_are_never_written() {
+        let logs = logs(StorageMode::LinKv);
+        let node = node_with(Box::new(LinKv::default()));
+        abandons_unwritten_offsets(&logs, &logs.lin_kv, &node);
+    }
+
+    #[test]
+    fn seq_kv_logs_abandon_offsets_that_are_never_written() {
+        let logs = logs(StorageMode::SeqKv);
+        let node = node_with(Box::new(SeqKv::new(2)));
+        abandons_unwritten_offsets(&logs, &logs.seq_kv, &node);
+    }
+}
@@ -0,0 +1,956 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use statsd::Client;
+use std::collections::{HashMap, HashSet};
+use std::future::poll_fn;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::context::{AsyncRequestContext, RequestContext};
+use crate::executor::{self, BoxFuture};
+use crate::node::{AppError, Node};
+use crate::protocol::{LogEntry, Message, MessageBody, MessageType};
+use crate::server::{AsyncRequestHandler, Module, Response, ServerBuilder, Timer};
+use crate::workloads::ConfigError;
+
+/// How often the leader checks whether followers need entries and the followers check whether the
+/// leader has failed
+const TICK_MS: u64 = 10;
+/// The longest the leader stays silent before sending a heartbeat
+const HEARTBEAT_INTERVAL_MS: u64 = 100;
+/// The bounds of the randomised time a follower waits for the leader before starting an election
+const MIN_ELECTION_TIMEOUT_MS: u64 = 500;
+const MAX_ELECTION_TIMEOUT_MS: u64 = 1_000;
+/// The most entries sent to a follower in a single `append_entries` message
+const MAX_ENTRIES_PER_MESSAGE: usize = 128;
+/// How long to wait for an operation to be committed, or for the leader to respond to a forwarded
+/// request
+const CLIENT_TIMEOUT_MS: u64 = 1_000;
+
+/// A client request, stored in the replicated log and applied once committed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Operation {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+}
+
+impl Operation {
+    fn from_request(request: &Message) -> Result<Self, AppError> {
+        let body = &request.body;
+        let key = body
+            .key
+            .clone()
+            .ok_or_else(|| AppError::MissingField("body.key".to_string()))?;
+        match body.message_type {
+            MessageType::read => Ok(Operation::Read { key }),
+            MessageType::write => Ok(Operation::Write {
+                key,
+                value: body
+                    .value
+                    .clone()
+                    .ok_or_else(|| AppError::MissingField("body.value".to_string()))?,
+            }),
+            _ => Ok(Operation::Cas {
+                key,
+                from: body
+                    .from
+                    .clone()
+                    .ok_or_else(|| AppError::MissingField("body.from".to_string()))?,
+                to: body
+                    .to
+                    .clone()
+                    .ok_or_else(|| AppError::MissingField("body.to".to_string()))?,
+                create_if_not_exists: body.create_if_not_exists.unwrap_or(false),
+            }),
+        }
+    }
+
+    /// Apply the operation to the state machine.
+    ///
+    /// Returns: the value read, if any
+    fn apply(&self, store: &mut HashMap<String, Value>) -> Result<Option<Value>, AppError> {
+        match self {
+            Operation::Read { key } => store
+                .get(&key.to_string())
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| AppError::KeyDoesNotExist(key.to_string())),
+            Operation::Write { key, value } => {
+                store.insert(key.to_string(), value.clone());
+                Ok(None)
+            }
+            Operation::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                match store.get(&key.to_string()) {
+                    None if !create_if_not_exists => {
+                        return Err(AppError::KeyDoesNotExist(key.to_string()))
+                    }
+                    Some(current) if current != from => {
+                        return Err(AppError::PreconditionFailed(format!(
+                            "expected {} to be {}, but it was {}",
+                            key, from, current
+                        )))
+                    }
+                    _ => {}
+                }
+                store.insert(key.to_string(), to.clone());
+                Ok(None)
+            }
+        }
+    }
+
+    fn response_type(&self) -> MessageType {
+        match self {
+            Operation::Read { .. } => MessageType::read_ok,
+            Operation::Write { .. } => MessageType::write_ok,
+            Operation::Cas { .. } => MessageType::cas_ok,
+        }
+    }
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<String>,
+    },
+    Leader {
+        /// For each follower, the index of the next entry to send it
+        next_index: HashMap<String, usize>,
+        /// For each follower, the highest index known to be replicated on it
+        match_index: HashMap<String, usize>,
+        /// For each follower, when it was last sent `append_entries`
+        last_sent: HashMap<String, Instant>,
+    },
+}
+
+type Outcome = Result<Option<Value>, AppError>;
+
+/// The outcome of a client operation, once its log entry has been applied
+#[derive(Default)]
+struct PendingOutcome {
+    outcome: Option<Outcome>,
+    /// The task to wake once the outcome is known
+    waker: Option<Waker>,
+}
+
+impl PendingOutcome {
+    fn complete(pending: &Mutex<PendingOutcome>, outcome: Outcome) {
+        let mut pending = pending
+            .lock()
+            .expect("Unable to deliver outcome: lock poisoned");
+        pending.outcome = Some(outcome);
+        if let Some(waker) = pending.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Returns: the outcome once it is known, or `AppError::Timeout` if it is not known in time
+    async fn wait(pending: Arc<Mutex<PendingOutcome>>, timeout: Duration) -> Outcome {
+        let deadline = executor::sleep(timeout);
+        poll_fn(|context| {
+            let mut pending = pending
+                .lock()
+                .expect("Unable to check for outcome: lock poisoned");
+            if let Some(outcome) = pending.outcome.take() {
+                return Poll::Ready(outcome);
+            }
+            pending.waker = Some(context.waker().clone());
+            if deadline.poll_elapsed(context) {
+                return Poll::Ready(Err(AppError::Timeout));
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// A client operation waiting for its log entry to be applied
+struct Waiter {
+    /// The term in which the entry was appended, if the entry at the index has a different term
+    /// when applied, the operation was discarded
+    term: u64,
+    /// Where to deliver the outcome, the client may have given up waiting for it
+    outcome: Arc<Mutex<PendingOutcome>>,
+}
+
+/// The state of a Raft participant. Log indices start at 1, index 0 is before the first entry.
+struct RaftState {
+    current_term: u64,
+    voted_for: Option<String>,
+    log: Vec<LogEntry>,
+    commit_index: usize,
+    last_applied: usize,
+    role: Role,
+    /// The leader of the current term, if known
+    leader: Option<String>,
+    election_deadline: Instant,
+    /// The state machine
+    store: HashMap<String, Value>,
+    /// Client operations proposed on this node, keyed by log index
+    waiters: HashMap<usize, Waiter>,
+}
+
+impl RaftState {
+    /// Returns: a follower with an empty log
+    fn new() -> Self {
+        let mut state = RaftState {
+            current_term: 0,
+            voted_for: None,
+            log: vec![],
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: Instant::now(),
+            store: Default::default(),
+            waiters: Default::default(),
+        };
+        state.reset_election_deadline();
+        state
+    }
+
+    fn last_log_index(&self) -> usize {
+        self.log.len()
+    }
+
+    fn term_at(&self, index: usize) -> u64 {
+        match index {
+            0 => 0,
+            index => self.log[index - 1].term,
+        }
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let timeout = rand::rng().random_range(MIN_ELECTION_TIMEOUT_MS..MAX_ELECTION_TIMEOUT_MS);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+
+    /// Adopt a newer term seen in a message from another node
+    fn step_down(&mut self, term: u64) {
+        self.current_term = term;
+        self.voted_for = None;
+        self.role = Role::Follower;
+        self.leader = None;
+        self.reset_election_deadline();
+    }
+
+    /// Apply the committed entries that have not been applied yet and notify any waiting clients
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.last_applied - 1];
+            let outcome = match serde_json::from_value::<Operation>(entry.command.clone()) {
+                Ok(operation) => operation.apply(&mut self.store),
+                Err(e) => {
+                    eprintln!("Skipping unreadable log entry: {}", e);
+                    continue;
+                }
+            };
+            if let Some(waiter) = self.waiters.remove(&self.last_applied) {
+                let outcome = if waiter.term == entry.term {
+                    outcome
+                } else {
+                    Err(AppError::Unavailable(
+                        "leadership changed before the operation was committed".to_string(),
+                    ))
+                };
+                PendingOutcome::complete(&waiter.outcome, outcome);
+            }
+        }
+    }
+
+    fn start_election(&mut self, response_sender: &Sender<Message>, node: &Node) {
+        self.current_term += 1;
+        self.voted_for = Some(node.node_id.clone());
+        self.role = Role::Candidate {
+            votes: HashSet::from([node.node_id.clone()]),
+        };
+        self.leader = None;
+        self.reset_election_deadline();
+        eprintln!("Starting election for term {}", self.current_term);
+        for peer in node.peers() {
+            let request = Message::request(node, peer, MessageType::request_vote)
+                .with_term(self.current_term)
+                .with_candidate_id(node.node_id.clone())
+                .with_last_log_index(self.last_log_index())
+                .with_last_log_term(self.term_at(self.last_log_index()));
+            response_sender.send(request).unwrap();
+        }
+        self.count_votes(response_sender, node);
+    }
+
+    fn count_votes(&mut self, response_sender: &Sender<Message>, node: &Node) {
+        let Role::Candidate { votes } = &self.role else {
+            return;
+        };
+        if votes.len() <= node.node_ids.len() / 2 {
+            return;
+        }
+        eprintln!("Elected leader for term {}", self.current_term);
+        let next_index = self.last_log_index() + 1;
+        self.role = Role::Leader {
+            next_index: node
+                .peers()
+                .map(|peer| (peer.clone(), next_index))
+                .collect(),
+            match_index: node.peers().map(|peer| (peer.clone(), 0)).collect(),
+            last_sent: Default::default(),
+        };
+        self.leader = Some(node.node_id.clone());
+        self.replicate(response_sender, node);
+    }
+
+    /// As the leader, send `append_entries` to every follower that is missing entries or is due a
+    /// heartbeat
+    fn replicate(&mut self, response_sender: &Sender<Message>, node: &Node) {
+        let now = Instant::now();
+        let heartbeat_interval = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
+        let last_log_index = self.last_log_index();
+        let mut requests = vec![];
+        if let Role::Leader {
+            next_index,
+            last_sent,
+            ..
+        } = &mut self.role
+        {
+            for peer in node.peers() {
+                let next = next_index[peer];
+                let heartbeat_due = last_sent
+                    .get(peer)
+                    .is_none_or(|sent| now.duration_since(*sent) >= heartbeat_interval);
+                if next > last_log_index && !heartbeat_due {
+                    continue;
+                }
+                last_sent.insert(peer.clone(), now);
+                requests.push((peer, next));
+            }
+        }
+        for (peer, next) in requests {
+            let prev_log_index = next - 1;
+            let entries: Vec<_> = self.log[prev_log_index..]
+                .iter()
+                .take(MAX_ENTRIES_PER_MESSAGE)
+                .cloned()
+                .collect();
+            let request = Message::request(node, peer, MessageType::append_entries)
+                .with_term(self.current_term)
+                .with_leader_id(node.node_id.clone())
+                .with_prev_log_index(prev_log_index)
+                .with_prev_log_term(self.term_at(prev_log_index))
+                .with_entries(entries)
+                .with_leader_commit(self.commit_index);
+            response_sender.send(request).unwrap();
+        }
+    }
+
+    /// As the leader, commit the latest entry from the current term that a majority has stored
+    fn advance_commit_index(&mut self, node: &Node) {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+        let majority = node.node_ids.len() / 2 + 1;
+        let committed = (self.commit_index + 1..=self.last_log_index())
+            .rev()
+            // entries from earlier terms are only committed indirectly
+            .take_while(|index| self.term_at(*index) == self.current_term)
+            .find(|index| {
+                // the leader itself has every entry
+                1 + match_index
+                    .values()
+                    .filter(|matched| *matched >= index)
+                    .count()
+                    >= majority
+            });
+        if let Some(committed) = committed {
+            self.commit_index = committed;
+            self.apply_committed();
+        }
+    }
+
+    fn handle_request_vote(
+        &mut self,
+        response_sender: &Sender<Message>,
+        node: &Node,
+        request: &Message,
+    ) {
+        let body = &request.body;
+        let (Some(term), Some(candidate), Some(last_log_index), Some(last_log_term)) = (
+            body.term,
+            body.candidate_id.as_ref(),
+            body.last_log_index,
+            body.last_log_term,
+        ) else {
+            eprintln!("Invalid vote request: {}", request);
+            return;
+        };
+        if term > self.current_term {
+            self.step_down(term);
+        }
+        let my_last_log_term = self.term_at(self.last_log_index());
+        let up_to_date = last_log_term > my_last_log_term
+            || (last_log_term == my_last_log_term && last_log_index >= self.last_log_index());
+        let vote_granted = term == self.current_term
+            && up_to_date
+            && self
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted| voted == candidate);
+        if vote_granted {
+            self.voted_for = Some(candidate.clone());
+            self.reset_election_deadline();
+        }
+        let response = request
+            .reply(node, MessageType::request_vote_ok)
+            .with_term(self.current_term)
+            .with_vote_granted(vote_granted);
+        response_sender.send(response).unwrap();
+    }
+
+    fn handle_request_vote_ok(
+        &mut self,
+        response_sender: &Sender<Message>,
+        node: &Node,
+        response: &Message,
+    ) {
+        let (Some(term), Some(vote_granted)) = (response.body.term, response.body.vote_granted)
+        else {
+            eprintln!("Invalid vote: {}", response);
+            return;
+        };
+        if term > self.current_term {
+            self.step_down(term);
+            return;
+        }
+        if term < self.current_term || !vote_granted {
+            return;
+        }
+        if let Role::Candidate { votes } = &mut self.role {
+            votes.insert(response.src.clone());
+        }
+        self.count_votes(response_sender, node);
+    }
+
+    fn handle_append_entries(
+        &mut self,
+        response_sender: &Sender<Message>,
+        node: &Node,
+        request: &Message,
+    ) {
+        let body = &request.body;
+        let (
+            Some(term),
+            Some(leader),
+            Some(prev_log_index),
+            Some(prev_log_term),
+            Some(entries),
+            Some(leader_commit),
+        ) = (
+            body.term,
+            body.leader_id.as_ref(),
+            body.prev_log_index,
+            body.prev_log_term,
+            body.entries.as_ref(),
+            body.leader_commit,
+        )
+        else {
+            eprintln!("Invalid append request: {}", request);
+            return;
+        };
+        if term > self.current_term {
+            self.step_down(term);
+        }
+        let mut response = request.reply(node, MessageType::append_entries_ok);
+        if term < self.current_term {
+            response_sender
+                .send(response.with_term(self.current_term).with_success(false))
+                .unwrap();
+            return;
+        }
+        self.role = Role::Follower;
+        self.leader = Some(leader.clone());
+        self.reset_election_deadline();
+
+        let success = prev_log_index <= self.last_log_index()
+            && self.term_at(prev_log_index) == prev_log_term;
+        if success {
+            for (offset, entry) in entries.iter().enumerate() {
+                let index = prev_log_index + 1 + offset;
+                if index <= self.last_log_index() {
+                    if self.term_at(index) == entry.term {
+                        continue;
+                    }
+                    // conflicting entries can never have been committed
+                    self.log.truncate(index - 1);
+                }
+                self.log.push(entry.clone());
+            }
+            let matched = prev_log_index + entries.len();
+            // a delayed message may cover fewer entries than are already known to be committed
+            let commit_index = self.commit_index.max(leader_commit.min(matched));
+            if commit_index > self.commit_index {
+                self.commit_index = commit_index;
+                self.apply_committed();
+            }
+            response = response.with_match_index(matched);
+        }
+        response_sender
+            .send(response.with_term(self.current_term).with_success(success))
+            .unwrap();
+    }
+
+    fn handle_append_entries_ok(
+        &mut self,
+        response_sender: &Sender<Message>,
+        node: &Node,
+        response: &Message,
+    ) {
+        let (Some(term), Some(success)) = (response.body.term, response.body.success) else {
+            eprintln!("Invalid append response: {}", response);
+            return;
+        };
+        if term > self.current_term {
+            self.step_down(term);
+            return;
+        }
+        if term < self.current_term {
+            return;
+        }
+        let Role::Leader {
+            next_index,
+            match_index,
+            last_sent,
+        } = &mut self.role
+        else {
+            return;
+        };
+        let follower = &response.src;
+        if success {
+            let matched = response.body.match_index.unwrap_or_default();
+            let previous = match_index.get(follower).copied().unwrap_or_default();
+            match_index.insert(follower.clone(), previous.max(matched));
+            next_index.insert(follower.clone(), previous.max(matched) + 1);
+            self.advance_commit_index(node);
+        } else {
+            // back off one entry at a time until the logs agree, then resend straight away
+            if let Some(next) = next_index.get_mut(follower) {
+                *next = (*next - 1).max(1);
+            }
+            last_sent.remove(follower);
+            self.replicate(response_sender, node);
+        }
+    }
+}
+
+/// Consensus on the order of key/value operations using
+/// [Raft](https://raft.github.io/raft.pdf). Each node keeps a replica of the log and applies
+/// committed operations to its own copy of the key/value store.
+#[derive(Clone)]
+struct Raft {
+    state: Arc<Mutex<RaftState>>,
+}
+
+/// The result of asking this node to order an operation
+enum Proposal {
+    /// This node is the leader and has appended the operation to its log
+    Appended(Arc<Mutex<PendingOutcome>>),
+    /// Another node is the leader, if one is known
+    NotLeader(Option<String>),
+}
+
+impl Raft {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RaftState::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RaftState> {
+        self.state
+            .lock()
+            .expect("Unable to access consensus state: lock poisoned")
+    }
+
+    fn propose(&self, operation: &Operation) -> Proposal {
+        let mut state = self.lock();
+        if !matches!(state.role, Role::Leader { .. }) {
+            return Proposal::NotLeader(state.leader.clone());
+        }
+        let term = state.current_term;
+        state.log.push(LogEntry {
+            term,
+            command: serde_json::to_value(operation).expect("Cannot serialise operation"),
+        });
+        let outcome: Arc<Mutex<PendingOutcome>> = Default::default();
+        let index = state.last_log_index();
+        state.waiters.insert(
+            index,
+            Waiter {
+                term,
+                outcome: outcome.clone(),
+            },
+        );
+        // the entry is sent to the followers on the next tick
+        Proposal::Appended(outcome)
+    }
+}
+
+impl Module for Raft {
+    fn handle(&self, response_sender: Sender<Message>, context: &RequestContext) {
+        let (node, request) = (context.node(), context.request());
+        let mut state = self.lock();
+        match &request.body.message_type {
+            MessageType::request_vote => state.handle_request_vote(&response_sender, node, request),
+            MessageType::request_vote_ok => {
+                state.handle_request_vote_ok(&response_sender, node, request)
+            }
+            MessageType::append_entries => {
+                state.handle_append_entries(&response_sender, node, request)
+            }
+            MessageType::append_entries_ok => {
+                state.handle_append_entries_ok(&response_sender, node, request)
+            }
+            other => eprintln!("Unexpected consensus message type: {:?}", other),
+        }
+    }
+}
+
+impl Timer for Raft {
+    fn interval(&self) -> Duration {
+        Duration::from_millis(TICK_MS)
+    }
+
+    fn tick(&self, response_sender: Sender<Message>, node: &Node) {
+        let mut state = self.lock();
+        if matches!(state.role, Role::Leader { .. }) {
+            state.replicate(&response_sender, node);
+            if node.node_ids.len() == 1 {
+                state.advance_commit_index(node);
+            }
+        } else if Instant::now() >= state.election_deadline {
+            state.start_election(&response_sender, node);
+        }
+    }
+}
+
+/// Handles `read`, `write` and `cas` requests. The leader orders them through the log, other nodes
+/// forward them to the leader. Neither occupies a worker while waiting, as committing an operation
+/// depends on the workers processing `append_entries_ok`.
+struct KvHandler {
+    raft: Raft,
+}
+
+impl KvHandler {
+    async fn execute(
+        raft: Raft,
+        node: &Node,
+        request: &Message,
+    ) -> Result<Box<dyn Response>, AppError> {
+        let operation = Operation::from_request(request)?;
+        let timeout = Duration::from_millis(CLIENT_TIMEOUT_MS);
+        let proposal = raft.propose(&operation);
+        match proposal {
+            Proposal::Appended(outcome) => {
+                let value = PendingOutcome::wait(outcome, timeout).await?;
+                Ok(Box::new(KvOk {
+                    message_type: operation.response_type(),
+                    value,
+                }))
+            }
+            Proposal::NotLeader(Some(leader)) => {
+                let mut forwarded = request.clone();
+                forwarded.src = node.node_id.clone();
+                forwarded.dest = leader;
+                let response = node.rpc_async(forwarded, timeout).await?;
+                Ok(Box::new(Forwarded {
+                    body: response.body,
+                }))
+            }
+            Proposal::NotLeader(None) => Err(AppError::Unavailable(
+                "no leader has been elected".to_string(),
+            )),
+        }
+    }
+}
+
+impl AsyncRequestHandler for KvHandler {
+    fn handle(
+        &self,
+        context: AsyncRequestContext,
+    ) -> BoxFuture<'static, Result<Box<dyn Response>, AppError>> {
+        let raft = self.raft.clone();
+        Box::pin(async move { Self::execute(raft, context.node(), context.request()).await })
+    }
+}
+
+struct KvOk {
+    message_type: MessageType,
+    value: Option<Value>,
+}
+
+impl Response for KvOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        let mut response = Message::reply_to(node, caller, in_reply_to, self.message_type.clone());
+        response.body.value = self.value.clone();
+        vec![response]
+    }
+}
+
+/// The leader's response to a forwarded request, relayed to the client
+struct Forwarded {
+    body: MessageBody,
+}
+
+impl Response for Forwarded {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        let mut body = self.body.clone();
+        body.msg_id = Some(node.get_and_increment_message_id());
+        body.in_reply_to = Some(in_reply_to);
+        vec![Message {
+            src: node.node_id.clone(),
+            dest: caller.to_string(),
+            body,
+        }]
+    }
+}
+
+/// Install the `lin-kv` workload
+pub fn register(builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let stats = Arc::new(Client::new("localhost:8125", "lin-kv").unwrap());
+    let raft = Raft::new();
+
+    Ok(builder
+        .with_stats(stats)
+        .with_async_handler(
+            MessageType::read,
+            Box::new(KvHandler { raft: raft.clone() }),
+        )
+        .with_async_handler(
+            MessageType::write,
+            Box::new(KvHandler { raft: raft.clone() }),
+        )
+        .with_async_handler(MessageType::cas, Box::new(KvHandler { raft: raft.clone() }))
+        .with_module(MessageType::request_vote, Box::new(raft.clone()))
+        .with_module(MessageType::request_vote_ok, Box::new(raft.clone()))
+        .with_module(MessageType::append_entries, Box::new(raft.clone()))
+        .with_module(MessageType::append_entries_ok, Box::new(raft.clone()))
+        .with_timer(Arc::new(raft)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::mpsc;
+
+    fn follower() -> (RaftState, Node) {
+        let (sender, _) = mpsc::channel();
+        let node_ids = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        (
+            RaftState::new(),
+            Node::new("n2".to_string(), node_ids, sender),
+        )
+    }
+
+    /// Returns: an `append_entries` request from `n1` with an entry for each of the terms, entry
+    /// `i` of the log writing `i` to key `i`
+    fn append_entries(
+        term: u64,
+        prev_log_index: usize,
+        prev_log_term: u64,
+        terms: &[u64],
+        leader_commit: usize,
+    ) -> Message {
+        let entries: Vec<LogEntry> = terms
+            .iter()
+            .enumerate()
+            .map(|(offset, term)| {
+                let index = prev_log_index + 1 + offset;
+                let operation = Operation::Write {
+                    key: json!(index),
+                    value: json!(index),
+                };
+                LogEntry {
+                    term: *term,
+                    command: serde_json::to_value(operation).unwrap(),
+                }
+            })
+            .collect();
+        Message::new("n1", "n2", MessageType::append_entries)
+            .with_msg_id(1_usize)
+            .with_term(term)
+            .with_leader_id("n1")
+            .with_prev_log_index(prev_log_index)
+            .with_prev_log_term(prev_log_term)
+            .with_entries(entries)
+            .with_leader_commit(leader_commit)
+    }
+
+    fn append(state: &mut RaftState, node: &Node, request: Message) -> Message {
+        let (sender, receiver) = mpsc::channel();
+        state.handle_append_entries(&sender, node, &request);
+        receiver.try_recv().expect("No response to append_entries")
+    }
+
+    fn terms(state: &RaftState) -> Vec<u64> {
+        state.log.iter().map(|entry| entry.term).collect()
+    }
+
+    #[test]
+    fn appends_entries_that_follow_a_matching_entry() {
+        let (mut state, node) = follower();
+        let response = append(&mut state, &node, append_entries(1, 0, 0, &[1, 1], 0));
+        assert_eq!(response.body.success, Some(true));
+        assert_eq!(response.body.match_index, Some(2));
+        assert_eq!(terms(&state), vec![1, 1]);
+
+        let response = append(&mut state, &node, append_entries(2, 2, 1, &[2], 0));
+        assert_eq!(response.body.success, Some(true));
+        assert_eq!(response.body.match_index, Some(3));
+        assert_eq!(terms(&state), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_entries_unless_the_previous_entry_matches() {
+        let (mut state, node) = follower();
+        append(&mut state, &node, append_entries(1, 0, 0, &[1], 0));
+
+        // the follower does not have the previous entry
+        let response = append(&mut state, &node, append_entries(2, 2, 1, &[2], 0));
+        assert_eq!(response.body.success, Some(false));
+        // the follower's previous entry is from a different term
+        let response = append(&mut state, &node, append_entries(2, 1, 2, &[2], 0));
+        assert_eq!(response.body.success, Some(false));
+        assert_eq!(terms(&state), vec![1]);
+    }
+
+    #[test]
+    fn rejects_entries_from_an_earlier_term() {
+        let (mut state, node) = follower();
+        append(&mut state, &node, append_entries(2, 0, 0, &[2], 0));
+        let response = append(&mut state, &node, append_entries(1, 1, 2, &[1], 0));
+        assert_eq!(response.body.success, Some(false));
+        assert_eq!(response.body.term, Some(2));
+        assert_eq!(terms(&state), vec![2]);
+    }
+
+    #[test]
+    fn truncates_conflicting_entries() {
+        let (mut state, node) = follower();
+        append(&mut state, &node, append_entries(2, 0, 0, &[1, 1, 2], 0));
+        let response = append(&mut state, &node, append_entries(3, 1, 1, &[3], 0));
+        assert_eq!(response.body.success, Some(true));
+        assert_eq!(terms(&state), vec![1, 3]);
+    }
+
+    #[test]
+    fn keeps_matching_entries_when_a_message_is_repeated() {
+        let (mut state, node) = follower();
+        append(&mut state, &node, append_entries(1, 0, 0, &[1, 1, 1], 0));
+        // a delayed copy of an earlier message must not discard the later entries
+        append(&mut state, &node, append_entries(1, 0, 0, &[1], 0));
+        assert_eq!(terms(&state), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn follower_commits_up_to_the_leader_commit_index() {
+        let (mut state, node) = follower();
+        append(&mut state, &node, append_entries(1, 0, 0, &[1, 1, 1], 2));
+        assert_eq!(state.commit_index, 2);
+        assert_eq!(state.last_applied, 2);
+        assert_eq!(state.store.get("2"), Some(&json!(2)));
+        assert_eq!(state.store.get("3"), None);
+
+        append(&mut state, &node, append_entries(1, 3, 1, &[], 3));
+        assert_eq!(state.commit_index, 3);
+        assert_eq!(state.store.get("3"), Some(&json!(3)));
+    }
+
+    #[test]
+    fn follower_commit_index_never_decreases() {
+        let (mut state, node) = follower();
+        append(&mut state, &node, append_entries(1, 0, 0, &[1, 1, 1], 2));
+        assert_eq!(state.commit_index, 2);
+        // the leader has since committed more, but this message only covers the first entry
+        append(&mut state, &node, append_entries(1, 0, 0, &[1], 3));
+        assert_eq!(state.commit_index, 2);
+        append(&mut state, &node, append_entries(1, 0, 0, &[], 3));
+        assert_eq!(state.commit_index, 2);
+    }
+
+    fn leader(log_terms: &[u64], current_term: u64) -> (RaftState, Node) {
+        let (sender, _) = mpsc::channel();
+        let node_ids = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        let mut state = RaftState::new();
+        state.current_term = current_term;
+        state.log = log_terms
+            .iter()
+            .map(|term| LogEntry {
+                term: *term,
+                command: json!(null),
+            })
+            .collect();
+        state.role = Role::Leader {
+            next_index: Default::default(),
+            match_index: HashMap::from([("n2".to_string(), 0), ("n3".to_string(), 0)]),
+            last_sent: Default::default(),
+        };
+        (state, Node::new("n1".to_string(), node_ids, sender))
+    }
+
+    fn set_match_index(state: &mut RaftState, follower: &str, index: usize) {
+        if let Role::Leader { match_index, .. } = &mut state.role {
+            match_index.insert(follower.to_string(), index);
+        }
+    }
+
+    #[test]
+    fn leader_commits_entries_stored_by_a_majority() {
+        let (mut state, node) = leader(&[1, 1, 1], 1);
+        state.advance_commit_index(&node);
+        assert_eq!(state.commit_index, 0);
+
+        set_match_index(&mut state, "n2", 2);
+        state.advance_commit_index(&node);
+        assert_eq!(state.commit_index, 2);
+
+        set_match_index(&mut state, "n3", 3);
+        state.advance_commit_index(&node);
+        assert_eq!(state.commit_index, 3);
+    }
+
+    #[test]
+    fn leader_only_commits_earlier_terms_through_an_entry_of_its_own() {
+        let (mut state, node) = leader(&[1, 2], 3);
+        set_match_index(&mut state, "n2", 2);
+        set_match_index(&mut state, "n3", 2);
+        state.advance_commit_index(&node);
+        assert_eq!(state.commit_index, 0);
+
+        state.log.push(LogEntry {
+            term: 3,
+            command: json!(null),
+        });
+        set_match_index(&mut state, "n2", 3);
+        state.advance_commit_index(&node);
+        assert_eq!(state.commit_index, 3);
+    }
+}
@@ -0,0 +1,148 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use crate::protocol::MessageType;
+use crate::server::ServerBuilder;
+
+/// Gossips messages to every node, using one of several dissemination algorithms
+pub mod broadcast;
+/// Sends each request's payload back to the caller
+pub mod echo;
+/// A grow-only set, replicated as a CRDT
+pub mod g_set;
+/// Append-only logs with committed offsets, stored in memory or in a key/value service
+pub mod kafka;
+/// A linearizable key/value store, replicated with Raft
+pub mod lin_kv;
+/// A counter supporting increments and decrements, replicated as a CRDT
+pub mod pn_counter;
+/// Transactions over lists, committed to `lin-kv` with compare-and-set
+pub mod txn_list_append;
+/// Transactions over registers, with read-uncommitted or read-committed isolation
+pub mod txn_rw_register;
+
+/// The names of the [workloads](https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md)
+/// that can be installed with `register`
+pub const NAMES: [&str; 8] = [
+    "echo",
+    "broadcast",
+    "g-set",
+    "pn-counter",
+    "kafka",
+    "lin-kv",
+    "txn-rw-register",
+    "txn-list-append",
+];
+
+/// A problem with the way a workload was selected or configured, which prevents the node from
+/// starting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// There is no workload with this name
+    UnknownWorkload(String),
+    /// A setting in the environment cannot be used
+    InvalidSetting {
+        /// The environment variable holding the setting
+        variable: String,
+        /// The value it holds
+        value: String,
+    },
+    /// The workload handles a message type that is already handled, so requests of that type
+    /// would be answered twice
+    ConflictingWorkload {
+        /// The workload being installed
+        workload: String,
+        /// The type it shares with the handlers already installed
+        message_type: MessageType,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::UnknownWorkload(name) => write!(f, "Unknown workload: {}", name),
+            ConfigError::InvalidSetting { variable, value } => {
+                write!(f, "{} has an invalid value: {}", variable, value)
+            }
+            ConfigError::ConflictingWorkload {
+                workload,
+                message_type,
+            } => write!(
+                f,
+                "{} handles {:?} requests, which are already handled",
+                workload, message_type
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Install the handlers, modules and timers for a workload. Workloads can be combined by
+/// registering several with the same builder, provided they handle different message types, as
+/// every handler for a type replies to each request.
+///
+/// Returns: the builder, or an error if there is no workload with that name, its settings are not
+/// valid, or it handles a message type that the builder already has a handler for
+pub fn register(name: &str, builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let register = match name {
+        "echo" => echo::register,
+        "broadcast" => broadcast::register,
+        "g-set" => g_set::register,
+        "pn-counter" => pn_counter::register,
+        "kafka" => kafka::register,
+        "lin-kv" => lin_kv::register,
+        "txn-rw-register" => txn_rw_register::register,
+        "txn-list-append" => txn_list_append::register,
+        _ => return Err(ConfigError::UnknownWorkload(name.to_string())),
+    };
+    let installed = builder.handler_counts();
+    let builder = register(builder)?;
+    let conflict = builder
+        .handler_counts()
+        .into_iter()
+        .find(|(message_type, count)| {
+            installed
+                .get(message_type)
+                .is_some_and(|installed| installed < count)
+        });
+    match conflict {
+        Some((message_type, _)) => Err(ConfigError::ConflictingWorkload {
+            workload: name.to_string(),
+            message_type,
+        }),
+        None => Ok(builder),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[test]
+    fn workloads_handling_different_types_can_be_combined() {
+        let builder = register("echo", Server::builder()).unwrap();
+        assert!(register("g-set", builder).is_ok());
+    }
+
+    #[test]
+    fn workloads_handling_the_same_type_are_rejected() {
+        let builder = register("g-set", Server::builder()).unwrap();
+        let Err(error) = register("pn-counter", builder) else {
+            panic!("Both workloads were installed");
+        };
+        assert!(matches!(
+            error,
+            ConfigError::ConflictingWorkload { ref workload, .. } if workload == "pn-counter"
+        ));
+    }
+
+    #[test]
+    fn unknown_workloads_are_rejected() {
+        assert!(matches!(
+            register("nope", Server::builder()),
+            Err(ConfigError::UnknownWorkload(name)) if name == "nope"
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+use statsd::Client;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::context::RequestContext;
+use crate::crdt::{PnCounter, Replicator};
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType};
+use crate::server::{RequestHandler, Response, ServerBuilder};
+use crate::workloads::ConfigError;
+
+/// How often each node sends its state to every other node
+const REPLICATION_INTERVAL_MS: u64 = 500;
+
+struct AddHandler {
+    counter: Replicator<PnCounter>,
+}
+
+impl RequestHandler for AddHandler {
+    fn handle(&self, context: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        let (node, request) = (context.node(), context.request());
+        let delta = request
+            .body
+            .delta
+            .ok_or_else(|| AppError::MissingField("body.delta".to_string()))?;
+        self.counter.write().add(&node.node_id, delta);
+        Ok(Box::new(AddOk {}))
+    }
+}
+
+struct AddOk;
+
+impl Response for AddOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![Message::reply_to(
+            node,
+            caller,
+            in_reply_to,
+            MessageType::add_ok,
+        )]
+    }
+}
+
+struct ReadHandler {
+    counter: Replicator<PnCounter>,
+}
+
+impl RequestHandler for ReadHandler {
+    fn handle(&self, _: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        Ok(Box::new(ReadOk {
+            value: self.counter.read().value(),
+        }))
+    }
+}
+
+struct ReadOk {
+    value: i64,
+}
+
+impl Response for ReadOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, MessageType::read_ok)
+                .with_value(self.value),
+        ]
+    }
+}
+
+/// Install the `pn-counter` workload
+pub fn register(builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let stats = Arc::new(Client::new("localhost:8125", "pn-counter").unwrap());
+    let counter: Replicator<PnCounter> =
+        Replicator::new(Duration::from_millis(REPLICATION_INTERVAL_MS));
+    let add_handler = AddHandler {
+        counter: counter.clone(),
+    };
+    let read_handler = ReadHandler {
+        counter: counter.clone(),
+    };
+
+    Ok(builder
+        .with_stats(stats)
+        .with_handler(MessageType::add, Box::new(add_handler))
+        .with_handler(MessageType::read, Box::new(read_handler))
+        .with_module(MessageType::replicate, Box::new(counter.clone()))
+        .with_timer(Arc::new(counter)))
+}
@@ -0,0 +1,318 @@
+use serde_json::{Map, Value};
+use statsd::Client;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::kv::KvClient;
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType, MicroOp};
+use crate::server::{Response, ServerBuilder};
+use crate::workloads::ConfigError;
+
+/// The key under which the database root is stored
+const ROOT_KEY: &str = "root";
+
+/// Executes transactions against a database kept in `lin-kv`, in the style of Datomic. Each list
+/// is stored as an immutable "thunk" under a unique key, and the database root maps every list's
+/// key to the thunk holding its current value. A transaction reads the root, writes new thunks for
+/// the lists it appends to, then commits by replacing the root with compare-and-set. If another
+/// transaction committed in the meantime, the compare-and-set fails and the transaction is
+/// aborted.
+///
+/// The thunks must be written before the root that refers to them, so an aborted transaction
+/// leaves its thunks behind in `lin-kv`. Nothing refers to them, so they are never read, and the
+/// space they take up is accepted as the cost of not coordinating transactions.
+///
+/// Each transaction makes several requests to `lin-kv` in turn, so the handler is async and does
+/// not occupy a thread while it waits for responses.
+struct TxnHandler {
+    lin_kv: KvClient,
+    /// Thunks never change once written, so they can be cached
+    thunks: Mutex<HashMap<String, Vec<Value>>>,
+    next_thunk_id: AtomicUsize,
+}
+
+impl TxnHandler {
+    /// Returns: the list stored in a thunk
+    async fn thunk(&self, node: &Node, id: &str) -> Result<Vec<Value>, AppError> {
+        let cached = self
+            .thunks
+            .lock()
+            .expect("Unable to read thunk cache: lock poisoned")
+            .get(id)
+            .cloned();
+        if let Some(list) = cached {
+            return Ok(list);
+        }
+        let list = match self.lin_kv.read_async(node, id).await? {
+            Value::Array(list) => list,
+            other => {
+                return Err(AppError::Unavailable(format!(
+                    "{} is not a list: {}",
+                    id, other
+                )))
+            }
+        };
+        self.thunks
+            .lock()
+            .expect("Unable to cache thunk: lock poisoned")
+            .insert(id.to_string(), list.clone());
+        Ok(list)
+    }
+
+    /// Store a list in a new thunk
+    ///
+    /// Returns: the thunk's identifier
+    async fn write_thunk(&self, node: &Node, list: Vec<Value>) -> Result<String, AppError> {
+        let id = format!(
+            "{}-{}",
+            node.node_id,
+            self.next_thunk_id.fetch_add(1, Ordering::Relaxed)
+        );
+        self.lin_kv
+            .write_async(node, id.as_str(), Value::Array(list.clone()))
+            .await?;
+        self.thunks
+            .lock()
+            .expect("Unable to cache thunk: lock poisoned")
+            .insert(id.clone(), list);
+        Ok(id)
+    }
+
+    async fn execute(&self, node: &Node, request: &Message) -> Result<TxnOk, AppError> {
+        let operations = request
+            .body
+            .txn
+            .as_ref()
+            .ok_or_else(|| AppError::MissingField("body.txn".to_string()))?;
+        if operations
+            .iter()
+            .any(|operation| matches!(operation, MicroOp::Write { .. }))
+        {
+            return Err(AppError::NotSupported("w".to_string()));
+        }
+
+        let root = match self.lin_kv.read_async(node, ROOT_KEY).await {
+            Ok(Value::Object(root)) => root,
+            Ok(other) => {
+                return Err(AppError::Unavailable(format!(
+                    "database root is not a map: {}",
+                    other
+                )))
+            }
+            Err(AppError::KeyDoesNotExist(_)) => Map::new(),
+            Err(e) => return Err(e),
+        };
+        // the lists this transaction has appended to
+        let mut appended: HashMap<String, Vec<Value>> = HashMap::new();
+        let mut txn = Vec::with_capacity(operations.len());
+        for operation in operations {
+            match operation {
+                MicroOp::Read { key, .. } => {
+                    let list = match appended.get(&key.to_string()) {
+                        Some(list) => Some(list.clone()),
+                        None => match root.get(&key.to_string()) {
+                            Some(id) => Some(self.thunk(node, &thunk_id(id)?).await?),
+                            None => None,
+                        },
+                    };
+                    txn.push(MicroOp::Read {
+                        key: key.clone(),
+                        value: list.map(Value::Array),
+                    });
+                }
+                MicroOp::Append { key, value } => {
+                    let mut list = match appended.remove(&key.to_string()) {
+                        Some(list) => list,
+                        None => match root.get(&key.to_string()) {
+                            Some(id) => self.thunk(node, &thunk_id(id)?).await?,
+                            None => vec![],
+                        },
+                    };
+                    list.push(value.clone());
+                    appended.insert(key.to_string(), list);
+                    txn.push(operation.clone());
+                }
+                MicroOp::Write { .. } => unreachable!("Writes are rejected before execution"),
+            }
+        }
+
+        if !appended.is_empty() {
+            let mut new_root = root.clone();
+            let mut written = Vec::with_capacity(appended.len());
+            for (key, list) in appended {
+                let id = self.write_thunk(node, list).await?;
+                written.push(id.clone());
+                new_root.insert(key, Value::String(id));
+            }
+            match self
+                .lin_kv
+                .cas_async(
+                    node,
+                    ROOT_KEY,
+                    Value::Object(root),
+                    Value::Object(new_root),
+                    true,
+                )
+                .await
+            {
+                Ok(()) => {}
+                Err(AppError::PreconditionFailed(_)) => {
+                    // the thunks are orphaned, so there is no need to keep them cached
+                    let mut thunks = self
+                        .thunks
+                        .lock()
+                        .expect("Unable to evict thunks: lock poisoned");
+                    for id in written {
+                        thunks.remove(&id);
+                    }
+                    return Err(AppError::TxnConflict(
+                        "another transaction committed first".to_string(),
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(TxnOk { txn })
+    }
+}
+
+fn thunk_id(id: &Value) -> Result<String, AppError> {
+    id.as_str()
+        .map(str::to_string)
+        .ok_or_else(|| AppError::Unavailable(format!("{} is not a thunk identifier", id)))
+}
+
+struct TxnOk {
+    txn: Vec<MicroOp>,
+}
+
+impl Response for TxnOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, MessageType::txn_ok)
+                .with_txn(self.txn.clone()),
+        ]
+    }
+}
+
+/// Install the `txn-list-append` workload
+pub fn register(builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let stats = Arc::new(Client::new("localhost:8125", "txn-list-append").unwrap());
+    let txn_handler = Arc::new(TxnHandler {
+        lin_kv: KvClient::lin_kv(),
+        thunks: Default::default(),
+        next_thunk_id: Default::default(),
+    });
+
+    Ok(builder.with_stats(stats).with_async_handler(
+        MessageType::txn,
+        Box::new(move |node: Arc<Node>, request: Message| {
+            let txn_handler = txn_handler.clone();
+            async move { txn_handler.execute(&node, &request).await }
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::block_on;
+    use crate::services::{node_with, LinKv, Service};
+    use serde_json::json;
+
+    /// A `lin-kv` in which another transaction commits just before the first compare-and-set of
+    /// the root
+    #[derive(Default)]
+    struct Interfering {
+        lin_kv: LinKv,
+        interfered: bool,
+    }
+
+    impl Service for Interfering {
+        fn name(&self) -> &str {
+            self.lin_kv.name()
+        }
+
+        fn handle(&mut self, request: &Message) -> Message {
+            let is_root = request.body.key == Some(json!(ROOT_KEY));
+            if request.body.message_type == MessageType::cas && is_root && !self.interfered {
+                self.interfered = true;
+                let commit = Message::new("n2", self.name(), MessageType::write)
+                    .with_msg_id(1_usize)
+                    .with_key(ROOT_KEY)
+                    .with_value(json!({"x": "n2-0"}));
+                self.lin_kv.handle(&commit);
+            }
+            self.lin_kv.handle(request)
+        }
+    }
+
+    fn handler() -> TxnHandler {
+        TxnHandler {
+            lin_kv: KvClient::lin_kv(),
+            thunks: Default::default(),
+            next_thunk_id: Default::default(),
+        }
+    }
+
+    fn txn(operations: Vec<MicroOp>) -> Message {
+        Message::new("c1", "n1", MessageType::txn)
+            .with_msg_id(1_usize)
+            .with_txn(operations)
+    }
+
+    fn append(key: i64, value: i64) -> MicroOp {
+        MicroOp::Append {
+            key: json!(key),
+            value: json!(value),
+        }
+    }
+
+    fn read(key: i64) -> MicroOp {
+        MicroOp::Read {
+            key: json!(key),
+            value: None,
+        }
+    }
+
+    #[test]
+    fn committed_appends_are_read_by_later_transactions() {
+        let handler = handler();
+        let node = node_with(Box::new(LinKv::default()));
+        block_on(handler.execute(&node, &txn(vec![append(1, 1), append(1, 2)]))).unwrap();
+        let result = block_on(handler.execute(&node, &txn(vec![read(1), read(2)]))).unwrap();
+        assert_eq!(
+            result.txn,
+            vec![
+                MicroOp::Read {
+                    key: json!(1),
+                    value: Some(json!([1, 2]))
+                },
+                MicroOp::Read {
+                    key: json!(2),
+                    value: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_root_changed_by_another_transaction_aborts_with_a_conflict() {
+        let handler = handler();
+        let node = node_with(Box::<Interfering>::default());
+        let result = block_on(handler.execute(&node, &txn(vec![append(1, 1)])));
+        let Err(error) = result else {
+            panic!("Transaction committed despite a conflict");
+        };
+        assert_eq!(error.code(), 30);
+        assert!(error.is_definite());
+        // the orphaned thunk is not kept
+        assert!(handler.thunks.lock().unwrap().is_empty());
+
+        // the other transaction's commit stands
+        let root = block_on(handler.lin_kv.read_async(&node, ROOT_KEY)).unwrap();
+        assert_eq!(root, json!({"x": "n2-0"}));
+    }
+}
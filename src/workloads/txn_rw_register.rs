@@ -0,0 +1,300 @@
+use serde_json::Value;
+use statsd::Client;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::context::RequestContext;
+use crate::crdt::{LwwMap, Replicator};
+use crate::node::{AppError, Node};
+use crate::protocol::{Message, MessageType, MicroOp};
+use crate::server::{RequestHandler, Response, ServerBuilder};
+use crate::workloads::ConfigError;
+
+/// How often each node sends its registers to every other node
+const REPLICATION_INTERVAL_MS: u64 = 500;
+
+/// The keys are kept as their JSON representations because JSON values cannot be hashed
+type Registers = LwwMap<String, Value>;
+
+/// The isolation level provided, selected with the `TXN_CONSISTENCY` environment variable. Both
+/// are totally available: every node executes transactions against its own replica of the
+/// registers without coordinating with the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Consistency {
+    /// Each micro-operation is applied on its own, so other transactions may observe a
+    /// transaction's intermediate writes
+    ReadUncommitted,
+    /// Each transaction is applied atomically, so only its final writes are ever observed
+    ReadCommitted,
+}
+
+impl Consistency {
+    /// Returns: the isolation level configured in the environment, or an error if it is not
+    /// recognised
+    fn from_env() -> Result<Self, ConfigError> {
+        match env::var("TXN_CONSISTENCY").as_deref() {
+            Ok("read-uncommitted") => Ok(Consistency::ReadUncommitted),
+            Ok("read-committed") | Err(_) => Ok(Consistency::ReadCommitted),
+            Ok(other) => Err(ConfigError::InvalidSetting {
+                variable: "TXN_CONSISTENCY".to_string(),
+                value: other.to_string(),
+            }),
+        }
+    }
+}
+
+/// Executes transactions. Every write in a transaction shares the same logical clock value, which
+/// is greater than that of any write this node has observed. Conflicting writes are resolved by
+/// last-writer-wins on `(clock, node_id)`, which is the same for every key a transaction writes,
+/// so the orders of the writes to different keys agree with each other and there are no cycles of
+/// write dependencies. For this to hold each transaction installs at most one write per key: only
+/// the last write to a key reaches the registers, and the transaction reads its earlier writes
+/// from its own buffer.
+struct TxnHandler {
+    registers: Replicator<Registers>,
+    consistency: Consistency,
+    /// The latest logical clock value assigned to a transaction on this node
+    clock: Mutex<u64>,
+}
+
+impl TxnHandler {
+    /// Returns: a logical clock value for a new transaction
+    fn tick(&self, registers: &Registers) -> u64 {
+        let mut clock = self.clock.lock().expect("Unable to tick: lock poisoned");
+        *clock = (*clock).max(registers.timestamp()) + 1;
+        *clock
+    }
+}
+
+impl RequestHandler for TxnHandler {
+    fn handle(&self, context: &RequestContext) -> Result<Box<dyn Response>, AppError> {
+        let (node, request) = (context.node(), context.request());
+        let operations = request
+            .body
+            .txn
+            .as_ref()
+            .ok_or_else(|| AppError::MissingField("body.txn".to_string()))?;
+        // reject the transaction before applying any of it, the registers hold single values
+        if operations
+            .iter()
+            .any(|operation| matches!(operation, MicroOp::Append { .. }))
+        {
+            return Err(AppError::NotSupported("append".to_string()));
+        }
+        let mut transaction = Transaction::new(operations);
+        let txn = match self.consistency {
+            Consistency::ReadUncommitted => {
+                let clock = self.tick(&self.registers.read());
+                operations
+                    .iter()
+                    .enumerate()
+                    .map(|(position, operation)| {
+                        let mut registers = self.registers.write();
+                        transaction.execute(&mut registers, clock, position, operation, node)
+                    })
+                    .collect()
+            }
+            Consistency::ReadCommitted => {
+                // hold the lock for the whole transaction so that neither other transactions nor
+                // replication observe its intermediate writes
+                let mut registers = self.registers.write();
+                let clock = self.tick(&registers);
+                operations
+                    .iter()
+                    .enumerate()
+                    .map(|(position, operation)| {
+                        transaction.execute(&mut registers, clock, position, operation, node)
+                    })
+                    .collect()
+            }
+        };
+        Ok(Box::new(TxnOk { txn }))
+    }
+}
+
+/// The writes of a transaction in progress
+struct Transaction {
+    /// The position of the last write to each key
+    last_writes: HashMap<String, usize>,
+    /// The latest value the transaction has written to each key
+    written: HashMap<String, Value>,
+}
+
+impl Transaction {
+    fn new(operations: &[MicroOp]) -> Self {
+        let last_writes = operations
+            .iter()
+            .enumerate()
+            .filter_map(|(position, operation)| match operation {
+                MicroOp::Write { key, .. } => Some((key.to_string(), position)),
+                _ => None,
+            })
+            .collect();
+        Self {
+            last_writes,
+            written: HashMap::new(),
+        }
+    }
+
+    /// Apply a single micro-operation. A write only reaches the registers if it is the
+    /// transaction's last write to its key.
+    ///
+    /// Returns: the micro-operation as performed, including any value read
+    fn execute(
+        &mut self,
+        registers: &mut Registers,
+        clock: u64,
+        position: usize,
+        operation: &MicroOp,
+        node: &Node,
+    ) -> MicroOp {
+        match operation {
+            MicroOp::Read { key, .. } => {
+                let key_string = key.to_string();
+                let value = self
+                    .written
+                    .get(&key_string)
+                    .or_else(|| registers.get(&key_string))
+                    .cloned();
+                MicroOp::Read {
+                    key: key.clone(),
+                    value,
+                }
+            }
+            MicroOp::Write { key, value } => {
+                let key = key.to_string();
+                if self.last_writes.get(&key) == Some(&position) {
+                    registers.set(key.clone(), value.clone(), clock, &node.node_id);
+                }
+                self.written.insert(key, value.clone());
+                operation.clone()
+            }
+            MicroOp::Append { .. } => unreachable!("Appends are rejected before execution"),
+        }
+    }
+}
+
+struct TxnOk {
+    txn: Vec<MicroOp>,
+}
+
+impl Response for TxnOk {
+    fn to_messages(&self, node: &Node, caller: &str, in_reply_to: usize) -> Vec<Message> {
+        vec![
+            Message::reply_to(node, caller, in_reply_to, MessageType::txn_ok)
+                .with_txn(self.txn.clone()),
+        ]
+    }
+}
+
+/// Install the `txn-rw-register` workload
+pub fn register(builder: ServerBuilder) -> Result<ServerBuilder, ConfigError> {
+    let stats = Arc::new(Client::new("localhost:8125", "txn-rw-register").unwrap());
+    let registers: Replicator<Registers> =
+        Replicator::new(Duration::from_millis(REPLICATION_INTERVAL_MS));
+    let txn_handler = TxnHandler {
+        registers: registers.clone(),
+        consistency: Consistency::from_env()?,
+        clock: Default::default(),
+    };
+
+    Ok(builder
+        .with_stats(stats)
+        .with_handler(MessageType::txn, Box::new(txn_handler))
+        .with_module(MessageType::replicate, Box::new(registers.clone()))
+        .with_timer(Arc::new(registers)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::Crdt;
+    use serde_json::json;
+    use std::sync::mpsc;
+
+    fn handler(consistency: Consistency) -> TxnHandler {
+        TxnHandler {
+            registers: Replicator::new(Duration::from_millis(REPLICATION_INTERVAL_MS)),
+            consistency,
+            clock: Default::default(),
+        }
+    }
+
+    fn node(node_id: &str) -> Node {
+        let (sender, _) = mpsc::channel();
+        Node::new(
+            node_id.to_string(),
+            vec!["n1".to_string(), "n2".to_string()],
+            sender,
+        )
+    }
+
+    fn write(key: u64, value: u64) -> MicroOp {
+        MicroOp::Write {
+            key: json!(key),
+            value: json!(value),
+        }
+    }
+
+    fn read(key: u64) -> MicroOp {
+        MicroOp::Read {
+            key: json!(key),
+            value: None,
+        }
+    }
+
+    fn read_of(key: u64, value: u64) -> MicroOp {
+        MicroOp::Read {
+            key: json!(key),
+            value: Some(json!(value)),
+        }
+    }
+
+    /// Returns: the micro-operations as performed
+    fn transact(handler: &TxnHandler, node: &Node, txn: Vec<MicroOp>) -> Vec<MicroOp> {
+        let request = Message::new("c1", &node.node_id, MessageType::txn)
+            .with_msg_id(1_usize)
+            .with_txn(txn);
+        let response = handler
+            .handle(&RequestContext::new(node, &request))
+            .expect("Transaction failed");
+        response.to_messages(node, "c1", 1)[0]
+            .body
+            .txn
+            .clone()
+            .expect("Response has no txn")
+    }
+
+    #[test]
+    fn concurrent_transactions_order_their_writes_the_same_way_on_every_key() {
+        for consistency in [Consistency::ReadUncommitted, Consistency::ReadCommitted] {
+            let (n1, n2) = (node("n1"), node("n2"));
+            let (h1, h2) = (handler(consistency), handler(consistency));
+            // both transactions get the same clock value and write the keys in opposite orders
+            transact(&h1, &n1, vec![write(1, 1), write(2, 1)]);
+            transact(&h2, &n2, vec![write(2, 2), write(1, 2)]);
+            h1.registers.write().merge(&h2.registers.read());
+            h2.registers.write().merge(&h1.registers.read());
+
+            for (handler, node) in [(&h1, &n1), (&h2, &n2)] {
+                let txn = transact(handler, node, vec![read(1), read(2)]);
+                // a mix of the two transactions' writes would be a cycle of write dependencies
+                assert_eq!(txn, vec![read_of(1, 2), read_of(2, 2)], "{:?}", consistency);
+            }
+        }
+    }
+
+    #[test]
+    fn transactions_read_their_own_writes_and_install_the_last() {
+        for consistency in [Consistency::ReadUncommitted, Consistency::ReadCommitted] {
+            let (n1, h1) = (node("n1"), handler(consistency));
+            let txn = transact(&h1, &n1, vec![write(1, 1), read(1), write(1, 2), read(1)]);
+            assert_eq!(txn[1], read_of(1, 1), "{:?}", consistency);
+            assert_eq!(txn[3], read_of(1, 2), "{:?}", consistency);
+            let txn = transact(&h1, &n1, vec![read(1)]);
+            assert_eq!(txn[0], read_of(1, 2), "{:?}", consistency);
+        }
+    }
+}
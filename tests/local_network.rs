@@ -0,0 +1,168 @@
+//! Runs workloads end to end on the `local-network` binary, which stands in for Maelstrom and its
+//! services
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+/// How long to wait for the response to a client request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A cluster of workload nodes on an in-memory network, driven by a single client
+struct Cluster {
+    network: Child,
+    requests: Option<ChildStdin>,
+    responses: Receiver<Value>,
+    next_msg_id: usize,
+}
+
+impl Cluster {
+    /// Parameters:
+    /// - `workload` - the path of the workload binary
+    /// - `nodes` - the number of nodes, named `n1` to `nN`
+    /// - `env` - environment variables for the nodes
+    fn start(workload: &str, nodes: usize, env: &[(&str, &str)]) -> Self {
+        let mut network = Command::new(env!("CARGO_BIN_EXE_local-network"))
+            .args(["--nodes", &nodes.to_string(), "--", workload])
+            .envs(env.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Unable to start local-network");
+        let requests = network.stdin.take();
+        let output = network.stdout.take().expect("Output is piped");
+        let (sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines() {
+                let Ok(line) = line else { break };
+                let message: Value = serde_json::from_str(&line).expect("Invalid response");
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            network,
+            requests,
+            responses,
+            next_msg_id: 1,
+        }
+    }
+
+    /// Send a client request to a node and wait for the response
+    ///
+    /// Returns: the body of the response
+    fn request(&mut self, node: &str, mut body: Value) -> Value {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+        body["msg_id"] = json!(msg_id);
+        let request = json!({"src": "c1", "dest": node, "body": body});
+        let requests = self.requests.as_mut().expect("Input is open");
+        writeln!(requests, "{}", request).expect("Unable to send request");
+        requests.flush().expect("Unable to send request");
+        loop {
+            let response = self
+                .responses
+                .recv_timeout(RESPONSE_TIMEOUT)
+                .unwrap_or_else(|_| panic!("No response to {}", request));
+            // skip the responses to the network's own init requests
+            if response["dest"] == "c1" && response["body"]["in_reply_to"] == msg_id {
+                return response["body"].clone();
+            }
+        }
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        // closing the input shuts the network down
+        drop(self.requests.take());
+        let _ = self.network.wait();
+    }
+}
+
+#[test]
+fn kafka_over_lin_kv() {
+    let mut cluster = Cluster::start(
+        env!("CARGO_BIN_EXE_kafka"),
+        3,
+        &[("KAFKA_STORAGE", "lin-kv")],
+    );
+    let mut sent: Vec<(&str, u64, u64)> = vec![];
+    for msg in 0..9_u64 {
+        let node = format!("n{}", msg % 3 + 1);
+        let key = if msg % 2 == 0 { "even" } else { "odd" };
+        let response = cluster.request(&node, json!({"type": "send", "key": key, "msg": msg}));
+        assert_eq!(response["type"], "send_ok", "{}", response);
+        sent.push((key, response["offset"].as_u64().unwrap(), msg));
+    }
+    // each node assigns offsets from the same sequence
+    for key in ["even", "odd"] {
+        let offsets: Vec<u64> = sent
+            .iter()
+            .filter(|(k, _, _)| *k == key)
+            .map(|(_, offset, _)| *offset)
+            .collect();
+        assert!(
+            offsets.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            offsets
+        );
+    }
+
+    let response = cluster.request(
+        "n2",
+        json!({"type": "poll", "offsets": {"even": 0, "odd": 0}}),
+    );
+    assert_eq!(response["type"], "poll_ok", "{}", response);
+    for key in ["even", "odd"] {
+        let expected: Vec<Value> = sent
+            .iter()
+            .filter(|(k, _, _)| *k == key)
+            .map(|(_, offset, msg)| json!([offset, msg]))
+            .collect();
+        assert_eq!(response["msgs"][key], json!(expected));
+    }
+
+    let (_, committed, _) = sent[2];
+    let response = cluster.request(
+        "n3",
+        json!({"type": "commit_offsets", "offsets": {"even": committed}}),
+    );
+    assert_eq!(response["type"], "commit_offsets_ok", "{}", response);
+    let response = cluster.request(
+        "n1",
+        json!({"type": "list_committed_offsets", "keys": ["even", "odd"]}),
+    );
+    assert_eq!(response["offsets"], json!({"even": committed}));
+}
+
+#[test]
+fn txn_list_append_across_nodes() {
+    let mut cluster = Cluster::start(env!("CARGO_BIN_EXE_txn-list-append"), 2, &[]);
+    let response = cluster.request(
+        "n1",
+        json!({"type": "txn", "txn": [["append", 1, 10], ["r", 1, null]]}),
+    );
+    assert_eq!(response["txn"], json!([["append", 1, 10], ["r", 1, [10]]]));
+    let response = cluster.request(
+        "n2",
+        json!({"type": "txn", "txn": [["append", 1, 11], ["append", 2, 20], ["r", 1, null]]}),
+    );
+    assert_eq!(
+        response["txn"],
+        json!([["append", 1, 11], ["append", 2, 20], ["r", 1, [10, 11]]])
+    );
+    let response = cluster.request(
+        "n1",
+        json!({"type": "txn", "txn": [["r", 1, null], ["r", 2, null], ["r", 3, null]]}),
+    );
+    assert_eq!(
+        response["txn"],
+        json!([["r", 1, [10, 11]], ["r", 2, [20]], ["r", 3, null]])
+    );
+}